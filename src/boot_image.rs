@@ -0,0 +1,281 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use image::RgbImage;
+use log::warn;
+
+use crate::scaling::{PANEL_HEIGHT, PANEL_WIDTH};
+use crate::storage;
+
+const MODE_FILE: &str = "boot_mode";
+/// Rewritten when something new is displayed, see [`LastFrame`]
+const LAST_FRAME_FILE: &str = "last_frame";
+const PINNED_FRAME_FILE: &str = "boot_frame";
+
+/// Least time between two writes of the last frame
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// What the panel shows at boot, before Wi-Fi comes up
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BootImage {
+    /// Whatever was displayed before the restart
+    Last,
+    /// The built in color wheel
+    Default,
+    /// The image last chosen with `/bootimage current`, set again with `/bootimage pinned`
+    Pinned,
+}
+
+impl BootImage {
+    pub fn parse(word: &str) -> Option<BootImage> {
+        match word {
+            "last" => Some(BootImage::Last),
+            "default" => Some(BootImage::Default),
+            "pinned" => Some(BootImage::Pinned),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            BootImage::Last => "last",
+            BootImage::Default => "default",
+            BootImage::Pinned => "pinned",
+        }
+    }
+
+    pub fn load_setting() -> BootImage {
+        fs::read_to_string(storage::path(MODE_FILE))
+            .ok()
+            .and_then(|mode| BootImage::parse(mode.trim()))
+            .unwrap_or(BootImage::Last)
+    }
+
+    pub fn save_setting(self) {
+        if let Err(err) = fs::write(storage::path(MODE_FILE), self.name()) {
+            warn!("Could not save the boot image setting: {:?}", err);
+        }
+    }
+
+    /// The frame to show at boot, `None` means the built in one
+    pub fn load_frame(self) -> Option<RgbImage> {
+        let name = match self {
+            BootImage::Last => LAST_FRAME_FILE,
+            BootImage::Default => return None,
+            BootImage::Pinned => PINNED_FRAME_FILE,
+        };
+
+        let pixels = fs::read(storage::path(name)).ok()?;
+        RgbImage::from_raw(PANEL_WIDTH, PANEL_HEIGHT, pixels)
+    }
+}
+
+/// Whether `/bootimage current` was ever used, `/bootimage pinned` needs it
+pub fn has_pinned_frame() -> bool {
+    storage::path(PINNED_FRAME_FILE).exists()
+}
+
+/// Saves `frame` right away, for a restart that can't wait for [`LastFrame`]
+pub fn save_last_frame(frame: &RgbImage) {
+    save_frame(LAST_FRAME_FILE, frame);
+}
+
+pub fn pin_frame(frame: &RgbImage) {
+    save_frame(PINNED_FRAME_FILE, frame);
+}
+
+fn save_frame(name: &str, frame: &RgbImage) {
+    if let Err(err) = fs::write(storage::path(name), frame.as_raw()) {
+        warn!("Could not save {}: {:?}", name, err);
+    }
+}
+
+/// Keeps the file [`BootImage::Last`] boots from up to date without wearing out the flash.
+///
+/// Nothing is written unless the boot image is `Last`, a frame that is
+/// already saved isn't written again, and new ones at most every
+/// `SAVE_INTERVAL`. A frame shown sooner waits for its turn, replaced by any
+/// newer one, so a burst of images only writes the first and the last.
+pub struct LastFrame {
+    path: PathBuf,
+    enabled: bool,
+    /// Hash of what the file holds
+    saved: Option<u64>,
+    saved_at: Option<Instant>,
+    waiting: Option<RgbImage>,
+}
+
+impl LastFrame {
+    /// `on_flash` is the frame the file already holds, if it's known
+    pub fn new(
+        path: impl Into<PathBuf>,
+        mode: BootImage,
+        on_flash: Option<&RgbImage>,
+    ) -> LastFrame {
+        LastFrame {
+            path: path.into(),
+            enabled: mode == BootImage::Last,
+            saved: on_flash.map(hash),
+            saved_at: None,
+            waiting: None,
+        }
+    }
+
+    /// The one for the storage partition
+    pub fn on_storage(mode: BootImage, on_flash: Option<&RgbImage>) -> LastFrame {
+        LastFrame::new(storage::path(LAST_FRAME_FILE), mode, on_flash)
+    }
+
+    pub fn set_mode(&mut self, mode: BootImage) {
+        self.enabled = mode == BootImage::Last;
+        if !self.enabled {
+            self.waiting = None;
+        }
+    }
+
+    /// Takes note of a frame put on the panel, and saves it if it's time
+    pub fn shown(&mut self, frame: &RgbImage, now: Instant) {
+        if !self.enabled {
+            return;
+        }
+
+        if self.saved == Some(hash(frame)) {
+            self.waiting = None;
+            return;
+        }
+
+        self.waiting = Some(frame.clone());
+        self.save_if_due(now);
+    }
+
+    /// When the frame waiting to be saved can be written, `None` if there is none
+    pub fn due(&self) -> Option<Instant> {
+        self.waiting.as_ref()?;
+
+        Some(match self.saved_at {
+            Some(at) => at + SAVE_INTERVAL,
+            None => Instant::now(),
+        })
+    }
+
+    pub fn save_if_due(&mut self, now: Instant) {
+        if self.saved_at.is_some_and(|at| now < at + SAVE_INTERVAL) {
+            return;
+        }
+        let Some(frame) = self.waiting.take() else {
+            return;
+        };
+
+        // a failed write isn't retried any sooner, it would fail the same way
+        self.saved_at = Some(now);
+        match fs::write(&self.path, frame.as_raw()) {
+            Ok(()) => self.saved = Some(hash(&frame)),
+            Err(err) => warn!("Could not save the last frame: {:?}", err),
+        }
+    }
+}
+
+fn hash(frame: &RgbImage) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    frame.as_raw().hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use image::Rgb;
+
+    use super::*;
+
+    fn temp_file(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("last-frame-{}-{}", std::process::id(), name));
+        fs::remove_file(&path).ok();
+        path
+    }
+
+    fn frame(value: u8) -> RgbImage {
+        RgbImage::from_pixel(2, 2, Rgb([value, value, value]))
+    }
+
+    fn saved(path: &Path) -> Option<Vec<u8>> {
+        fs::read(path).ok()
+    }
+
+    #[test]
+    fn saves_only_for_the_last_boot_image() {
+        let path = temp_file("mode");
+        let mut last_frame = LastFrame::new(&path, BootImage::Default, None);
+        let now = Instant::now();
+
+        last_frame.shown(&frame(1), now);
+        assert_eq!(saved(&path), None);
+
+        last_frame.set_mode(BootImage::Last);
+        last_frame.shown(&frame(2), now);
+        assert_eq!(saved(&path), Some(frame(2).into_raw()));
+
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn skips_the_frame_already_saved() {
+        let path = temp_file("same");
+        let mut last_frame = LastFrame::new(&path, BootImage::Last, Some(&frame(1)));
+        let now = Instant::now();
+
+        last_frame.shown(&frame(1), now);
+        assert_eq!(saved(&path), None);
+        assert_eq!(last_frame.due(), None);
+
+        last_frame.shown(&frame(2), now);
+        fs::remove_file(&path).unwrap();
+        last_frame.shown(&frame(2), now + SAVE_INTERVAL * 2);
+        assert_eq!(saved(&path), None);
+    }
+
+    #[test]
+    fn writes_at_most_once_per_interval() {
+        let path = temp_file("interval");
+        let mut last_frame = LastFrame::new(&path, BootImage::Last, None);
+        let start = Instant::now();
+
+        last_frame.shown(&frame(1), start);
+        assert_eq!(saved(&path), Some(frame(1).into_raw()));
+
+        // only the newest of the frames shown in between is written
+        last_frame.shown(&frame(2), start + Duration::from_secs(1));
+        last_frame.shown(&frame(3), start + Duration::from_secs(2));
+        assert_eq!(saved(&path), Some(frame(1).into_raw()));
+        assert_eq!(last_frame.due(), Some(start + SAVE_INTERVAL));
+
+        last_frame.save_if_due(start + SAVE_INTERVAL - Duration::from_secs(1));
+        assert_eq!(saved(&path), Some(frame(1).into_raw()));
+
+        last_frame.save_if_due(start + SAVE_INTERVAL);
+        assert_eq!(saved(&path), Some(frame(3).into_raw()));
+        assert_eq!(last_frame.due(), None);
+
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn forgets_the_waiting_frame_when_turned_off() {
+        let path = temp_file("off");
+        let mut last_frame = LastFrame::new(&path, BootImage::Last, None);
+        let start = Instant::now();
+
+        last_frame.shown(&frame(1), start);
+        last_frame.shown(&frame(2), start);
+        last_frame.set_mode(BootImage::Pinned);
+        last_frame.save_if_due(start + SAVE_INTERVAL);
+
+        assert_eq!(saved(&path), Some(frame(1).into_raw()));
+
+        fs::remove_file(path).ok();
+    }
+}
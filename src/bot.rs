@@ -0,0 +1,628 @@
+use anyhow::{anyhow, Context, Result};
+use esp_idf_svc::wifi::EspWifi;
+use frankenstein::{
+    CallbackQuery, EditMessageTextParams, GetCustomEmojiStickersParams, GetFileParams,
+    MaybeInaccessibleMessage, Message, SendChatActionParams, SendMessageParams, TelegramApi,
+    Update, UpdateContent,
+};
+use image::{RgbImage, RgbaImage};
+use log::{error, info};
+use std::time::Instant;
+
+use crate::boot_image::{self, BootImage};
+use crate::bot_api::{Esp32Api, EspBotError};
+use crate::cache::StickerCache;
+use crate::clock::{Clock, ClockFace};
+use crate::commands;
+use crate::compose::{self, Background};
+use crate::custom_emoji::{self, Grid};
+use crate::decode::{self, DecodeError};
+use crate::display::Display;
+use crate::download::{self, DownloadError, MAX_FILE_SIZE};
+use crate::filters::Filters;
+use crate::groups::Groups;
+use crate::media::{self, MediaFile};
+use crate::moderation::{Decision, Moderation};
+use crate::panel::{self, Action};
+use crate::playlist::Playlist;
+use crate::rate_limit::{Limited, RateLimiter};
+use crate::registry::{Args, Kind};
+use crate::roles::{Role, Roles};
+use crate::router;
+use crate::scaling::{ScaleMode, PANEL_HEIGHT, PANEL_WIDTH};
+use crate::transition::Transition;
+use crate::updates;
+
+/// Everything the update loop and the commands work on
+pub struct Bot {
+    pub api: Esp32Api,
+    pub owner_id: i64,
+    pub bot_token: &'static str,
+    /// Without the @, commands addressed to other bots are ignored
+    pub username: String,
+    pub wifi: Box<EspWifi<'static>>,
+
+    pub roles: Roles,
+    pub rate_limit: RateLimiter,
+    pub moderation: Moderation,
+    pub groups: Groups,
+
+    /// From 1 to `panel::BRIGHTNESS_LEVELS`, kept while the panel is blank
+    pub brightness: u8,
+    pub blank: bool,
+    /// Playlist entry the slideshow was last started from or jumped to, and when
+    pub slide: usize,
+    pub slide_started: Instant,
+
+    pub display: Display,
+    pub cache: StickerCache,
+    pub playlist: Playlist,
+
+    pub scale_mode: ScaleMode,
+    pub background: Background,
+    pub transition: Transition,
+    pub filters: Filters,
+
+    // kept around to be used as background for transparent images
+    // and for /playlist add
+    pub current_frame: RgbImage,
+    pub current_label: String,
+}
+
+impl Bot {
+    /// Role of whoever sent `message`, messages sent on behalf of a chat have none
+    pub fn role(&self, message: &Message) -> Role {
+        self.roles.sender_role(message)
+    }
+
+    /// What users that aren't on the allowlist get told
+    pub fn reply_not_allowed(&self, message: &Message) {
+        router::reply_not_allowed(&self.api, message);
+    }
+
+    pub fn reply(&self, message: &Message, text: impl Into<String>) {
+        router::reply(&self.api, message, text);
+    }
+
+    pub fn send_owner_info(&self) {
+        let mut rssi = 0;
+        unsafe {
+            esp_idf_sys::esp_wifi_sta_get_rssi(&mut rssi);
+        }
+
+        let ip = match self.wifi.sta_netif().get_ip_info() {
+            Ok(info) => info.ip.to_string(),
+            Err(_) => "unknown".to_string(),
+        };
+
+        self.api
+            .send_message(
+                &SendMessageParams::builder()
+                    .chat_id(self.owner_id)
+                    .text(format!("IP: {}\nRSSI: {}", ip, rssi))
+                    .build(),
+            )
+            .ok();
+    }
+
+    pub fn send_upload_action(&self, chat_id: i64) {
+        self.api
+            .send_chat_action(
+                &SendChatActionParams::builder()
+                    .chat_id(chat_id)
+                    .action(frankenstein::ChatAction::UploadPhoto)
+                    .build(),
+            )
+            .ok();
+    }
+
+    /// Whether the sender of `message` can change the panel right now,
+    /// telling them how long to wait the first time they can't
+    pub fn allow_display(&mut self, message: &Message) -> bool {
+        let Some(user) = &message.from else {
+            return false;
+        };
+
+        match self.check_display(user.id as i64) {
+            Ok(()) => true,
+            Err(limited) => {
+                if limited.first {
+                    self.reply(message, cooldown(&limited));
+                }
+                false
+            }
+        }
+    }
+
+    /// Counts a change to the panel by `user_id` against the rate limits
+    fn check_display(&mut self, user_id: i64) -> Result<(), Limited> {
+        // admins are trusted not to flood
+        if self.roles.role(user_id) >= Role::Admin {
+            return Ok(());
+        }
+
+        self.rate_limit.check(user_id, Instant::now())
+    }
+
+    /// Shows a new image and makes it the current one. A playlist picked
+    /// by hand stops, one that keeps the last images gets it added and
+    /// carries on from there
+    pub fn show(&mut self, frame: RgbImage, label: String) {
+        self.current_frame = frame;
+        self.current_label = label;
+
+        if self.playlist.keep_last().is_some() {
+            if let Err(reason) = self.playlist.add(&self.current_frame, &self.current_label) {
+                error!("Could not add to the playlist: {}", reason);
+            }
+
+            if self.playlist.playing && self.play_playlist(self.playlist.len().saturating_sub(1)) {
+                return;
+            }
+        }
+
+        self.playlist.stop();
+        self.display.show(self.current_frame.clone());
+    }
+
+    /// Starts the slideshow from entry `start`, `false` if the playlist is empty
+    pub fn play_playlist(&mut self, start: usize) -> bool {
+        match self.playlist.slideshow(start, self.transition) {
+            Some(slideshow) => {
+                self.display.play(slideshow);
+                self.slide = start % self.playlist.len().max(1);
+                self.slide_started = Instant::now();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The playlist entry on the panel, or the last one that was
+    fn current_slide(&self) -> usize {
+        let count = self.playlist.len().max(1);
+        if !self.playlist.playing {
+            return self.slide % count;
+        }
+
+        let interval = self.playlist.interval.as_millis().max(1);
+        let steps = self.slide_started.elapsed().as_millis() / interval;
+        (self.slide + steps as usize) % count
+    }
+
+    pub fn panel_state(&self) -> panel::State {
+        panel::State {
+            brightness: self.brightness,
+            blank: self.blank,
+            paused: self.display.is_paused(),
+            slide: (!self.playlist.is_empty()).then(|| {
+                (
+                    self.current_slide() + 1,
+                    self.playlist.len(),
+                    self.playlist.playing,
+                )
+            }),
+        }
+    }
+
+    /// Does what a `/panel` button pressed by `user_id` says, returns what to tell them.
+    /// Buttons that change the image count against the rate limits like images do
+    fn panel_action(&mut self, action: Action, user_id: i64) -> String {
+        match action {
+            Action::BrightnessDown | Action::BrightnessUp => {
+                self.brightness =
+                    panel::step_brightness(self.brightness, action == Action::BrightnessUp);
+                self.blank = false;
+                panel::save_brightness(self.brightness);
+                self.display
+                    .set_brightness(panel::brightness_value(self.brightness));
+
+                format!(
+                    "Brightness {} of {}",
+                    self.brightness,
+                    panel::BRIGHTNESS_LEVELS
+                )
+            }
+            Action::Previous | Action::Next => {
+                let count = self.playlist.len();
+                if count == 0 {
+                    return "The playlist is empty, add images with /playlist add".to_string();
+                }
+
+                if let Err(limited) = self.check_display(user_id) {
+                    return cooldown(&limited);
+                }
+
+                let slide = match action {
+                    Action::Next => (self.current_slide() + 1) % count,
+                    _ => (self.current_slide() + count - 1) % count,
+                };
+
+                if self.playlist.playing {
+                    self.play_playlist(slide);
+                } else if let Some((frame, label)) = self.playlist.entry(slide) {
+                    self.current_label = label.to_string();
+                    self.current_frame = frame;
+                    self.slide = slide;
+                    self.display.show(self.current_frame.clone());
+                }
+
+                format!("Image {} of {}", slide + 1, count)
+            }
+            Action::Pause => {
+                let paused = !self.display.is_paused();
+                self.display.pause(paused);
+
+                let answer = if paused { "Paused" } else { "Resumed" };
+                answer.to_string()
+            }
+            Action::Clock => {
+                if let Err(limited) = self.check_display(user_id) {
+                    return cooldown(&limited);
+                }
+
+                let background = self.background.render(&self.current_frame);
+                self.playlist.stop();
+                self.display
+                    .play(Clock::new(ClockFace::Digital, background));
+
+                "Showing the clock".to_string()
+            }
+            Action::Blank => {
+                self.blank = !self.blank;
+                self.display.set_brightness(if self.blank {
+                    0
+                } else {
+                    panel::brightness_value(self.brightness)
+                });
+
+                let answer = if self.blank { "Panel off" } else { "Panel on" };
+                answer.to_string()
+            }
+        }
+    }
+
+    /// Downloads a file from Telegram and scales it to a `width` x `height` area
+    pub fn fetch_image(
+        &mut self,
+        media_file: &MediaFile,
+        mode: ScaleMode,
+        width: u32,
+        height: u32,
+    ) -> Result<RgbaImage> {
+        // checked before talking to Telegram, so cached images work even when downloads don't
+        if let Some(cached) = self
+            .cache
+            .get(&media_file.file_unique_id, mode, width, height)
+        {
+            info!("Using cached {}", media_file.file_unique_id);
+            return Ok(cached);
+        }
+
+        let file = self
+            .api
+            .get_file(
+                &GetFileParams::builder()
+                    .file_id(media_file.file_id.clone())
+                    .build(),
+            )?
+            .result;
+
+        let file_path = file
+            .file_path
+            .ok_or_else(|| anyhow!("Telegram didn't send a download path"))?;
+
+        let url = format!(
+            "https://api.telegram.org/file/bot{}/{}",
+            self.bot_token, file_path
+        );
+
+        download::check_size(file.file_size.or(media_file.file_size), MAX_FILE_SIZE)?;
+
+        // the body goes from the socket through the decoder into the scaler,
+        // without ever holding the whole file for PNGs and GIFs
+        let scaled = download::download(&url, MAX_FILE_SIZE, |body, size_hint| {
+            decode::decode_scaled(body, size_hint, mode, width, height)
+        })?;
+
+        self.cache.put(&media_file.file_unique_id, mode, &scaled);
+
+        Ok(scaled)
+    }
+
+    /// Resolves the custom emoji to their stickers and lays them out in a grid
+    pub fn fetch_custom_emoji(&mut self, ids: Vec<String>) -> Result<RgbaImage> {
+        let stickers = self
+            .api
+            .get_custom_emoji_stickers(
+                &GetCustomEmojiStickersParams::builder()
+                    .custom_emoji_ids(ids)
+                    .build(),
+            )?
+            .result;
+
+        let grid = Grid::new(stickers.len());
+        let mut canvas = RgbaImage::new(PANEL_WIDTH, PANEL_HEIGHT);
+
+        for (index, sticker) in stickers.iter().enumerate() {
+            let media_file = media::from_sticker(sticker).map_err(|reason| anyhow!(reason))?;
+
+            let cell = self.fetch_image(
+                &media_file,
+                ScaleMode::Contain,
+                grid.cell_size,
+                grid.cell_size,
+            )?;
+
+            let (x, y) = grid.cell_origin(index);
+            image::imageops::replace(&mut canvas, &cell, x as i64, y as i64);
+        }
+
+        Ok(canvas)
+    }
+
+    /// Puts the sticker, photo, GIF or custom emoji in `message` on the panel
+    fn display_image(&mut self, message: &Message) -> Result<()> {
+        let mut scaled = match media::from_message(message) {
+            Some(Ok(media_file)) => {
+                self.send_upload_action(message.chat.id);
+
+                let scale_mode = message
+                    .caption
+                    .as_deref()
+                    .and_then(ScaleMode::from_caption)
+                    .unwrap_or(self.scale_mode);
+
+                match self.fetch_image(&media_file, scale_mode, PANEL_WIDTH, PANEL_HEIGHT) {
+                    Ok(scaled) => scaled,
+                    Err(err) => match unsupported(&err) {
+                        Some(reason) => {
+                            self.reply(message, reason);
+                            return Ok(());
+                        }
+                        None => return Err(err.context("Can't display this image")),
+                    },
+                }
+            }
+            // a file the panel can't show isn't something that went wrong
+            Some(Err(reason)) => {
+                self.reply(message, reason);
+                return Ok(());
+            }
+            None => match custom_emoji::custom_emoji_ids(message) {
+                Some(ids) => {
+                    self.send_upload_action(message.chat.id);
+
+                    self.fetch_custom_emoji(ids)
+                        .context("Can't display these emoji")?
+                }
+                None => return Ok(()),
+            },
+        };
+
+        // filters in the caption replace the /filter ones
+        let caption_filters = message.caption.as_deref().and_then(Filters::from_caption);
+        caption_filters
+            .as_ref()
+            .unwrap_or(&self.filters)
+            .apply(&mut scaled);
+
+        let background = self.background.render(&self.current_frame);
+        self.show(
+            compose::composite(&scaled, &background),
+            media::label(message),
+        );
+
+        Ok(())
+    }
+
+    /// Acts on a review button, returns what to tell whoever pressed it
+    fn decide(&mut self, decision: Decision, id: u32) -> String {
+        let Some(pending) = self.moderation.take(id) else {
+            return "This was already decided".to_string();
+        };
+
+        let label = media::label(&pending.message);
+        let mut decided = vec![pending];
+
+        let outcome = match decision {
+            Decision::Approve => match self.display_image(&decided[0].message) {
+                Ok(()) => {
+                    self.reply(
+                        &decided[0].message,
+                        format!("Your {label} was approved and is on the panel"),
+                    );
+                    "Approved"
+                }
+                // the sender is told why, the owner pressing the button only that it failed
+                Err(err) => {
+                    error!("Could not display an approved image: {:?}", err);
+
+                    self.reply(
+                        &decided[0].message,
+                        format!("Your {label} was approved, but {err:#}"),
+                    );
+                    "Approved, but it could not be shown"
+                }
+            },
+            Decision::Reject => {
+                self.reply(&decided[0].message, format!("Your {label} wasn't approved"));
+                "Rejected"
+            }
+            Decision::Ban => {
+                let sender = decided[0].message.from.as_ref().map(|user| user.id as i64);
+                let name = decided[0]
+                    .message
+                    .from
+                    .as_ref()
+                    .map(|user| user.first_name.clone())
+                    .unwrap_or_default();
+                let banned = sender
+                    .is_some_and(|sender| self.roles.set(sender, Role::Banned, &name).is_ok());
+
+                self.reply(&decided[0].message, format!("Your {label} wasn't approved"));
+
+                match sender {
+                    Some(sender) if banned => {
+                        decided.extend(self.moderation.take_from(sender));
+                        "Rejected, sender banned"
+                    }
+                    _ => "Rejected, but the sender could not be banned",
+                }
+            }
+        };
+
+        // the buttons go away with the edit
+        for pending in &decided {
+            if let Some((chat_id, message_id)) = pending.review {
+                self.api
+                    .edit_message_text(
+                        &EditMessageTextParams::builder()
+                            .chat_id(chat_id)
+                            .message_id(message_id)
+                            .text(format!("{}: {}", outcome, pending.summary))
+                            .build(),
+                    )
+                    .ok();
+            }
+        }
+
+        outcome.to_string()
+    }
+
+    /// Tells the owner why, if Telegram can still be reached, and restarts
+    pub fn restart(&self, reason: &str) -> ! {
+        error!("Restarting: {}", reason);
+
+        // the display thread may still be waiting to save it
+        if BootImage::load_setting() == BootImage::Last {
+            boot_image::save_last_frame(&self.current_frame);
+        }
+
+        self.api
+            .send_message(
+                &SendMessageParams::builder()
+                    .chat_id(self.owner_id)
+                    .text(format!("Restarting: {reason}"))
+                    .build(),
+            )
+            .ok();
+
+        unsafe { esp_idf_sys::esp_restart() }
+    }
+
+    /// Edits the `/panel` message the button was under to show the new state
+    fn update_panel(&self, query: &CallbackQuery) {
+        let (chat_id, message_id) = match &query.message {
+            Some(MaybeInaccessibleMessage::Message(message)) => {
+                (message.chat.id, message.message_id)
+            }
+            Some(MaybeInaccessibleMessage::InaccessibleMessage(message)) => {
+                (message.chat.id, message.message_id)
+            }
+            None => return,
+        };
+
+        let state = self.panel_state();
+
+        // fails when nothing changed, like dimming at the lowest level
+        self.api
+            .edit_message_text(
+                &EditMessageTextParams::builder()
+                    .chat_id(chat_id)
+                    .message_id(message_id)
+                    .text(state.describe())
+                    .reply_markup(state.keyboard())
+                    .build(),
+            )
+            .ok();
+    }
+}
+
+impl router::Bot for Bot {
+    type Api = Esp32Api;
+    type ApiError = EspBotError;
+
+    fn api(&self) -> &Esp32Api {
+        &self.api
+    }
+
+    fn owner_id(&self) -> i64 {
+        self.owner_id
+    }
+
+    fn username(&self) -> &str {
+        &self.username
+    }
+
+    fn roles(&self) -> &Roles {
+        &self.roles
+    }
+
+    fn groups(&self) -> &Groups {
+        &self.groups
+    }
+
+    fn moderation(&mut self) -> &mut Moderation {
+        &mut self.moderation
+    }
+
+    fn allow_display(&mut self, message: &Message) -> bool {
+        Bot::allow_display(self, message)
+    }
+
+    fn display_image(&mut self, message: &Message) -> Result<()> {
+        Bot::display_image(self, message)
+    }
+
+    fn run_command(&mut self, kind: Kind, message: &Message, args: Args) -> Result<Option<String>> {
+        commands::run(self, kind, message, args)
+    }
+
+    fn decide(&mut self, decision: Decision, id: u32) -> String {
+        Bot::decide(self, decision, id)
+    }
+
+    fn panel_action(&mut self, action: Action, user_id: i64) -> String {
+        Bot::panel_action(self, action, user_id)
+    }
+
+    fn update_panel(&self, query: &CallbackQuery) {
+        Bot::update_panel(self, query)
+    }
+}
+
+impl updates::Handler for Bot {
+    type Api = Esp32Api;
+
+    fn api(&self) -> &Esp32Api {
+        &self.api
+    }
+
+    fn handle_update(&mut self, update: &Update) -> Result<()> {
+        match &update.content {
+            UpdateContent::Message(message) => router::handle_message(self, message),
+            UpdateContent::CallbackQuery(query) => router::handle_callback(self, query),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// What someone who has to wait before changing the panel is told
+fn cooldown(limited: &Limited) -> String {
+    let seconds = limited.retry_in.as_secs() + 1;
+
+    if limited.global {
+        format!("The panel is busy, try again in {seconds} seconds")
+    } else {
+        format!("Slow down, you can change the panel again in {seconds} seconds")
+    }
+}
+
+/// Why a downloaded file isn't something the panel can show, when that's why `err` happened
+fn unsupported(err: &anyhow::Error) -> Option<&str> {
+    match err.downcast_ref::<DownloadError>() {
+        Some(DownloadError::Decode(DecodeError::Unsupported(reason))) => Some(reason),
+        _ => None,
+    }
+}
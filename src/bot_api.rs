@@ -1,8 +1,64 @@
-use embedded_svc::http::{client::Client, Method};
+use embedded_svc::http::{
+    client::{Client, Request},
+    Method,
+};
 use esp_idf_hal::io::EspIOError;
 use esp_idf_svc::http::client::{Configuration, EspHttpConnection};
 
+use crate::multipart::{FileSource, Multipart};
+
 pub fn http_post(url: impl AsRef<str>, data: &[u8]) -> Result<Vec<u8>, EspBotError> {
+    let headers = [("Content-Type", "application/json")];
+
+    post(url.as_ref(), &headers, |request| {
+        request.write(data)?;
+        Ok(())
+    })
+}
+
+/// Sends a `multipart/form-data` body, files are streamed from flash as it goes
+pub fn http_post_multipart(url: impl AsRef<str>, form: &Multipart) -> Result<Vec<u8>, EspBotError> {
+    let content_type = form.content_type();
+    let content_length = form.content_length()?.to_string();
+    let headers = [
+        ("Content-Type", content_type.as_str()),
+        ("Content-Length", content_length.as_str()),
+    ];
+
+    post(url.as_ref(), &headers, |request| {
+        form.write_to(&mut RequestBody(request))?;
+        Ok(())
+    })
+}
+
+/// Lets the multipart encoder write straight into the request
+struct RequestBody<'a, 'b>(&'a mut Request<&'b mut EspHttpConnection>);
+
+impl std::io::Write for RequestBody<'_, '_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .write(buf)
+            .map_err(|err| std::io::Error::other(format!("{err:?}")))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0
+            .flush()
+            .map_err(|err| std::io::Error::other(format!("{err:?}")))
+    }
+}
+
+/// Random enough that it won't show up inside a file
+fn random_boundary() -> String {
+    let random = unsafe { [esp_idf_sys::esp_random(), esp_idf_sys::esp_random()] };
+    format!("----hub75bot{:08x}{:08x}", random[0], random[1])
+}
+
+fn post(
+    url: &str,
+    headers: &[(&str, &str)],
+    write_body: impl FnOnce(&mut Request<&mut EspHttpConnection>) -> Result<(), EspBotError>,
+) -> Result<Vec<u8>, EspBotError> {
     // 1. Create a new EspHttpConnection with default Configuration. (Check documentation)
     let configuration = Configuration {
         timeout: Some(core::time::Duration::from_secs(130)),
@@ -13,11 +69,9 @@ pub fn http_post(url: impl AsRef<str>, data: &[u8]) -> Result<Vec<u8>, EspBotErr
     // 2. Get a client using the embedded_svc Client::wrap method. (Check documentation)
     let mut client = Client::wrap(connection);
 
-    let headers = [("Content-Type", "application/json")];
+    let mut request = client.request(Method::Post, url, headers)?;
 
-    let mut request = client.request(Method::Post, url.as_ref(), &headers)?;
-
-    request.write(data)?;
+    write_body(&mut request)?;
 
     // 4. Submit the request and check the status code of the response.
     // Successful http status codes are in the 200..=299 range.
@@ -94,6 +148,71 @@ impl Esp32Api {
         let api_url = format!("{BASE_API_URL}{api_key}");
         Self { api_url }
     }
+
+    /// Like `request_with_form_data`, but files can also come from memory.
+    ///
+    /// Every field of `params` becomes a form field, except the ones
+    /// named in `files` which are replaced by the file contents.
+    pub fn request_with_files<T1: serde::ser::Serialize, T2: serde::de::DeserializeOwned>(
+        &self,
+        method: &str,
+        params: T1,
+        files: Vec<(&str, FileSource)>,
+    ) -> Result<T2, EspBotError> {
+        let url = format!("{}/{method}", self.api_url);
+
+        let serde_json::Value::Object(fields) = serde_json::to_value(&params)? else {
+            return Err(EspBotError::Http(HttpError {
+                _code: 500,
+                _message: "form data parameters must be an object".to_string(),
+            }));
+        };
+
+        let mut form = Multipart::new(random_boundary());
+
+        for (name, value) in fields {
+            if files.iter().any(|(file_field, _)| *file_field == name) {
+                continue;
+            }
+
+            match value {
+                serde_json::Value::Null => {}
+                serde_json::Value::String(text) => form.text(name, text),
+                // numbers, booleans and nested objects like reply markups are sent as JSON
+                other => form.text(name, other.to_string()),
+            }
+        }
+
+        for (name, source) in files {
+            form.file(name, source);
+        }
+
+        let response = http_post_multipart(url, &form)?;
+
+        parse_response(&response)
+    }
+}
+
+fn parse_response<T2: serde::de::DeserializeOwned>(response: &[u8]) -> Result<T2, EspBotError> {
+    let text = core::str::from_utf8(response)?;
+
+    let parsed_result: Result<T2, serde_json::Error> = serde_json::from_str(text);
+
+    parsed_result.map_err(|_| {
+        let parsed_error: Result<ErrorResponse, serde_json::Error> = serde_json::from_str(text);
+
+        match parsed_error {
+            Ok(result) => EspBotError::Api(result),
+            Err(error) => {
+                let message = format!("{error:?}");
+                let error = HttpError {
+                    _code: 500,
+                    _message: message,
+                };
+                EspBotError::Http(error)
+            }
+        }
+    })
 }
 
 impl From<std::io::Error> for EspBotError {
@@ -125,40 +244,20 @@ impl TelegramApi for Esp32Api {
             }
         };
 
-        let text = core::str::from_utf8(&response)?;
-
-        let parsed_result: Result<T2, serde_json::Error> = serde_json::from_str(text);
-
-        parsed_result.map_err(|_| {
-            let parsed_error: Result<ErrorResponse, serde_json::Error> = serde_json::from_str(text);
-
-            match parsed_error {
-                Ok(result) => EspBotError::Api(result),
-                Err(error) => {
-                    let message = format!("{error:?}");
-                    let error = HttpError {
-                        _code: 500,
-                        _message: message,
-                    };
-                    EspBotError::Http(error)
-                }
-            }
-        })
+        parse_response(&response)
     }
 
-    // isahc doesn't support multipart uploads
-    // https://github.com/sagebind/isahc/issues/14
     fn request_with_form_data<T1: serde::ser::Serialize, T2: serde::de::DeserializeOwned>(
         &self,
-        _method: &str,
-        _params: T1,
-        _files: Vec<(&str, PathBuf)>,
+        method: &str,
+        params: T1,
+        files: Vec<(&str, PathBuf)>,
     ) -> Result<T2, EspBotError> {
-        let error = HttpError {
-            _code: 500,
-            _message: "isahc doesn't support form data requests".to_string(),
-        };
+        let files = files
+            .into_iter()
+            .map(|(name, path)| (name, FileSource::Path(path)))
+            .collect();
 
-        Err(EspBotError::Http(error))
+        self.request_with_files(method, params, files)
     }
 }
@@ -0,0 +1,299 @@
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use image::RgbaImage;
+use log::{info, warn};
+
+use crate::scaling::ScaleMode;
+
+/// Flash space the cache may take, each panel sized image is about 16KB
+pub const CACHE_BUDGET: u64 = 1024 * 1024;
+
+const INDEX_FILE: &str = "cache_index";
+
+struct Entry {
+    name: String,
+    size: u64,
+}
+
+/// Scaled images kept on flash, keyed by the `file_unique_id` Telegram gives to every file.
+///
+/// Images are stored after scaling but before compositing, with alpha,
+/// so a cached sticker still picks up the current background.
+/// Least recently used images are dropped once `budget` bytes are in use.
+/// The order is only saved when an image is added or dropped, so hits since
+/// then are forgotten at a restart.
+pub struct StickerCache {
+    dir: PathBuf,
+    budget: u64,
+    /// Least recently used first
+    entries: Vec<Entry>,
+}
+
+impl StickerCache {
+    /// Loads the index from `dir`, entries whose file went missing are forgotten
+    pub fn open(dir: impl Into<PathBuf>, budget: u64) -> StickerCache {
+        let dir = dir.into();
+
+        let entries = fs::read_to_string(dir.join(INDEX_FILE))
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|name| {
+                let size = fs::metadata(dir.join(name)).ok()?.len();
+                Some(Entry {
+                    name: name.to_string(),
+                    size,
+                })
+            })
+            .collect();
+
+        let mut cache = StickerCache {
+            dir,
+            budget,
+            entries,
+        };
+        info!(
+            "Sticker cache has {} images, {} bytes",
+            cache.entries.len(),
+            cache.used()
+        );
+
+        cache.evict();
+        cache
+    }
+
+    pub fn get(
+        &mut self,
+        file_unique_id: &str,
+        mode: ScaleMode,
+        width: u32,
+        height: u32,
+    ) -> Option<RgbaImage> {
+        let name = file_name(file_unique_id, mode, width, height);
+        let position = self.entries.iter().position(|entry| entry.name == name)?;
+
+        match read_image(&self.dir.join(&name)) {
+            Ok(image) if image.dimensions() == (width, height) => {
+                // the new order is saved with the next put, flash wears out
+                // and a hit shouldn't cost a write
+                let entry = self.entries.remove(position);
+                self.entries.push(entry);
+
+                Some(image)
+            }
+            other => {
+                warn!("Dropping broken cache entry {}: {:?}", name, other.err());
+                let entry = self.entries.remove(position);
+                fs::remove_file(self.dir.join(entry.name)).ok();
+                self.save_index();
+
+                None
+            }
+        }
+    }
+
+    pub fn put(&mut self, file_unique_id: &str, mode: ScaleMode, image: &RgbaImage) {
+        let name = file_name(file_unique_id, mode, image.width(), image.height());
+        self.entries.retain(|entry| entry.name != name);
+
+        let path = self.dir.join(&name);
+        if let Err(err) = write_image(&path, image) {
+            warn!("Could not cache {}: {:?}", file_unique_id, err);
+            fs::remove_file(path).ok();
+            return;
+        }
+
+        self.entries.push(Entry {
+            name,
+            size: 4 + image.len() as u64,
+        });
+
+        self.evict();
+        self.save_index();
+    }
+
+    fn used(&self) -> u64 {
+        self.entries.iter().map(|entry| entry.size).sum()
+    }
+
+    fn evict(&mut self) {
+        while self.used() > self.budget && !self.entries.is_empty() {
+            let entry = self.entries.remove(0);
+            info!("Evicting {} from the sticker cache", entry.name);
+            fs::remove_file(self.dir.join(entry.name)).ok();
+        }
+    }
+
+    fn save_index(&self) {
+        let index: String = self
+            .entries
+            .iter()
+            .map(|entry| format!("{}\n", entry.name))
+            .collect();
+
+        if let Err(err) = fs::write(self.dir.join(INDEX_FILE), index) {
+            warn!("Could not save the sticker cache index: {:?}", err);
+        }
+    }
+}
+
+/// SPIFFS names are short, so the key is hashed (64 bit FNV-1a)
+fn file_name(file_unique_id: &str, mode: ScaleMode, width: u32, height: u32) -> String {
+    let key = format!("{}:{}:{}x{}", file_unique_id, mode.name(), width, height);
+
+    let hash = key.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+
+    format!("c{:016x}", hash)
+}
+
+/// Width and height as little endian u16, followed by the RGBA pixels
+fn write_image(path: &Path, image: &RgbaImage) -> std::io::Result<()> {
+    let mut file = fs::File::create(path)?;
+    file.write_all(&(image.width() as u16).to_le_bytes())?;
+    file.write_all(&(image.height() as u16).to_le_bytes())?;
+    file.write_all(image.as_raw())?;
+    Ok(())
+}
+
+fn read_image(path: &Path) -> std::io::Result<RgbaImage> {
+    let mut file = fs::File::open(path)?;
+
+    let mut header = [0u8; 4];
+    file.read_exact(&mut header)?;
+    let width = u16::from_le_bytes([header[0], header[1]]) as u32;
+    let height = u16::from_le_bytes([header[2], header[3]]) as u32;
+
+    let mut pixels = vec![0u8; width as usize * height as usize * 4];
+    file.read_exact(&mut pixels)?;
+
+    RgbaImage::from_raw(width, height, pixels)
+        .ok_or_else(|| std::io::Error::other("image size mismatch"))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+
+    /// An empty directory of its own for every test
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cache-{}-{}", std::process::id(), name));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn image(value: u8) -> RgbaImage {
+        RgbaImage::from_pixel(4, 4, Rgba([value, value, value, 255]))
+    }
+
+    /// Room for two 4x4 images, 4 bytes of size and 64 of pixels each
+    const TWO_IMAGES: u64 = 2 * 68;
+
+    fn index(dir: &Path) -> String {
+        fs::read_to_string(dir.join(INDEX_FILE)).unwrap()
+    }
+
+    #[test]
+    fn gives_back_what_was_put() {
+        let dir = temp_dir("put");
+        let mut cache = StickerCache::open(&dir, TWO_IMAGES);
+
+        cache.put("a", ScaleMode::Contain, &image(1));
+
+        assert_eq!(cache.get("a", ScaleMode::Contain, 4, 4), Some(image(1)));
+        assert_eq!(cache.get("a", ScaleMode::Cover, 4, 4), None);
+        assert_eq!(cache.get("a", ScaleMode::Contain, 8, 8), None);
+        assert_eq!(cache.get("b", ScaleMode::Contain, 4, 4), None);
+
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used() {
+        let dir = temp_dir("evict");
+        let mut cache = StickerCache::open(&dir, TWO_IMAGES);
+
+        cache.put("a", ScaleMode::Contain, &image(1));
+        cache.put("b", ScaleMode::Contain, &image(2));
+        // a is used again, so b is the oldest now
+        assert!(cache.get("a", ScaleMode::Contain, 4, 4).is_some());
+        cache.put("c", ScaleMode::Contain, &image(3));
+
+        assert!(cache.get("b", ScaleMode::Contain, 4, 4).is_none());
+        assert!(!dir.join(file_name("b", ScaleMode::Contain, 4, 4)).exists());
+        assert!(cache.get("a", ScaleMode::Contain, 4, 4).is_some());
+        assert!(cache.get("c", ScaleMode::Contain, 4, 4).is_some());
+
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn stays_within_the_budget() {
+        let dir = temp_dir("budget");
+        let mut cache = StickerCache::open(&dir, TWO_IMAGES + 67);
+
+        for value in 0..10 {
+            cache.put(&value.to_string(), ScaleMode::Contain, &image(value));
+            assert!(cache.used() <= TWO_IMAGES + 67);
+        }
+        assert_eq!(cache.entries.len(), 2);
+
+        // the files left behind are just the ones in the index, plus the index
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+
+        // and a smaller budget at the next boot drops the oldest
+        let cache = StickerCache::open(&dir, 68);
+        assert_eq!(cache.entries.len(), 1);
+        assert_eq!(
+            cache.entries[0].name,
+            file_name("9", ScaleMode::Contain, 4, 4)
+        );
+
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn hits_dont_write_the_index() {
+        let dir = temp_dir("hits");
+        let mut cache = StickerCache::open(&dir, TWO_IMAGES);
+
+        cache.put("a", ScaleMode::Contain, &image(1));
+        cache.put("b", ScaleMode::Contain, &image(2));
+        let saved = index(&dir);
+
+        assert!(cache.get("a", ScaleMode::Contain, 4, 4).is_some());
+        assert_eq!(index(&dir), saved);
+
+        // the order after the hit goes out with the next put
+        cache.put("c", ScaleMode::Contain, &image(3));
+        let expected = format!(
+            "{}\n{}\n",
+            file_name("a", ScaleMode::Contain, 4, 4),
+            file_name("c", ScaleMode::Contain, 4, 4)
+        );
+        assert_eq!(index(&dir), expected);
+
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn forgets_files_that_went_missing() {
+        let dir = temp_dir("missing");
+        let mut cache = StickerCache::open(&dir, TWO_IMAGES);
+        cache.put("a", ScaleMode::Contain, &image(1));
+        cache.put("b", ScaleMode::Contain, &image(2));
+
+        fs::remove_file(dir.join(file_name("a", ScaleMode::Contain, 4, 4))).unwrap();
+
+        let mut cache = StickerCache::open(&dir, TWO_IMAGES);
+        assert_eq!(cache.entries.len(), 1);
+        assert!(cache.get("b", ScaleMode::Contain, 4, 4).is_some());
+
+        fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,37 @@
+use embedded_graphics::{pixelcolor::Rgb888, prelude::*};
+use image::{Rgb, Rgba, RgbaImage};
+
+/// Lets embedded-graphics draw on an RGBA image, drawn pixels are opaque
+/// so the result can be composited like a premultiplied image
+pub struct Canvas<'a>(pub &'a mut RgbaImage);
+
+impl OriginDimensions for Canvas<'_> {
+    fn size(&self) -> Size {
+        Size::new(self.0.width(), self.0.height())
+    }
+}
+
+impl DrawTarget for Canvas<'_> {
+    type Color = Rgb888;
+    type Error = core::convert::Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            if let (Ok(x), Ok(y)) = (u32::try_from(point.x), u32::try_from(point.y)) {
+                if x < self.0.width() && y < self.0.height() {
+                    self.0
+                        .put_pixel(x, y, Rgba([color.r(), color.g(), color.b(), 255]));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub fn to_rgb888(color: Rgb<u8>) -> Rgb888 {
+    Rgb888::new(color[0], color[1], color[2])
+}
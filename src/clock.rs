@@ -0,0 +1,273 @@
+use std::f32::consts::TAU;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use embedded_graphics::{
+    pixelcolor::Rgb888,
+    prelude::*,
+    primitives::{Circle, Line, PrimitiveStyle, Rectangle},
+};
+use esp_idf_sys::{localtime_r, time_t, tm, tzset};
+use image::{RgbImage, RgbaImage};
+use log::info;
+
+use crate::canvas::Canvas;
+use crate::compose;
+use crate::scaling::{PANEL_HEIGHT, PANEL_WIDTH};
+use crate::scene::Scene;
+use crate::text::{self, FontSize};
+use crate::timezone;
+
+/// The clock starts at 1970 after a restart, anything before this
+/// means SNTP hasn't synced yet
+const SYNCED_AFTER: u64 = 1_700_000_000;
+
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+const FACE_COLOR: Rgb888 = Rgb888::new(255, 255, 255);
+const DIM_COLOR: Rgb888 = Rgb888::new(90, 90, 90);
+const SECONDS_COLOR: Rgb888 = Rgb888::new(255, 40, 40);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClockFace {
+    /// Big hours and minutes with the date below
+    Digital,
+    /// Hands and ticks drawn with primitives
+    Analog,
+    /// A small clock in the corner of the current image
+    Overlay,
+}
+
+impl ClockFace {
+    pub fn parse(word: &str) -> Option<ClockFace> {
+        match word {
+            "" | "digital" => Some(ClockFace::Digital),
+            "analog" => Some(ClockFace::Analog),
+            "overlay" => Some(ClockFace::Overlay),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ClockFace::Digital => "digital",
+            ClockFace::Analog => "analog",
+            ClockFace::Overlay => "overlay",
+        }
+    }
+}
+
+/// Applies the saved timezone, UTC if there is none.
+/// Only for boot, before the display thread reads the time
+pub fn load_timezone() {
+    apply_timezone(&timezone::saved());
+}
+
+/// Changes TZ, which `localtime_r` reads without a lock. Only call it from
+/// the thread that draws clocks, or before it starts
+pub fn apply_timezone(timezone: &str) {
+    info!("Timezone set to {}", timezone);
+    std::env::set_var("TZ", timezone);
+    unsafe { tzset() };
+}
+
+/// Whether SNTP has synced the clock, safe to call from any thread
+pub fn is_synced() -> bool {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .is_ok_and(|since_epoch| since_epoch.as_secs() >= SYNCED_AFTER)
+}
+
+pub struct LocalTime {
+    hour: u32,
+    minute: u32,
+    second: u32,
+    millis: u32,
+    weekday: usize,
+    day: u32,
+    month: usize,
+}
+
+impl LocalTime {
+    /// `None` until SNTP has synced the clock. Reads TZ, so only the display
+    /// thread calls this
+    fn now() -> Option<LocalTime> {
+        if !is_synced() {
+            return None;
+        }
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+
+        let seconds = since_epoch.as_secs() as time_t;
+        let mut local: tm = unsafe { std::mem::zeroed() };
+        if unsafe { localtime_r(&seconds, &mut local) }.is_null() {
+            return None;
+        }
+
+        Some(LocalTime {
+            hour: local.tm_hour as u32,
+            minute: local.tm_min as u32,
+            second: local.tm_sec as u32,
+            millis: since_epoch.subsec_millis(),
+            weekday: local.tm_wday as usize % 7,
+            day: local.tm_mday as u32,
+            month: local.tm_mon as usize % 12,
+        })
+    }
+
+    pub fn hours_minutes(&self) -> String {
+        format!("{:02}:{:02}", self.hour, self.minute)
+    }
+
+    pub fn date(&self) -> String {
+        format!(
+            "{} {} {}",
+            WEEKDAYS[self.weekday], self.day, MONTHS[self.month]
+        )
+    }
+
+    /// Until the second hand moves
+    fn next_second(&self) -> Duration {
+        Duration::from_millis(1000 - self.millis as u64)
+    }
+
+    /// Until the minutes change
+    fn next_minute(&self) -> Duration {
+        Duration::from_secs(59 - self.second as u64) + self.next_second()
+    }
+}
+
+/// Clock drawn over a still background, for the overlay face
+/// the background is the image that was on the panel
+pub struct Clock {
+    face: ClockFace,
+    background: RgbImage,
+}
+
+impl Clock {
+    pub fn new(face: ClockFace, background: RgbImage) -> Clock {
+        Clock { face, background }
+    }
+}
+
+impl Scene for Clock {
+    fn frame(&mut self, _elapsed: Duration) -> (RgbImage, Option<Duration>) {
+        let time = LocalTime::now();
+
+        let mut layer = RgbaImage::new(PANEL_WIDTH, PANEL_HEIGHT);
+        let mut canvas = Canvas(&mut layer);
+
+        match self.face {
+            ClockFace::Digital => draw_digital(&mut canvas, time.as_ref()),
+            ClockFace::Analog => draw_analog(&mut canvas, time.as_ref()),
+            ClockFace::Overlay => draw_overlay(&mut canvas, time.as_ref()),
+        }
+
+        let next = match (&time, self.face) {
+            (None, _) => Duration::from_secs(1),
+            (Some(time), ClockFace::Analog) => time.next_second(),
+            (Some(time), _) => time.next_minute(),
+        };
+
+        (compose::composite(&layer, &self.background), Some(next))
+    }
+}
+
+fn draw_centered(canvas: &mut Canvas, text: &str, size: FontSize, y: i32, color: Rgb888) {
+    let glyphs = text::glyphs(text);
+    let x = PANEL_WIDTH.saturating_sub(text::line_width(&glyphs, size)) / 2;
+    text::draw_line(canvas, &glyphs, size, Point::new(x as i32, y), color);
+}
+
+fn draw_digital(canvas: &mut Canvas, time: Option<&LocalTime>) {
+    let (hours_minutes, date) = match time {
+        Some(time) => (time.hours_minutes(), time.date()),
+        None => ("--:--".to_string(), "no time yet".to_string()),
+    };
+
+    draw_centered(canvas, &hours_minutes, FontSize::Huge, 12, FACE_COLOR);
+    draw_centered(canvas, &date, FontSize::Medium, 40, DIM_COLOR);
+}
+
+/// Point at `length` pixels from the center, `turns` clockwise from 12 o'clock
+fn hand_end(center: Point, turns: f32, length: f32) -> Point {
+    let angle = turns * TAU;
+    center
+        + Point::new(
+            (angle.sin() * length).round() as i32,
+            (-angle.cos() * length).round() as i32,
+        )
+}
+
+fn draw_analog(canvas: &mut Canvas, time: Option<&LocalTime>) {
+    let center = Point::new(PANEL_WIDTH as i32 / 2, PANEL_HEIGHT as i32 / 2);
+    let radius = (PANEL_WIDTH.min(PANEL_HEIGHT) / 2 - 1) as f32;
+
+    Circle::with_center(center, radius as u32 * 2 + 1)
+        .into_styled(PrimitiveStyle::with_stroke(DIM_COLOR, 1))
+        .draw(canvas)
+        .ok();
+
+    for hour in 0..12 {
+        let turns = hour as f32 / 12.0;
+        let inner = if hour % 3 == 0 {
+            radius - 6.0
+        } else {
+            radius - 3.0
+        };
+        Line::new(
+            hand_end(center, turns, inner),
+            hand_end(center, turns, radius - 1.0),
+        )
+        .into_styled(PrimitiveStyle::with_stroke(FACE_COLOR, 1))
+        .draw(canvas)
+        .ok();
+    }
+
+    if let Some(time) = time {
+        let seconds = time.second as f32;
+        let minutes = time.minute as f32 + seconds / 60.0;
+        let hours = (time.hour % 12) as f32 + minutes / 60.0;
+
+        for (turns, length, width, color) in [
+            (hours / 12.0, radius * 0.5, 3, FACE_COLOR),
+            (minutes / 60.0, radius * 0.8, 2, FACE_COLOR),
+            (seconds / 60.0, radius * 0.85, 1, SECONDS_COLOR),
+        ] {
+            Line::new(center, hand_end(center, turns, length))
+                .into_styled(PrimitiveStyle::with_stroke(color, width))
+                .draw(canvas)
+                .ok();
+        }
+    }
+
+    Circle::with_center(center, 3)
+        .into_styled(PrimitiveStyle::with_fill(SECONDS_COLOR))
+        .draw(canvas)
+        .ok();
+}
+
+/// Bottom right corner, on a black box so it reads over any image
+fn draw_overlay(canvas: &mut Canvas, time: Option<&LocalTime>) {
+    let hours_minutes = match time {
+        Some(time) => time.hours_minutes(),
+        None => "--:--".to_string(),
+    };
+
+    let glyphs = text::glyphs(&hours_minutes);
+    let size = FontSize::Small;
+    let width = text::line_width(&glyphs, size);
+    let height = size.line_height();
+    let origin = Point::new(
+        (PANEL_WIDTH - width - 2) as i32,
+        (PANEL_HEIGHT - height - 2) as i32,
+    );
+
+    Rectangle::new(origin - Point::new(1, 1), Size::new(width + 2, height + 2))
+        .into_styled(PrimitiveStyle::with_fill(Rgb888::BLACK))
+        .draw(canvas)
+        .ok();
+
+    text::draw_line(canvas, &glyphs, size, origin, FACE_COLOR);
+}
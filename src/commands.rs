@@ -0,0 +1,825 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use frankenstein::{
+    BotCommand, BotCommandScope, BotCommandScopeChat, ChatMember, ChatType, FileUpload,
+    GetChatMemberParams, InputFile, Message, MessageOrigin, ReplyMarkup, SendMessageParams,
+    SendPhotoParams, SetMyCommandsParams, TelegramApi,
+};
+use log::warn;
+
+use crate::boot_image::{self, BootImage};
+use crate::bot::Bot;
+use crate::bot_api::Esp32Api;
+use crate::clock::{self, Clock, ClockFace};
+use crate::compose::{self, Background};
+use crate::filters::Filters;
+use crate::groups::GroupPolicy;
+use crate::marquee::{self, Marquee};
+use crate::media;
+use crate::multipart::FileSource;
+use crate::playlist;
+use crate::qr;
+use crate::rate_limit::Limit;
+use crate::registry::{self, Args, Kind, Usage, COMMANDS};
+use crate::roles::Role;
+use crate::scaling::{ScaleMode, PANEL_HEIGHT, PANEL_WIDTH};
+use crate::screenshot;
+use crate::text;
+use crate::timezone;
+use crate::transition::{self, Transition, TransitionKind};
+
+/// What a command gets: the bot, the message it came in and
+/// the text after the command. The reply is sent back to the chat
+type Handler = fn(&mut Bot, &Message, Args) -> Result<Option<String>>;
+
+fn handler(kind: Kind) -> Handler {
+    match kind {
+        Kind::Help => help,
+        Kind::Scale => scale,
+        Kind::Background => background,
+        Kind::Text => show_text,
+        Kind::Qr => show_qr,
+        Kind::Marquee => show_marquee,
+        Kind::Clock => show_clock,
+        Kind::Timezone => set_timezone,
+        Kind::Filter => filter,
+        Kind::Transition => set_transition,
+        Kind::Playlist => playlist,
+        Kind::Panel => panel,
+        Kind::Screenshot => screenshot,
+        Kind::BootImage => bootimage,
+        Kind::Allow => allow,
+        Kind::Ban => ban,
+        Kind::Admins => admins,
+        Kind::Moderation => moderation,
+        Kind::Group => group,
+        Kind::Groups => groups,
+        Kind::RateLimit => rate_limit,
+    }
+}
+
+/// Runs the handler of a command `router` already checked
+pub fn run(bot: &mut Bot, kind: Kind, message: &Message, args: Args) -> Result<Option<String>> {
+    handler(kind)(bot, message, args)
+}
+
+/// Fills the menu Telegram shows next to the text box with the commands `role`
+/// can run, in `chat_id` or by default everywhere
+pub fn register(api: &Esp32Api, chat_id: Option<i64>, role: Role) -> Result<()> {
+    let commands = COMMANDS
+        .iter()
+        .filter(|command| command.permission <= role)
+        .map(|command| {
+            BotCommand::builder()
+                .command(command.name)
+                .description(command.description)
+                .build()
+        })
+        .collect::<Vec<_>>();
+
+    let params = match chat_id {
+        Some(chat_id) => SetMyCommandsParams::builder()
+            .commands(commands)
+            .scope(BotCommandScope::Chat(
+                BotCommandScopeChat::builder().chat_id(chat_id).build(),
+            ))
+            .build(),
+        None => SetMyCommandsParams::builder().commands(commands).build(),
+    };
+
+    api.set_my_commands(&params)
+        .context("Could not set the command menu")?;
+
+    Ok(())
+}
+
+fn help(bot: &mut Bot, message: &Message, _: Args) -> Result<Option<String>> {
+    let role = bot.role(message);
+    // there's nothing they could run, so they get how to be let in instead
+    if role == Role::Guest {
+        bot.reply_not_allowed(message);
+        return Ok(None);
+    }
+
+    bot.reply(message, registry::help_text(role));
+
+    if message.chat.id == bot.owner_id {
+        bot.send_owner_info();
+    }
+
+    Ok(None)
+}
+
+fn scale(bot: &mut Bot, _: &Message, args: Args) -> Result<Option<String>> {
+    if args.rest.is_empty() {
+        return Ok(Some(format!(
+            "Scaling is {}, use /scale fit, crop, stretch or pixel to change it",
+            bot.scale_mode.name()
+        )));
+    }
+
+    let mode = ScaleMode::parse(args.rest).ok_or(Usage)?;
+    bot.scale_mode = mode;
+    mode.save();
+
+    Ok(Some(format!("Images will be scaled with {}", mode.name())))
+}
+
+fn background(bot: &mut Bot, _: &Message, args: Args) -> Result<Option<String>> {
+    let background = Background::parse(args.rest).ok_or(Usage)?;
+    bot.background = background;
+    background.save();
+
+    Ok(Some(format!("Background set to {}", background.describe())))
+}
+
+fn show_text(bot: &mut Bot, _: &Message, args: Args) -> Result<Option<String>> {
+    let (style, text) = text::parse_options(args.rest);
+
+    // only options
+    if text.trim().is_empty() {
+        return Err(Usage.into());
+    }
+
+    let rendered = text::render(text, &style);
+    let background = bot.background.render(&bot.current_frame);
+    bot.show(
+        compose::composite(&rendered, &background),
+        format!("text {}", text.trim()),
+    );
+
+    Ok(None)
+}
+
+fn show_qr(bot: &mut Bot, _: &Message, args: Args) -> Result<Option<String>> {
+    let text = match args.rest.strip_prefix("wifi ") {
+        Some(network) => {
+            let network = network.trim();
+            match network.rsplit_once(char::is_whitespace) {
+                Some((ssid, password)) => qr::wifi(ssid.trim(), password),
+                None => qr::wifi(network, ""),
+            }
+        }
+        None => args.rest.to_string(),
+    };
+
+    match qr::render(&text) {
+        Ok(code) => {
+            bot.show(code, "QR code".to_string());
+            Ok(None)
+        }
+        Err(reason) => Ok(Some(reason)),
+    }
+}
+
+fn show_marquee(bot: &mut Bot, message: &Message, args: Args) -> Result<Option<String>> {
+    let (style, speed, text) = marquee::parse_options(args.rest);
+
+    // only options
+    if text.trim().is_empty() {
+        return Err(Usage.into());
+    }
+
+    let background = bot.background.render(&bot.current_frame);
+
+    // replying to an image scrolls the text over it
+    let backdrop = match message
+        .reply_to_message
+        .as_deref()
+        .and_then(media::from_message)
+    {
+        Some(Ok(media_file)) => bot
+            .fetch_image(&media_file, bot.scale_mode, PANEL_WIDTH, PANEL_HEIGHT)
+            .map(|scaled| compose::composite(&scaled, &background))
+            .context("Can't display this image")?,
+        Some(Err(reason)) => return Err(anyhow!(reason)),
+        None => background,
+    };
+
+    bot.playlist.stop();
+    bot.display
+        .play(Marquee::new(text, &style, speed, backdrop));
+
+    Ok(None)
+}
+
+fn show_clock(bot: &mut Bot, _: &Message, args: Args) -> Result<Option<String>> {
+    let face = ClockFace::parse(args.word.unwrap_or_default()).ok_or(Usage)?;
+
+    let background = match face {
+        ClockFace::Overlay => bot.current_frame.clone(),
+        _ => bot.background.render(&bot.current_frame),
+    };
+
+    bot.playlist.stop();
+    bot.display.play(Clock::new(face, background));
+
+    if !clock::is_synced() {
+        return Ok(Some(
+            "The time isn't synced yet, the clock will start once it is".to_string(),
+        ));
+    }
+
+    Ok(None)
+}
+
+fn set_timezone(bot: &mut Bot, _: &Message, args: Args) -> Result<Option<String>> {
+    if args.rest.is_empty() {
+        return Ok(Some(format!(
+            "Timezone is {}, use /timezone +2, /timezone -5:30 or a POSIX TZ string like CET-1CEST,M3.5.0,M10.5.0/3 to change it",
+            timezone::saved()
+        )));
+    }
+
+    let timezone = timezone::parse(args.rest).ok_or(Usage)?;
+    timezone::save(&timezone);
+    let reply = format!("Timezone set to {}", timezone);
+    bot.display.set_timezone(timezone);
+
+    Ok(Some(reply))
+}
+
+fn filter(bot: &mut Bot, _: &Message, args: Args) -> Result<Option<String>> {
+    let reply = match args.rest {
+        "" if bot.filters.is_empty() => "No filters are set, use /filter followed by invert, brightness=N, contrast=N, hue=N, saturation=N, posterize=N, pixelate=N, edge or sepia".to_string(),
+        "" => format!(
+            "Filters are {}, use /filter off to remove them",
+            bot.filters.describe()
+        ),
+        "off" | "none" | "clear" => {
+            bot.filters = Filters::default();
+            bot.filters.save();
+            "Filters removed".to_string()
+        }
+        text => match Filters::parse(text) {
+            Ok(filters) => {
+                bot.filters = filters;
+                bot.filters.save();
+                format!(
+                    "The next images will be shown with {}",
+                    bot.filters.describe()
+                )
+            }
+            Err(reason) => reason,
+        },
+    };
+
+    Ok(Some(reply))
+}
+
+fn set_transition(bot: &mut Bot, _: &Message, args: Args) -> Result<Option<String>> {
+    if args.rest.is_empty() {
+        return Ok(Some(format!(
+            "Transition is {} of {} ms, use /transition cut, crossfade, wipe, slide, dissolve or pixelate to change it",
+            bot.transition.kind.name(),
+            bot.transition.duration.as_millis()
+        )));
+    }
+
+    let (kind, millis) = args
+        .rest
+        .split_once(char::is_whitespace)
+        .unwrap_or((args.rest, ""));
+    let kind = TransitionKind::parse(kind).ok_or(Usage)?;
+    let duration = match millis.trim() {
+        "" => bot.transition.duration,
+        millis => Duration::from_millis(millis.parse().map_err(|_| Usage)?),
+    };
+
+    if !(transition::MIN_DURATION..=transition::MAX_DURATION).contains(&duration) {
+        return Ok(Some(format!(
+            "Use {} to {} milliseconds",
+            transition::MIN_DURATION.as_millis(),
+            transition::MAX_DURATION.as_millis()
+        )));
+    }
+
+    bot.transition = Transition { kind, duration };
+    bot.transition.save();
+    bot.display.set_transition(bot.transition);
+
+    let reply = match kind {
+        TransitionKind::Cut => "Images will change at once".to_string(),
+        _ => format!(
+            "Images will change with a {} of {} ms",
+            kind.name(),
+            duration.as_millis()
+        ),
+    };
+
+    Ok(Some(reply))
+}
+
+fn playlist(bot: &mut Bot, _: &Message, args: Args) -> Result<Option<String>> {
+    let action = args.word.unwrap_or("list");
+    let playlist = &mut bot.playlist;
+    let was_playing = playlist.playing;
+
+    let reply = match action {
+        "add" => match playlist.add(&bot.current_frame, &bot.current_label) {
+            Ok(()) => format!(
+                "Added {} to the playlist, it has {} images",
+                bot.current_label,
+                playlist.len()
+            ),
+            Err(reason) => reason,
+        },
+        "remove" => {
+            let number: usize = args.number()?;
+            if number >= 1 && playlist.remove(number - 1) {
+                format!("Removed image {number}")
+            } else {
+                format!(
+                    "There is no image {}, the playlist has {} images",
+                    number,
+                    playlist.len()
+                )
+            }
+        }
+        "clear" => {
+            playlist.clear();
+            "The playlist is empty".to_string()
+        }
+        "interval" => {
+            let interval = Duration::from_secs(args.number()?);
+            if (playlist::MIN_INTERVAL..=playlist::MAX_INTERVAL).contains(&interval) {
+                playlist.interval = interval;
+                playlist.save();
+                format!("Images change every {} seconds", interval.as_secs())
+            } else {
+                format!(
+                    "Use from {} to {} seconds",
+                    playlist::MIN_INTERVAL.as_secs(),
+                    playlist::MAX_INTERVAL.as_secs()
+                )
+            }
+        }
+        "auto" if args.rest == "off" => {
+            playlist.set_keep_last(None);
+            "Only images added with /playlist add will be played".to_string()
+        }
+        "auto" => {
+            let count: usize = args.number()?;
+            if (1..=playlist::MAX_ENTRIES).contains(&count) {
+                playlist.set_keep_last(Some(count));
+                format!("The playlist will keep the last {count} images shown")
+            } else {
+                format!(
+                    "The playlist can keep up to {} images",
+                    playlist::MAX_ENTRIES
+                )
+            }
+        }
+        "play" => {
+            if playlist.is_empty() {
+                "The playlist is empty, add images with /playlist add".to_string()
+            } else {
+                playlist.playing = true;
+                playlist.save();
+                format!("Playing {} images", playlist.len())
+            }
+        }
+        "stop" => {
+            playlist.stop();
+            "Playlist stopped".to_string()
+        }
+        _ => {
+            let mut listing = format!(
+                "The playlist is {}, images change every {} seconds",
+                if playlist.playing {
+                    "playing"
+                } else {
+                    "stopped"
+                },
+                playlist.interval.as_secs()
+            );
+            if let Some(count) = playlist.keep_last() {
+                listing += &format!(" and the last {count} shown are kept");
+            }
+            for (number, label) in playlist.labels().enumerate() {
+                listing += &format!("\n{}. {}", number + 1, label);
+            }
+            listing
+        }
+    };
+
+    // restarted so the slideshow picks up the changes
+    if bot.playlist.playing && action != "list" {
+        bot.play_playlist(0);
+    } else if was_playing && !bot.playlist.playing {
+        bot.display.show(bot.current_frame.clone());
+    }
+
+    Ok(Some(reply))
+}
+
+fn panel(bot: &mut Bot, message: &Message, _: Args) -> Result<Option<String>> {
+    let state = bot.panel_state();
+
+    bot.api
+        .send_message(
+            &SendMessageParams::builder()
+                .chat_id(message.chat.id)
+                .text(state.describe())
+                .reply_markup(ReplyMarkup::InlineKeyboardMarkup(state.keyboard()))
+                .build(),
+        )
+        .context("Could not send the panel")?;
+
+    Ok(None)
+}
+
+fn screenshot(bot: &mut Bot, message: &Message, args: Args) -> Result<Option<String>> {
+    bot.send_upload_action(message.chat.id);
+
+    let led_look = args.word == Some("led");
+    let picture = screenshot::render(&bot.display.current_frame(), led_look);
+    let png = screenshot::encode_png(&picture).context("Could not encode the screenshot")?;
+
+    let params = SendPhotoParams::builder()
+        .chat_id(message.chat.id)
+        .photo(FileUpload::InputFile(InputFile {
+            path: "screenshot.png".into(),
+        }))
+        .build();
+
+    bot.api
+        .request_with_files::<_, frankenstein::MethodResponse<Message>>(
+            "sendPhoto",
+            &params,
+            vec![(
+                "photo",
+                FileSource::Memory {
+                    file_name: "screenshot.png".to_string(),
+                    data: png,
+                },
+            )],
+        )
+        .context("Could not send the screenshot")?;
+
+    Ok(None)
+}
+
+fn bootimage(bot: &mut Bot, _: &Message, args: Args) -> Result<Option<String>> {
+    let reply = match args.word.unwrap_or_default() {
+        "current" => {
+            boot_image::pin_frame(&bot.current_frame);
+            BootImage::Pinned.save_setting();
+            bot.display.set_boot_image(BootImage::Pinned);
+            "The current image will be shown at boot".to_string()
+        }
+        other => match BootImage::parse(other) {
+            Some(BootImage::Pinned) if !boot_image::has_pinned_frame() => {
+                return Err(anyhow!(
+                    "No image was pinned yet, use /bootimage current to pin the one shown"
+                ));
+            }
+            Some(boot_image) => {
+                boot_image.save_setting();
+                bot.display.set_boot_image(boot_image);
+                format!("Boot image set to {}", boot_image.name())
+            }
+            None => format!(
+                "Boot image is {}, use /bootimage last, default, current or pinned to change it",
+                BootImage::load_setting().name()
+            ),
+        },
+    };
+
+    Ok(Some(reply))
+}
+
+/// Who a moderation command is about: the user id in `argument`, or the sender
+/// of the message it replies to, looking through forwards to the owner
+fn target(message: &Message, argument: &str) -> Option<(i64, String)> {
+    if let Ok(id) = argument.parse() {
+        return Some((id, String::new()));
+    }
+
+    let replied = message.reply_to_message.as_deref()?;
+    let user = match replied.forward_origin.as_deref() {
+        Some(MessageOrigin::User(origin)) => &origin.sender_user,
+        // hidden by their privacy settings
+        Some(_) => return None,
+        None => replied.from.as_deref()?,
+    };
+
+    Some((user.id as i64, user.first_name.clone()))
+}
+
+/// The reason the sender of `message` can't change what `id` can do, if they can't
+fn check_rank(bot: &Bot, message: &Message, id: i64) -> Option<String> {
+    let target = bot.roles.role(id);
+
+    (target >= bot.role(message)).then(|| {
+        format!(
+            "{} is {} {}, you can't change that",
+            id,
+            if target == Role::Admin { "an" } else { "the" },
+            target.name()
+        )
+    })
+}
+
+fn describe_user(id: i64, name: &str) -> String {
+    match name {
+        "" => id.to_string(),
+        name => format!("{} ({})", name, id),
+    }
+}
+
+fn list_users(bot: &Bot, role: Role) -> String {
+    let users: Vec<_> = bot
+        .roles
+        .users(role)
+        .map(|(id, name)| describe_user(id, name))
+        .collect();
+
+    if users.is_empty() {
+        "nobody".to_string()
+    } else {
+        users.join(", ")
+    }
+}
+
+fn allow(bot: &mut Bot, message: &Message, args: Args) -> Result<Option<String>> {
+    let reply = match args.rest {
+        "" => format!(
+            "{}. Allowed: {}",
+            if bot.roles.allowlist() {
+                "Only allowed users can use the bot"
+            } else {
+                "Everyone can use the bot, except banned users"
+            },
+            list_users(bot, Role::User)
+        ),
+        "listed" => {
+            bot.roles.set_allowlist(true);
+            "Only allowed users can use the bot now".to_string()
+        }
+        "everyone" => {
+            bot.roles.set_allowlist(false);
+            "Everyone can use the bot now, except banned users".to_string()
+        }
+        argument => {
+            let (remove, argument) = match argument.strip_prefix("remove") {
+                Some(rest) => (true, rest.trim()),
+                None => (false, argument),
+            };
+
+            let (id, name) = target(message, argument).ok_or(Usage)?;
+
+            if let Some(reason) = check_rank(bot, message, id) {
+                return Ok(Some(reason));
+            }
+
+            if remove {
+                if bot.roles.remove(id) {
+                    format!("{} is off the list", id)
+                } else {
+                    format!("{} isn't on the list", id)
+                }
+            } else {
+                match bot.roles.set(id, Role::User, &name) {
+                    Ok(()) => format!("{} can use the bot", describe_user(id, &name)),
+                    Err(reason) => reason,
+                }
+            }
+        }
+    };
+
+    Ok(Some(reply))
+}
+
+fn ban(bot: &mut Bot, message: &Message, args: Args) -> Result<Option<String>> {
+    if args.rest.is_empty() && message.reply_to_message.is_none() {
+        return Ok(Some(format!(
+            "Banned: {}. Use /ban <user id> or reply to one of their messages with /ban",
+            list_users(bot, Role::Banned)
+        )));
+    }
+
+    let Some((id, name)) = target(message, args.rest) else {
+        if !args.rest.is_empty() {
+            return Err(Usage.into());
+        }
+        return Ok(Some(
+            "Can't tell who sent that message, use /ban <user id>".to_string(),
+        ));
+    };
+
+    if let Some(reason) = check_rank(bot, message, id) {
+        return Ok(Some(reason));
+    }
+
+    let reply = match bot.roles.set(id, Role::Banned, &name) {
+        Ok(()) => format!(
+            "{} is banned, /allow {} to undo",
+            describe_user(id, &name),
+            id
+        ),
+        Err(reason) => reason,
+    };
+
+    Ok(Some(reply))
+}
+
+fn admins(bot: &mut Bot, message: &Message, args: Args) -> Result<Option<String>> {
+    let role = match args.word {
+        Some("add") => Role::Admin,
+        // still allowed, just not an admin anymore
+        Some(_) => Role::User,
+        None => {
+            return Ok(Some(format!(
+                "Admins: {}. Use /admins add or remove followed by a user id, or reply to one of their messages",
+                list_users(bot, Role::Admin)
+            )))
+        }
+    };
+
+    let (id, name) = target(message, args.rest).ok_or(Usage)?;
+
+    if let Some(reason) = check_rank(bot, message, id) {
+        return Ok(Some(reason));
+    }
+
+    if let Err(reason) = bot.roles.set(id, role, &name) {
+        return Ok(Some(reason));
+    }
+
+    // only works once they have talked to the bot
+    if let Err(err) = register(&bot.api, Some(id), role) {
+        warn!("{:?}", err);
+    }
+
+    let reply = match role {
+        Role::Admin => format!("{} is an admin", describe_user(id, &name)),
+        _ => format!("{} isn't an admin anymore", describe_user(id, &name)),
+    };
+
+    Ok(Some(reply))
+}
+
+fn rate_limit(bot: &mut Bot, _: &Message, args: Args) -> Result<Option<String>> {
+    let describe = |limit: Option<Limit>| match limit {
+        Some(limit) => limit.describe(),
+        None => "as often as they like".to_string(),
+    };
+
+    let (global, value) = match args.rest.strip_prefix("global") {
+        Some(value) => (true, value.trim()),
+        None => (false, args.rest),
+    };
+
+    let limit = match value {
+        "" => {
+            return Ok(Some(format!(
+                "Each user can change the panel {}, everyone together {}. Use /ratelimit <count> <seconds>, /ratelimit global <count> <seconds> or off to change it",
+                describe(bot.rate_limit.user_limit),
+                describe(bot.rate_limit.global_limit)
+            )))
+        }
+        "off" => None,
+        value => match Limit::parse(value) {
+            Ok(limit) => Some(limit),
+            Err(reason) => return Ok(Some(reason)),
+        },
+    };
+
+    let reply = if global {
+        bot.rate_limit.global_limit = limit;
+        format!("Everyone together can change the panel {}", describe(limit))
+    } else {
+        bot.rate_limit.user_limit = limit;
+        format!("Each user can change the panel {}", describe(limit))
+    };
+    bot.rate_limit.save();
+
+    Ok(Some(reply))
+}
+
+fn moderation(bot: &mut Bot, _: &Message, args: Args) -> Result<Option<String>> {
+    let reply = match args.word {
+        Some("on") => {
+            bot.moderation.set_enabled(true);
+            "Images from users will wait for approval, admins' images are shown right away"
+                .to_string()
+        }
+        Some(_) => {
+            bot.moderation.set_enabled(false);
+            "Images are shown without approval".to_string()
+        }
+        None => format!(
+            "Moderation is {}, {} images are waiting. Use /moderation on or off to change it",
+            if bot.moderation.enabled { "on" } else { "off" },
+            bot.moderation.len()
+        ),
+    };
+
+    Ok(Some(reply))
+}
+
+fn is_group(message: &Message) -> bool {
+    matches!(
+        message.chat.type_field,
+        ChatType::Group | ChatType::Supergroup
+    )
+}
+
+/// Whether the sender of `message` is an admin of the group it was sent in
+fn is_group_admin(bot: &Bot, message: &Message) -> Result<bool> {
+    // admins posting anonymously send as the group itself
+    if message
+        .sender_chat
+        .as_ref()
+        .is_some_and(|chat| chat.id == message.chat.id)
+    {
+        return Ok(true);
+    }
+
+    let Some(user) = &message.from else {
+        return Ok(false);
+    };
+
+    let member = bot
+        .api
+        .get_chat_member(
+            &GetChatMemberParams::builder()
+                .chat_id(message.chat.id)
+                .user_id(user.id)
+                .build(),
+        )
+        .context("Could not check if you are an admin of this group")?
+        .result;
+
+    Ok(matches!(
+        member,
+        ChatMember::Creator(_) | ChatMember::Administrator(_)
+    ))
+}
+
+fn group(bot: &mut Bot, message: &Message, args: Args) -> Result<Option<String>> {
+    if !is_group(message) {
+        return Ok(Some(
+            "Use /group on or off in a group to let its images on the panel or keep them off"
+                .to_string(),
+        ));
+    }
+
+    let enabled = match args.word {
+        Some(word) => word == "on",
+        None => {
+            return Ok(Some(format!(
+                "Images from this group are {} the panel, group admins can use /group on or off to change it",
+                if bot.groups.is_enabled(message.chat.id) {
+                    "shown on"
+                } else {
+                    "kept off"
+                }
+            )))
+        }
+    };
+
+    if bot.role(message) < Role::Admin && !is_group_admin(bot, message)? {
+        return Ok(Some(
+            "Only admins of this group can turn the panel on or off for it".to_string(),
+        ));
+    }
+
+    if let Err(reason) = bot.groups.set_enabled(message.chat.id, enabled) {
+        return Ok(Some(reason));
+    }
+
+    let reply = match (enabled, bot.groups.policy) {
+        (false, _) => "Images from this group won't be shown anymore".to_string(),
+        (true, GroupPolicy::Mention) => {
+            "Images from this group that mention or reply to the bot will be shown".to_string()
+        }
+        (true, _) => "Images from this group will be shown".to_string(),
+    };
+
+    Ok(Some(reply))
+}
+
+fn groups(bot: &mut Bot, _: &Message, args: Args) -> Result<Option<String>> {
+    if args.rest.is_empty() {
+        return Ok(Some(format!(
+            "Group policy is {}, use /groups all, mention or listed to change it. Group admins can still turn their group on or off with /group",
+            bot.groups.policy.name()
+        )));
+    }
+
+    let policy = GroupPolicy::parse(args.rest).ok_or(Usage)?;
+    bot.groups.set_policy(policy);
+
+    let reply = match policy {
+        GroupPolicy::All => "Every image posted in groups will be shown",
+        GroupPolicy::Mention => {
+            "Images from groups will be shown when they mention or reply to the bot, or someone replies to them mentioning it"
+        }
+        GroupPolicy::Listed => "Only images from groups turned on with /group on will be shown",
+    };
+
+    Ok(Some(reply.to_string()))
+}
@@ -0,0 +1,168 @@
+use std::fs;
+
+use image::{Rgb, RgbImage, RgbaImage};
+use log::warn;
+
+use crate::scaling::{parse_color, PANEL_HEIGHT, PANEL_WIDTH};
+use crate::storage;
+
+/// The `/background`, as `parse` takes it
+const BACKGROUND_FILE: &str = "background";
+
+/// What shows through the transparent parts of an image
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Background {
+    Solid(Rgb<u8>),
+    /// Vertical gradient, top color first
+    Gradient(Rgb<u8>, Rgb<u8>),
+    /// Whatever the panel was showing before
+    Previous,
+}
+
+impl Background {
+    /// Parses `#rrggbb`, `#rrggbb #rrggbb` or `previous`
+    pub fn parse(text: &str) -> Option<Background> {
+        let mut words = text.split_whitespace();
+
+        match (words.next()?, words.next(), words.next()) {
+            ("previous", None, None) => Some(Background::Previous),
+            (color, None, None) => Some(Background::Solid(parse_color(color)?)),
+            (top, Some(bottom), None) => Some(Background::Gradient(
+                parse_color(top)?,
+                parse_color(bottom)?,
+            )),
+            _ => None,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            Background::Solid(color) => format_color(*color),
+            Background::Gradient(top, bottom) => {
+                format!("{} to {}", format_color(*top), format_color(*bottom))
+            }
+            Background::Previous => "the previous image".to_string(),
+        }
+    }
+
+    /// The one set with `/background`, black if it was never set
+    pub fn load() -> Background {
+        fs::read_to_string(storage::path(BACKGROUND_FILE))
+            .ok()
+            .and_then(|text| Background::parse(&text))
+            .unwrap_or(Background::Solid(Rgb([0, 0, 0])))
+    }
+
+    pub fn save(&self) {
+        if let Err(err) = fs::write(storage::path(BACKGROUND_FILE), self.setting()) {
+            warn!("Could not save the background: {:?}", err);
+        }
+    }
+
+    /// The way `parse` takes it back
+    fn setting(&self) -> String {
+        match self {
+            Background::Solid(color) => format_color(*color),
+            Background::Gradient(top, bottom) => {
+                format!("{} {}", format_color(*top), format_color(*bottom))
+            }
+            Background::Previous => "previous".to_string(),
+        }
+    }
+
+    /// Draws the background at panel size, `previous` is the image currently on the panel
+    pub fn render(&self, previous: &RgbImage) -> RgbImage {
+        match *self {
+            Background::Solid(color) => RgbImage::from_pixel(PANEL_WIDTH, PANEL_HEIGHT, color),
+            Background::Gradient(top, bottom) => {
+                RgbImage::from_fn(PANEL_WIDTH, PANEL_HEIGHT, |_, y| {
+                    let t = y * 255 / (PANEL_HEIGHT - 1);
+                    Rgb(std::array::from_fn(|c| {
+                        ((top[c] as u32 * (255 - t) + bottom[c] as u32 * t + 127) / 255) as u8
+                    }))
+                })
+            }
+            Background::Previous => previous.clone(),
+        }
+    }
+}
+
+fn format_color(color: Rgb<u8>) -> String {
+    format!("#{:02x}{:02x}{:02x}", color[0], color[1], color[2])
+}
+
+/// Blends a premultiplied foreground over an opaque background of the same size
+pub fn composite(foreground: &RgbaImage, background: &RgbImage) -> RgbImage {
+    assert!(foreground.dimensions() == background.dimensions());
+
+    let mut output = background.clone();
+
+    for (out, fg) in output.pixels_mut().zip(foreground.pixels()) {
+        let transparency = 255 - fg[3] as u32;
+        for c in 0..3 {
+            let blended = fg[c] as u32 + (out[c] as u32 * transparency + 127) / 255;
+            out[c] = blended.min(255) as u8;
+        }
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, Rgba};
+
+    use super::*;
+    use crate::scaling::{fit, ScaleMode};
+
+    #[test]
+    fn transparent_pixels_show_only_the_background() {
+        let foreground = RgbaImage::from_pixel(2, 2, Rgba([0, 0, 0, 0]));
+        let background = RgbImage::from_pixel(2, 2, Rgb([10, 200, 30]));
+
+        assert_eq!(composite(&foreground, &background), background);
+    }
+
+    #[test]
+    fn opaque_pixels_hide_the_background() {
+        let foreground = RgbaImage::from_pixel(2, 2, Rgba([250, 5, 128, 255]));
+        let background = RgbImage::from_pixel(2, 2, Rgb([10, 200, 30]));
+
+        let output = composite(&foreground, &background);
+        assert!(output.pixels().all(|pixel| *pixel == Rgb([250, 5, 128])));
+    }
+
+    #[test]
+    fn half_transparent_edges_have_no_dark_halo() {
+        // white next to a transparent black pixel, averaged into one half covered pixel
+        let mut edge = RgbaImage::new(2, 1);
+        edge.put_pixel(0, 0, Rgba([255, 255, 255, 255]));
+        edge.put_pixel(1, 0, Rgba([0, 0, 0, 0]));
+
+        let scaled = fit(&DynamicImage::ImageRgba8(edge), ScaleMode::Stretch, 1, 1);
+        assert_eq!(scaled.get_pixel(0, 0)[3], 128);
+
+        let white = RgbImage::from_pixel(1, 1, Rgb([255, 255, 255]));
+        assert_eq!(
+            *composite(&scaled, &white).get_pixel(0, 0),
+            Rgb([255, 255, 255])
+        );
+
+        let black = RgbImage::from_pixel(1, 1, Rgb([0, 0, 0]));
+        assert_eq!(
+            *composite(&scaled, &black).get_pixel(0, 0),
+            Rgb([128, 128, 128])
+        );
+    }
+
+    #[test]
+    fn parses_what_it_saves() {
+        for text in ["#102030", "#ff0000 #0000ff", "previous"] {
+            let background = Background::parse(text).unwrap();
+            assert_eq!(Background::parse(&background.setting()), Some(background));
+        }
+
+        assert_eq!(Background::parse("#ff0000 #00ff00 #0000ff"), None);
+        assert_eq!(Background::parse(""), None);
+    }
+}
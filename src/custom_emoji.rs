@@ -0,0 +1,154 @@
+use frankenstein::{Message, MessageEntityType};
+
+use crate::scaling::{PANEL_HEIGHT, PANEL_WIDTH};
+
+/// More than this and every emoji would be a handful of pixels
+pub const MAX_EMOJI: usize = 16;
+
+/// Returns the custom emoji ids of a message made only of custom emoji,
+/// `None` if the message has any other text in it
+pub fn custom_emoji_ids(message: &Message) -> Option<Vec<String>> {
+    let text = message.text.as_deref()?;
+    let entities = message.entities.as_deref()?;
+
+    let emoji: Vec<_> = entities
+        .iter()
+        .filter(|entity| entity.type_field == MessageEntityType::CustomEmoji)
+        .filter_map(|entity| Some((entity, entity.custom_emoji_id.clone()?)))
+        .collect();
+
+    if emoji.is_empty() {
+        return None;
+    }
+
+    // entity offsets count UTF-16 code units
+    let mut offset = 0;
+    for c in text.chars() {
+        let covered = emoji.iter().any(|(entity, _)| {
+            let start = entity.offset as usize;
+            (start..start + entity.length as usize).contains(&offset)
+        });
+
+        if !covered && !c.is_whitespace() {
+            return None;
+        }
+
+        offset += c.len_utf16();
+    }
+
+    Some(
+        emoji
+            .into_iter()
+            .map(|(_, id)| id)
+            .take(MAX_EMOJI)
+            .collect(),
+    )
+}
+
+/// Square cells laid out in rows, centered on the panel
+pub struct Grid {
+    pub columns: u32,
+    pub rows: u32,
+    pub cell_size: u32,
+}
+
+impl Grid {
+    pub fn new(count: usize) -> Grid {
+        let count = count.max(1) as u32;
+
+        let mut columns = 1;
+        while columns * columns < count {
+            columns += 1;
+        }
+        let rows = count.div_ceil(columns);
+
+        Grid {
+            columns,
+            rows,
+            cell_size: (PANEL_WIDTH / columns).min(PANEL_HEIGHT / rows),
+        }
+    }
+
+    /// Top left corner of the `index`th cell
+    pub fn cell_origin(&self, index: usize) -> (u32, u32) {
+        let index = index as u32;
+        let x_margin = (PANEL_WIDTH - self.columns * self.cell_size) / 2;
+        let y_margin = (PANEL_HEIGHT - self.rows * self.cell_size) / 2;
+
+        (
+            x_margin + index % self.columns * self.cell_size,
+            y_margin + index / self.columns * self.cell_size,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn message(text: &str, entities: Value) -> Message {
+        serde_json::from_value(json!({
+            "message_id": 7,
+            "date": 0,
+            "chat": { "id": 42, "type": "private" },
+            "text": text,
+            "entities": entities,
+        }))
+        .unwrap()
+    }
+
+    fn custom_emoji(offset: u16, length: u16, id: &str) -> Value {
+        json!({ "type": "custom_emoji", "offset": offset, "length": length, "custom_emoji_id": id })
+    }
+
+    #[test]
+    fn counts_offsets_in_utf16() {
+        // each placeholder is a surrogate pair, the second entity starts at 2 and not at 1
+        let message = message(
+            "🎉🐱 🐶",
+            json!([
+                custom_emoji(0, 2, "party"),
+                custom_emoji(2, 2, "cat"),
+                custom_emoji(5, 2, "dog"),
+            ]),
+        );
+
+        assert_eq!(
+            custom_emoji_ids(&message),
+            Some(vec![
+                "party".to_string(),
+                "cat".to_string(),
+                "dog".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn refuses_messages_with_plain_emoji_or_text() {
+        let plain_emoji_first = message("😀🐱", json!([custom_emoji(2, 2, "cat")]));
+        assert_eq!(custom_emoji_ids(&plain_emoji_first), None);
+
+        let plain_emoji_last = message("🐱😀", json!([custom_emoji(0, 2, "cat")]));
+        assert_eq!(custom_emoji_ids(&plain_emoji_last), None);
+
+        let text = message("hi 🐱", json!([custom_emoji(3, 2, "cat")]));
+        assert_eq!(custom_emoji_ids(&text), None);
+
+        let bold = message("hi", json!([{ "type": "bold", "offset": 0, "length": 2 }]));
+        assert_eq!(custom_emoji_ids(&bold), None);
+    }
+
+    #[test]
+    fn lays_out_a_square_grid() {
+        let grid = Grid::new(5);
+        assert_eq!((grid.columns, grid.rows, grid.cell_size), (3, 2, 21));
+        assert_eq!(grid.cell_origin(0), (0, 11));
+        assert_eq!(grid.cell_origin(4), (21, 32));
+
+        let single = Grid::new(1);
+        assert_eq!(single.cell_size, 64);
+        assert_eq!(single.cell_origin(0), (0, 0));
+    }
+}
@@ -0,0 +1,315 @@
+use std::io::{Cursor, Read};
+use std::num::NonZeroU64;
+
+use image::{ImageFormat, ImageReader, Limits, RgbaImage};
+use log::info;
+use thiserror::Error;
+
+use crate::media;
+use crate::scaling::{self, Placement, ScaleMode};
+
+/// Most a whole decoded frame can take, for the formats that can't be scaled
+/// row by row. Fits in PSRAM next to the panel's own buffers
+pub const MAX_FRAME_BYTES: u64 = 4 * 1024 * 1024;
+
+/// Longest side scaled at all, even rows streamed one at a time need
+/// buffers and coordinates that grow with it
+pub const MAX_SIDE: u32 = 16_384;
+
+#[derive(Error, Debug)]
+pub enum DecodeError {
+    #[error("{0}")]
+    Unsupported(String),
+    #[error("This image is {width}x{height}, too big to decode here. Send it as a photo instead of a file and Telegram will shrink it")]
+    TooLarge { width: u32, height: u32 },
+    #[error("broken image: {0}")]
+    Image(#[from] image::ImageError),
+    #[error("broken PNG: {0}")]
+    Png(#[from] png::DecodingError),
+    #[error("broken GIF: {0}")]
+    Gif(#[from] gif::DecodingError),
+    #[error("download interrupted: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Decodes an image while it is being read and scales it to a `width` x `height` area,
+/// the result is premultiplied RGBA as returned by [`crate::scaling::fit`].
+///
+/// PNG and GIF are decoded one row at a time straight into the scaler,
+/// WebP and JPEG decoders need the whole file so they are buffered first.
+/// Images are measured before anything as big as them is allocated,
+/// the ones that wouldn't fit are refused with [`DecodeError::TooLarge`].
+pub fn decode_scaled(
+    mut reader: impl Read,
+    size_hint: Option<u64>,
+    mode: ScaleMode,
+    width: u32,
+    height: u32,
+) -> Result<RgbaImage, DecodeError> {
+    // enough for every magic number we know about
+    let mut header = [0u8; 16];
+    let mut header_len = 0;
+    while header_len < header.len() {
+        match reader.read(&mut header[header_len..])? {
+            0 => break,
+            bytes_read => header_len += bytes_read,
+        }
+    }
+    let header = &header[..header_len];
+
+    let format = media::sniff_format(header).map_err(DecodeError::Unsupported)?;
+    info!("Decoding {:?} image", format);
+
+    let mut reader = Cursor::new(header).chain(reader);
+
+    match format {
+        ImageFormat::Png => decode_png(reader, mode, width, height),
+        ImageFormat::Gif => decode_gif(reader, mode, width, height),
+        _ => {
+            let mut data = Vec::with_capacity(size_hint.unwrap_or_default() as usize);
+            reader.read_to_end(&mut data)?;
+
+            let (image_width, image_height) =
+                ImageReader::with_format(Cursor::new(&data), format).into_dimensions()?;
+            check_size(image_width, image_height, true)?;
+
+            // in case the header lied about the size
+            let mut limits = Limits::default();
+            limits.max_image_width = Some(MAX_SIDE);
+            limits.max_image_height = Some(MAX_SIDE);
+            limits.max_alloc = Some(MAX_FRAME_BYTES);
+
+            let mut image_reader = ImageReader::with_format(Cursor::new(&data), format);
+            image_reader.limits(limits);
+            let image = image_reader.decode()?;
+            drop(data);
+
+            Ok(scaling::fit(&image, mode, width, height))
+        }
+    }
+}
+
+fn decode_png(
+    reader: impl Read,
+    mode: ScaleMode,
+    area_width: u32,
+    area_height: u32,
+) -> Result<RgbaImage, DecodeError> {
+    let limits = png::Limits {
+        bytes: MAX_FRAME_BYTES as usize,
+    };
+    let mut decoder = png::Decoder::new_with_limits(reader, limits);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+
+    let mut reader = decoder.read_info()?;
+    let (width, height) = (reader.info().width, reader.info().height);
+    let (color_type, _) = reader.output_color_type();
+    check_size(width, height, reader.info().interlaced)?;
+
+    let placement = Placement::new(width, height, mode, area_width, area_height);
+    let mut downscaler = placement.downscaler();
+    let mut rgba = vec![0u8; width as usize * 4];
+
+    if reader.info().interlaced {
+        // interlaced rows come out of order, only the whole frame makes sense
+        let mut frame = vec![0u8; reader.output_buffer_size()];
+        reader.next_frame(&mut frame)?;
+
+        for row in frame.chunks_exact(reader.output_line_size(width)) {
+            png_row_to_rgba(color_type, row, &mut rgba);
+            downscaler.push_row(&rgba);
+        }
+    } else {
+        while let Some(row) = reader.next_row()? {
+            png_row_to_rgba(color_type, row.data(), &mut rgba);
+            downscaler.push_row(&rgba);
+        }
+    }
+
+    Ok(placement.place(&downscaler.finish()))
+}
+
+/// Refuses images with a side over [`MAX_SIDE`], or when the whole frame has
+/// to be held, more than [`MAX_FRAME_BYTES`] of RGBA
+fn check_size(width: u32, height: u32, whole_frame: bool) -> Result<(), DecodeError> {
+    let frame_bytes = width as u64 * height as u64 * 4;
+
+    if width > MAX_SIDE || height > MAX_SIDE || (whole_frame && frame_bytes > MAX_FRAME_BYTES) {
+        return Err(DecodeError::TooLarge { width, height });
+    }
+
+    Ok(())
+}
+
+fn png_row_to_rgba(color_type: png::ColorType, row: &[u8], rgba: &mut [u8]) {
+    let pixels = rgba.chunks_exact_mut(4);
+
+    match color_type {
+        png::ColorType::Rgba => rgba.copy_from_slice(&row[..rgba.len()]),
+        png::ColorType::Rgb => {
+            for (out, rgb) in pixels.zip(row.chunks_exact(3)) {
+                out.copy_from_slice(&[rgb[0], rgb[1], rgb[2], 255]);
+            }
+        }
+        png::ColorType::GrayscaleAlpha => {
+            for (out, ga) in pixels.zip(row.chunks_exact(2)) {
+                out.copy_from_slice(&[ga[0], ga[0], ga[0], ga[1]]);
+            }
+        }
+        // indexed images are expanded to RGB(A) by the decoder
+        png::ColorType::Grayscale | png::ColorType::Indexed => {
+            for (out, &gray) in pixels.zip(row) {
+                out.copy_from_slice(&[gray, gray, gray, 255]);
+            }
+        }
+    }
+}
+
+/// Only the first frame is shown
+fn decode_gif(
+    reader: impl Read,
+    mode: ScaleMode,
+    area_width: u32,
+    area_height: u32,
+) -> Result<RgbaImage, DecodeError> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::RGBA);
+    // frames can be bigger than the size the GIF says it is
+    if let Some(limit) = NonZeroU64::new(MAX_FRAME_BYTES) {
+        options.set_memory_limit(gif::MemoryLimit::Bytes(limit));
+    }
+
+    let mut decoder = options.read_info(reader)?;
+    let (width, height) = (decoder.width() as u32, decoder.height() as u32);
+    // the decoder holds the whole first frame
+    check_size(width, height, true)?;
+
+    let frame = decoder
+        .read_next_frame()?
+        .ok_or_else(|| DecodeError::Unsupported("This GIF has no frames".to_string()))?;
+
+    let placement = Placement::new(width, height, mode, area_width, area_height);
+    let mut downscaler = placement.downscaler();
+
+    // the frame can be smaller than the GIF, the rest of the screen is transparent
+    let (left, top) = (frame.left as usize, frame.top as usize);
+    let frame_width = frame.width as usize;
+    let mut rgba = vec![0u8; width as usize * 4];
+
+    for y in 0..height as usize {
+        rgba.fill(0);
+
+        if y >= top && y < top + frame.height as usize {
+            let row = &frame.buffer[(y - top) * frame_width * 4..][..frame_width * 4];
+            let end = ((left + frame_width) * 4).min(rgba.len());
+            if left * 4 < end {
+                rgba[left * 4..end].copy_from_slice(&row[..end - left * 4]);
+            }
+        }
+
+        downscaler.push_row(&rgba);
+    }
+
+    Ok(placement.place(&downscaler.finish()))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::codecs::jpeg::JpegEncoder;
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+
+    fn jpeg(width: u32, height: u32) -> Vec<u8> {
+        let image = RgbImage::from_pixel(width, height, Rgb([200, 40, 90]));
+        let mut data = Vec::new();
+        JpegEncoder::new(&mut data).encode_image(&image).unwrap();
+        data
+    }
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut encoder = png::Encoder::new(&mut data, width, height);
+        encoder.set_color(png::ColorType::Rgb);
+        let mut writer = encoder.write_header().unwrap();
+        let mut stream = writer.stream_writer().unwrap();
+        let row = [200u8, 40, 90].repeat(width as usize);
+        for _ in 0..height {
+            std::io::Write::write_all(&mut stream, &row).unwrap();
+        }
+        stream.finish().unwrap();
+        drop(writer);
+        data
+    }
+
+    fn gif(width: u16, height: u16) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut encoder = gif::Encoder::new(&mut data, width, height, &[200, 40, 90]).unwrap();
+        let frame = gif::Frame {
+            width: 1,
+            height: 1,
+            buffer: vec![0].into(),
+            ..Default::default()
+        };
+        encoder.write_frame(&frame).unwrap();
+        drop(encoder);
+        data
+    }
+
+    fn decode(data: &[u8]) -> Result<RgbaImage, DecodeError> {
+        decode_scaled(data, Some(data.len() as u64), ScaleMode::Contain, 64, 64)
+    }
+
+    #[test]
+    fn decodes_images_that_fit() {
+        for data in [
+            jpeg(640, 480),
+            jpeg(1024, 1024),
+            png(640, 480),
+            png(12_000, 2),
+        ] {
+            let scaled = decode(&data).unwrap();
+            assert_eq!(scaled.dimensions(), (64, 64));
+        }
+
+        let scaled = decode(&jpeg(640, 480)).unwrap();
+        let center = scaled.get_pixel(32, 32);
+        assert!(center[0].abs_diff(200) < 4 && center[3] == 255);
+    }
+
+    #[test]
+    fn refuses_a_phone_photo_sent_as_a_file() {
+        assert!(matches!(
+            decode(&jpeg(4000, 3000)),
+            Err(DecodeError::TooLarge {
+                width: 4000,
+                height: 3000
+            })
+        ));
+    }
+
+    #[test]
+    fn refuses_sides_past_the_limit() {
+        assert!(matches!(
+            decode(&png(MAX_SIDE + 1, 1)),
+            Err(DecodeError::TooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn refuses_huge_gif_screens() {
+        // a few bytes that would need 16 GB for their first frame
+        assert!(matches!(
+            decode(&gif(u16::MAX, u16::MAX)),
+            Err(DecodeError::TooLarge { .. })
+        ));
+        assert!(decode(&gif(640, 480)).is_ok());
+    }
+
+    #[test]
+    fn checks_whole_frames_only_when_they_are_held() {
+        assert!(check_size(2000, 2000, false).is_ok());
+        assert!(check_size(2000, 2000, true).is_err());
+        assert!(check_size(1024, 1024, true).is_ok());
+    }
+}
@@ -0,0 +1,274 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use image::{Pixel, RgbImage};
+
+use crate::boot_image::{BootImage, LastFrame};
+use crate::clock;
+use crate::hub75::Hub75;
+use crate::scene::Scene;
+use crate::transition::{self, Transition};
+
+enum Command {
+    Show(RgbImage),
+    Play(Box<dyn Scene>),
+    SetTransition(Transition),
+    /// 0 is off, 255 full
+    SetBrightness(u8),
+    Pause(bool),
+    /// A POSIX TZ string, applied here since the clocks read it on this thread
+    SetTimezone(String),
+    /// Whether images shown are saved for the next boot
+    SetBootImage(BootImage),
+}
+
+/// Handle to the thread that turns frames into GPIO states.
+///
+/// Rendering a frame takes tens of milliseconds, so scenes and transitions
+/// are stepped there and the Telegram polling loop only tells it what to show.
+#[derive(Clone)]
+pub struct Display {
+    commands: Sender<Command>,
+    /// The frame the GPIO states were last rendered from, before brightness
+    shown: Arc<Mutex<RgbImage>>,
+    paused: Arc<AtomicBool>,
+}
+
+impl Display {
+    /// Takes over the panel, `states` is what the fb writer thread is sending
+    /// out and `first_frame` what they were rendered from, loaded for `boot_image`
+    pub fn start(
+        hub75: Hub75<'static>,
+        states: Arc<RwLock<Vec<u32>>>,
+        first_frame: RgbImage,
+        boot_image: BootImage,
+    ) -> Display {
+        let (commands, receiver) = mpsc::channel();
+        let shown = Arc::new(Mutex::new(first_frame.clone()));
+        let paused = Arc::new(AtomicBool::new(false));
+
+        let shown_clone = shown.clone();
+        let paused_clone = paused.clone();
+        std::thread::Builder::new()
+            .name("display".to_string())
+            .stack_size(16 * 1024)
+            .spawn(move || {
+                run(
+                    hub75,
+                    &states,
+                    &shown_clone,
+                    &paused_clone,
+                    first_frame,
+                    boot_image,
+                    receiver,
+                )
+            })
+            .unwrap();
+
+        Display {
+            commands,
+            shown,
+            paused,
+        }
+    }
+
+    /// What the panel is showing right now, halfway through a transition included
+    pub fn current_frame(&self) -> RgbImage {
+        self.shown.lock().unwrap().clone()
+    }
+
+    /// Shows a still frame, stopping any scene, and remembers it for the next boot
+    pub fn show(&self, frame: RgbImage) {
+        self.paused.store(false, Ordering::Relaxed);
+        self.commands.send(Command::Show(frame)).ok();
+    }
+
+    /// Replaces whatever is on the panel with a scene
+    pub fn play(&self, scene: impl Scene + 'static) {
+        self.paused.store(false, Ordering::Relaxed);
+        self.commands.send(Command::Play(Box::new(scene))).ok();
+    }
+
+    /// How the panel goes from one image or scene to the next
+    pub fn set_transition(&self, transition: Transition) {
+        self.commands.send(Command::SetTransition(transition)).ok();
+    }
+
+    /// Scales every color, 0 blanks the panel while scenes keep going underneath
+    pub fn set_brightness(&self, brightness: u8) {
+        self.commands.send(Command::SetBrightness(brightness)).ok();
+    }
+
+    /// Freezes the scene on its current frame, or lets it carry on from there.
+    /// Anything new on the panel resumes
+    pub fn pause(&self, paused: bool) {
+        // set right away so it reads back as expected, the thread corrects it
+        // if there is nothing to pause
+        self.paused.store(paused, Ordering::Relaxed);
+        self.commands.send(Command::Pause(paused)).ok();
+    }
+
+    /// Changes the timezone clocks are drawn in
+    pub fn set_timezone(&self, timezone: String) {
+        self.commands.send(Command::SetTimezone(timezone)).ok();
+    }
+
+    /// Starts or stops saving the images shown, for [`BootImage::Last`]
+    pub fn set_boot_image(&self, boot_image: BootImage) {
+        self.commands.send(Command::SetBootImage(boot_image)).ok();
+    }
+
+    /// Whether a scene is frozen, a still image is never paused
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+}
+
+fn run(
+    mut hub75: Hub75<'static>,
+    states: &RwLock<Vec<u32>>,
+    published: &Mutex<RgbImage>,
+    published_paused: &AtomicBool,
+    first_frame: RgbImage,
+    boot_image: BootImage,
+    commands: Receiver<Command>,
+) {
+    let mut draw = |frame: &RgbImage, brightness: u8| {
+        let new_states = if brightness == u8::MAX {
+            hub75.render_unoptimized(frame)
+        } else {
+            let mut dimmed = frame.clone();
+            for pixel in dimmed.pixels_mut() {
+                pixel.apply(|c| (c as u32 * brightness as u32 / 255) as u8);
+            }
+            hub75.render_unoptimized(&dimmed)
+        };
+        *states.write().unwrap() = new_states;
+        published.lock().unwrap().clone_from(frame);
+    };
+
+    // what the panel booted with is already on flash
+    let mut last_frame = LastFrame::on_storage(
+        boot_image,
+        (boot_image == BootImage::Last).then_some(&first_frame),
+    );
+
+    let mut transition = Transition::default();
+    let mut brightness = u8::MAX;
+
+    // what is on the panel, and what will be once the transition is over
+    let mut shown = first_frame.clone();
+    let mut target = first_frame;
+
+    let mut scene: Option<(Box<dyn Scene>, Instant)> = None;
+    let mut scene_due: Option<Instant> = None;
+    // the frame the transition started from
+    let mut fading: Option<(RgbImage, Instant)> = None;
+    let mut drawn_at = Instant::now();
+    // when the scene was frozen, it gets that much later to make up for it
+    let mut paused_at: Option<Instant> = None;
+
+    loop {
+        let fade_due = fading.as_ref().map(|_| drawn_at + transition::FRAME_TIME);
+        let due = scene_due
+            .into_iter()
+            .chain(fade_due)
+            .chain(last_frame.due());
+        let command = match due.min() {
+            Some(at) => commands.recv_timeout(at.saturating_duration_since(Instant::now())),
+            None => commands.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+
+        let mut changed = false;
+        match command {
+            Ok(Command::Show(frame)) => {
+                scene = None;
+                scene_due = None;
+                paused_at = None;
+                last_frame.shown(&frame, Instant::now());
+                target = frame;
+                fading = (!transition.is_cut()).then(|| (shown.clone(), Instant::now()));
+                changed = true;
+            }
+            Ok(Command::Play(new_scene)) => {
+                paused_at = None;
+                scene = Some((new_scene, Instant::now()));
+                scene_due = Some(Instant::now());
+                fading = (!transition.is_cut()).then(|| (shown.clone(), Instant::now()));
+            }
+            Ok(Command::SetTransition(new_transition)) => transition = new_transition,
+            Ok(Command::SetBrightness(new_brightness)) => {
+                brightness = new_brightness;
+                draw(&shown, brightness);
+            }
+            Ok(Command::Pause(true)) => {
+                if scene.is_some() && paused_at.is_none() {
+                    paused_at = Some(Instant::now());
+                    scene_due = None;
+                }
+            }
+            Ok(Command::Pause(false)) => {
+                if let (Some(at), Some((_, started))) = (paused_at.take(), &mut scene) {
+                    *started += at.elapsed();
+                    scene_due = Some(Instant::now());
+                }
+            }
+            Ok(Command::SetTimezone(timezone)) => {
+                clock::apply_timezone(&timezone);
+                // a clock on the panel shows the new time right away
+                if scene.is_some() && paused_at.is_none() {
+                    scene_due = Some(Instant::now());
+                }
+            }
+            Ok(Command::SetBootImage(boot_image)) => {
+                last_frame.set_mode(boot_image);
+                if scene.is_none() {
+                    last_frame.shown(&target, Instant::now());
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+        published_paused.store(paused_at.is_some(), Ordering::Relaxed);
+        last_frame.save_if_due(Instant::now());
+
+        if let (Some((active, started)), Some(due)) = (&mut scene, scene_due) {
+            if due <= Instant::now() {
+                // positions come from the elapsed time, so a slow render drops
+                // frames instead of slowing the scene down
+                let stepped_at = Instant::now();
+                let (frame, stays_for) = active.frame(started.elapsed());
+                target = frame;
+                changed = true;
+
+                scene_due = stays_for.map(|duration| stepped_at + duration);
+                if scene_due.is_none() {
+                    scene = None;
+                }
+            }
+        }
+
+        // a scene keeps moving under the transition
+        if let Some((from, started)) = &fading {
+            drawn_at = Instant::now();
+            match transition.progress(started.elapsed()) {
+                Some(progress) => {
+                    shown = transition.blend(from, &target, progress);
+                    draw(&shown, brightness);
+                    continue;
+                }
+                None => {
+                    fading = None;
+                    changed = true;
+                }
+            }
+        }
+
+        if changed {
+            shown = target.clone();
+            draw(&shown, brightness);
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use embedded_svc::http::{
+    client::{Client, Response},
+    Headers, Method,
+};
+use esp_idf_hal::io::EspIOError;
+use esp_idf_svc::http::client::EspHttpConnection;
+use log::info;
+use thiserror::Error;
+
+use crate::decode::DecodeError;
+
+/// Biggest file we are willing to download, way more than any sticker or photo needs
+pub const MAX_FILE_SIZE: u64 = 4 * 1024 * 1024;
+
+#[derive(Error, Debug)]
+pub enum DownloadError {
+    #[error("the file is {size} bytes, the limit is {max} bytes")]
+    TooBig { size: u64, max: u64 },
+    #[error("download failed with status code {0}")]
+    Status(u16),
+    #[error("ESP error")]
+    Esp(#[from] esp_idf_sys::EspError),
+    #[error("IO error")]
+    Io(#[from] EspIOError),
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+}
+
+/// Refuses files bigger than `max_size`, before downloading anything
+pub fn check_size(size: Option<u64>, max_size: u64) -> Result<(), DownloadError> {
+    match size {
+        Some(size) if size > max_size => Err(DownloadError::TooBig {
+            size,
+            max: max_size,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Response body as a `std::io::Read`, failing once more than `max_size` bytes come through
+struct HttpBody<'a> {
+    response: Response<&'a mut EspHttpConnection>,
+    bytes_read: u64,
+    max_size: u64,
+}
+
+impl std::io::Read for HttpBody<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let bytes_read = self
+            .response
+            .read(buf)
+            .map_err(|err| std::io::Error::other(format!("{err:?}")))?;
+
+        self.bytes_read += bytes_read as u64;
+        if self.bytes_read > self.max_size {
+            return Err(std::io::Error::other(format!(
+                "the file is bigger than {} bytes",
+                self.max_size
+            )));
+        }
+
+        Ok(bytes_read)
+    }
+}
+
+/// Downloads `url` and hands the body to `read` while it is still arriving,
+/// together with the `Content-Length` if the server sent one
+pub fn download<T>(
+    url: &str,
+    max_size: u64,
+    read: impl FnOnce(&mut dyn std::io::Read, Option<u64>) -> Result<T, DecodeError>,
+) -> Result<T, DownloadError> {
+    info!("Downloading file from {}", url);
+
+    let mut client = Client::wrap(EspHttpConnection::new(&Default::default())?);
+
+    let headers = [("accept", "image/*")];
+
+    let request = client.request(Method::Get, url, &headers)?;
+
+    let response = request.submit()?;
+
+    let status = response.status();
+    info!("<- {status}");
+
+    if status != 200 {
+        return Err(DownloadError::Status(status));
+    }
+
+    let content_length = response.content_len();
+    check_size(content_length, max_size)?;
+
+    let mut body = HttpBody {
+        response,
+        bytes_read: 0,
+        max_size,
+    };
+
+    let result = read(&mut body, content_length)?;
+
+    info!("Downloaded {} bytes", body.bytes_read);
+
+    Ok(result)
+}
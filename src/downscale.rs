@@ -0,0 +1,393 @@
+use image::RgbaImage;
+
+/// Part of the source image that ends up on the panel
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Crop {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Source pixels covered by one output column, with their coverage
+struct ColumnSpan {
+    first: u32,
+    weights: Vec<u32>,
+}
+
+/// Area averaging resampler working on integers only.
+///
+/// Source rows are pushed one at a time as they come out of the decoder, so the
+/// full size image never needs to be in memory. Every output pixel is the
+/// average of the source area it covers, weighted by how much of each source
+/// pixel falls inside it. Colors are premultiplied by alpha before averaging
+/// and the output is premultiplied RGBA.
+///
+/// Coordinates are scaled so that both pixel grids land on integers: a source
+/// pixel is `dst` units long and an output pixel is `src` units long. They and
+/// the sums are kept in 64 bits, a few hundred thousand source pixels would
+/// overflow 32 and PNG allows far more.
+pub struct BoxDownscaler {
+    crop: Crop,
+    width: u32,
+    height: u32,
+    columns: Vec<ColumnSpan>,
+    /// Source row pushed next
+    row: u32,
+    /// Current row after the horizontal pass, 8 bits of fraction
+    horizontal: Vec<u32>,
+    /// Sum of the horizontal rows covered by the output row being built
+    vertical: Vec<u64>,
+    /// RGB rows converted to RGBA, kept to not allocate one per row
+    rgba_row: Vec<u8>,
+    output: RgbaImage,
+}
+
+impl BoxDownscaler {
+    pub fn new(crop: Crop, width: u32, height: u32) -> BoxDownscaler {
+        assert!(crop.width > 0 && crop.height > 0 && width > 0 && height > 0);
+
+        let columns = (0..width as u64)
+            .map(|x| {
+                let (width, crop_width) = (width as u64, crop.width as u64);
+                let start = x * crop_width;
+                let end = start + crop_width;
+                let first = start / width;
+                let last = (end - 1) / width;
+
+                // a source pixel is `width` units long, so no weight is more than that
+                let weights = (first..=last)
+                    .map(|source| overlap(start, end, source * width, (source + 1) * width) as u32)
+                    .collect();
+
+                ColumnSpan {
+                    first: first as u32,
+                    weights,
+                }
+            })
+            .collect();
+
+        BoxDownscaler {
+            crop,
+            width,
+            height,
+            columns,
+            row: 0,
+            horizontal: vec![0; width as usize * 4],
+            vertical: vec![0; width as usize * 4],
+            rgba_row: Vec::new(),
+            output: RgbaImage::new(width, height),
+        }
+    }
+
+    /// Feeds the next source row, as straight alpha RGBA covering the whole source width
+    pub fn push_row(&mut self, pixels: &[u8]) {
+        let y = self.row;
+        self.row += 1;
+
+        if y < self.crop.y || y >= self.crop.y + self.crop.height {
+            return;
+        }
+
+        let row = &pixels[self.crop.x as usize * 4..];
+        self.resample_row(row);
+
+        let (height, crop_height) = (self.height as u64, self.crop.height as u64);
+        let start = (y - self.crop.y) as u64 * height;
+        let end = start + height;
+
+        let mut output_row = start / crop_height;
+        while output_row < height && output_row * crop_height < end {
+            let output_start = output_row * crop_height;
+            let output_end = output_start + crop_height;
+            let weight = overlap(start, end, output_start, output_end);
+
+            for (sum, &value) in self.vertical.iter_mut().zip(&self.horizontal) {
+                *sum += weight * value as u64;
+            }
+
+            if output_end > end {
+                // the rest of this output row comes from the next source row
+                break;
+            }
+
+            self.emit_row(output_row as u32);
+            output_row += 1;
+        }
+    }
+
+    /// Feeds the next source row, as RGB covering the whole source width
+    pub fn push_rgb_row(&mut self, pixels: &[u8]) {
+        let mut rgba = std::mem::take(&mut self.rgba_row);
+        rgba.clear();
+        rgba.extend(
+            pixels
+                .chunks_exact(3)
+                .flat_map(|rgb| [rgb[0], rgb[1], rgb[2], 255]),
+        );
+
+        self.push_row(&rgba);
+        self.rgba_row = rgba;
+    }
+
+    pub fn finish(self) -> RgbaImage {
+        self.output
+    }
+
+    fn resample_row(&mut self, row: &[u8]) {
+        let total = self.crop.width as u64;
+
+        for (column, horizontal) in self.columns.iter().zip(self.horizontal.chunks_exact_mut(4)) {
+            let mut sums = [0u64; 4];
+
+            for (i, &weight) in column.weights.iter().enumerate() {
+                let offset = (column.first as usize + i) * 4;
+                let alpha = row[offset + 3] as u32;
+
+                // the weight and a color both fit in 16 bits, only the sum needs more
+                for (sum, &value) in sums.iter_mut().zip(&row[offset..offset + 3]) {
+                    *sum += (weight * premultiply(value, alpha)) as u64;
+                }
+                sums[3] += (weight * alpha) as u64;
+            }
+
+            for (value, sum) in horizontal.iter_mut().zip(sums) {
+                *value = ((sum * 256 + total / 2) / total) as u32;
+            }
+        }
+    }
+
+    fn emit_row(&mut self, y: u32) {
+        let total = self.crop.height as u64 * 256;
+
+        for x in 0..self.width {
+            let sums = &mut self.vertical[x as usize * 4..x as usize * 4 + 4];
+            let pixel = self.output.get_pixel_mut(x, y);
+
+            for c in 0..4 {
+                pixel[c] = ((sums[c] + total / 2) / total).min(255) as u8;
+                sums[c] = 0;
+            }
+
+            // rounding can leave a color a step above its alpha
+            for c in 0..3 {
+                pixel[c] = pixel[c].min(pixel[3]);
+            }
+        }
+    }
+}
+
+fn overlap(a_start: u64, a_end: u64, b_start: u64, b_end: u64) -> u64 {
+    a_end.min(b_end).saturating_sub(a_start.max(b_start))
+}
+
+fn premultiply(value: u8, alpha: u32) -> u32 {
+    (value as u32 * alpha + 127) / 255
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use image::imageops::{self, FilterType};
+    use image::Rgba;
+
+    use super::*;
+
+    fn downscale(source: &RgbaImage, width: u32, height: u32) -> RgbaImage {
+        let crop = Crop {
+            x: 0,
+            y: 0,
+            width: source.width(),
+            height: source.height(),
+        };
+        let mut downscaler = BoxDownscaler::new(crop, width, height);
+        for row in source.chunks_exact(source.width() as usize * 4) {
+            downscaler.push_row(row);
+        }
+        downscaler.finish()
+    }
+
+    /// Smooth gradients with some detail and a hard edged disc, like a sticker
+    fn sticker(size: u32) -> RgbaImage {
+        RgbaImage::from_fn(size, size, |x, y| {
+            let (fx, fy) = (x as f32 / size as f32, y as f32 / size as f32);
+            let (dx, dy) = (fx - 0.5, fy - 0.5);
+            if dx * dx + dy * dy < 0.09 {
+                let wave = ((fx * 40.0).sin() * (fy * 30.0).cos() * 60.0 + 128.0) as u8;
+                Rgba([230, wave, 40, 255])
+            } else {
+                Rgba([(fx * 255.0) as u8, (fy * 255.0) as u8, 160, 255])
+            }
+        })
+    }
+
+    fn psnr(a: &RgbaImage, b: &RgbaImage) -> f64 {
+        let squared: f64 = a
+            .as_raw()
+            .iter()
+            .zip(b.as_raw())
+            .map(|(&a, &b)| (a as f64 - b as f64).powi(2))
+            .sum();
+        let mse = squared / a.as_raw().len() as f64;
+
+        10.0 * (255.0 * 255.0 / mse).log10()
+    }
+
+    #[test]
+    fn keeps_a_flat_color() {
+        let source = RgbaImage::from_pixel(300, 170, Rgba([200, 100, 50, 255]));
+
+        for (width, height) in [(64, 64), (64, 36), (7, 3), (300, 170)] {
+            let scaled = downscale(&source, width, height);
+            assert!(scaled
+                .pixels()
+                .all(|pixel| *pixel == Rgba([200, 100, 50, 255])));
+        }
+    }
+
+    #[test]
+    fn rgb_rows_scale_like_opaque_rgba() {
+        let source = sticker(100);
+        let rgb = image::DynamicImage::ImageRgba8(source.clone()).to_rgb8();
+        let opaque = image::DynamicImage::ImageRgb8(rgb.clone()).to_rgba8();
+
+        let crop = Crop {
+            x: 0,
+            y: 0,
+            width: 100,
+            height: 100,
+        };
+        let mut downscaler = BoxDownscaler::new(crop, 64, 64);
+        for row in rgb.chunks_exact(100 * 3) {
+            downscaler.push_rgb_row(row);
+        }
+
+        assert_eq!(downscaler.finish(), downscale(&opaque, 64, 64));
+    }
+
+    #[test]
+    fn premultiplies_alpha() {
+        let mut source = RgbaImage::from_pixel(2, 1, Rgba([255, 255, 255, 255]));
+        source.put_pixel(1, 0, Rgba([255, 0, 0, 0]));
+
+        // the invisible red doesn't bleed in
+        assert_eq!(
+            *downscale(&source, 1, 1).get_pixel(0, 0),
+            Rgba([128, 128, 128, 128])
+        );
+    }
+
+    #[test]
+    fn averages_whole_blocks() {
+        let source = RgbaImage::from_fn(4, 2, |x, _| {
+            if x < 2 {
+                Rgba([0, 0, 0, 255])
+            } else {
+                Rgba([255, 255, 255, 255])
+            }
+        });
+
+        let scaled = downscale(&source, 2, 1);
+        assert_eq!(*scaled.get_pixel(0, 0), Rgba([0, 0, 0, 255]));
+        assert_eq!(*scaled.get_pixel(1, 0), Rgba([255, 255, 255, 255]));
+    }
+
+    #[test]
+    fn crops() {
+        let source = RgbaImage::from_fn(8, 8, |x, y| {
+            if (2..6).contains(&x) && (2..6).contains(&y) {
+                Rgba([10, 20, 30, 255])
+            } else {
+                Rgba([255, 255, 255, 255])
+            }
+        });
+        let crop = Crop {
+            x: 2,
+            y: 2,
+            width: 4,
+            height: 4,
+        };
+
+        let mut downscaler = BoxDownscaler::new(crop, 2, 2);
+        for row in source.chunks_exact(8 * 4) {
+            downscaler.push_row(row);
+        }
+
+        assert!(downscaler
+            .finish()
+            .pixels()
+            .all(|pixel| *pixel == Rgba([10, 20, 30, 255])));
+    }
+
+    #[test]
+    fn very_wide_rows_dont_overflow() {
+        let source = RgbaImage::from_pixel(70_000, 2, Rgba([255, 255, 255, 255]));
+
+        let scaled = downscale(&source, 64, 1);
+        assert!(scaled
+            .pixels()
+            .all(|pixel| *pixel == Rgba([255, 255, 255, 255])));
+    }
+
+    #[test]
+    fn very_tall_images_dont_overflow() {
+        let mut downscaler = BoxDownscaler::new(
+            Crop {
+                x: 0,
+                y: 0,
+                width: 1,
+                height: 1_000_000,
+            },
+            1,
+            64,
+        );
+        for _ in 0..1_000_000 {
+            downscaler.push_row(&[255, 255, 255, 255]);
+        }
+
+        let scaled = downscaler.finish();
+        assert!(scaled
+            .pixels()
+            .all(|pixel| *pixel == Rgba([255, 255, 255, 255])));
+    }
+
+    #[test]
+    fn close_to_lanczos() {
+        for size in [128, 512, 1000] {
+            let source = sticker(size);
+
+            let boxed = downscale(&source, 64, 64);
+            let lanczos = imageops::resize(&source, 64, 64, FilterType::Lanczos3);
+
+            let psnr = psnr(&boxed, &lanczos);
+            assert!(psnr > 40.0, "{size}x{size}: {psnr:.1} dB");
+        }
+    }
+
+    /// `cargo +stable test --release --lib --target x86_64-unknown-linux-gnu -- --ignored --nocapture`
+    #[test]
+    #[ignore = "benchmark"]
+    fn faster_than_lanczos() {
+        const RUNS: u32 = 20;
+
+        for size in [512, 2048] {
+            let source = sticker(size);
+
+            let started = Instant::now();
+            for _ in 0..RUNS {
+                std::hint::black_box(downscale(&source, 64, 64));
+            }
+            let boxed = started.elapsed() / RUNS;
+
+            let started = Instant::now();
+            for _ in 0..RUNS {
+                std::hint::black_box(imageops::resize(&source, 64, 64, FilterType::Lanczos3));
+            }
+            let lanczos = started.elapsed() / RUNS;
+
+            println!("{size}x{size} to 64x64: box {boxed:?}, Lanczos3 {lanczos:?}");
+            assert!(boxed < lanczos);
+        }
+    }
+}
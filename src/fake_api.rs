@@ -0,0 +1,66 @@
+//! A Telegram that answers every call the same way and remembers them
+
+use std::cell::RefCell;
+use std::path::PathBuf;
+
+use frankenstein::TelegramApi;
+use serde_json::{json, Value};
+
+pub struct FakeApi {
+    /// What every call gets back, `{"ok": true, "result": true}` unless set
+    pub response: Value,
+    /// Method names and their parameters, in the order they were called
+    pub calls: RefCell<Vec<(String, Value)>>,
+}
+
+impl FakeApi {
+    pub fn new() -> FakeApi {
+        FakeApi::answering(json!({ "ok": true, "result": true }))
+    }
+
+    pub fn answering(response: Value) -> FakeApi {
+        FakeApi {
+            response,
+            calls: RefCell::new(Vec::new()),
+        }
+    }
+
+    fn answer<T1: serde::ser::Serialize, T2: serde::de::DeserializeOwned>(
+        &self,
+        method: &str,
+        params: T1,
+    ) -> Result<T2, serde_json::Error> {
+        let params = serde_json::to_value(params)?;
+        self.calls.borrow_mut().push((method.to_string(), params));
+
+        serde_json::from_value(self.response.clone())
+    }
+
+    /// The only call made, panics if there were more or none
+    pub fn call(&self) -> (String, Value) {
+        let calls = self.calls.borrow();
+        assert_eq!(calls.len(), 1, "calls: {calls:?}");
+        calls[0].clone()
+    }
+}
+
+impl TelegramApi for FakeApi {
+    type Error = serde_json::Error;
+
+    fn request<T1: serde::ser::Serialize, T2: serde::de::DeserializeOwned>(
+        &self,
+        method: &str,
+        params: Option<T1>,
+    ) -> Result<T2, serde_json::Error> {
+        self.answer(method, params)
+    }
+
+    fn request_with_form_data<T1: serde::ser::Serialize, T2: serde::de::DeserializeOwned>(
+        &self,
+        method: &str,
+        params: T1,
+        _files: Vec<(&str, PathBuf)>,
+    ) -> Result<T2, serde_json::Error> {
+        self.answer(method, params)
+    }
+}
@@ -0,0 +1,412 @@
+use std::fs;
+
+use image::{ImageBuffer, Pixel, RgbaImage};
+use log::warn;
+
+use crate::storage;
+
+const FILTERS_FILE: &str = "filters";
+
+/// More than this in one chain is surely a typo
+pub const MAX_FILTERS: usize = 8;
+
+/// Rec. 709 luma weights, the same the CSS filter matrices use
+const LUMA: [f32; 3] = [0.213, 0.715, 0.072];
+
+const SEPIA: [[f32; 3]; 3] = [
+    [0.393, 0.769, 0.189],
+    [0.349, 0.686, 0.168],
+    [0.272, 0.534, 0.131],
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Filter {
+    Invert,
+    /// Percent of full scale added to every channel, -100 to 100
+    Brightness(i32),
+    /// Percent, 100 leaves the image as it is
+    Contrast(u32),
+    /// Degrees around the color wheel
+    Hue(i32),
+    /// Percent, 0 is grayscale and 100 leaves the image as it is
+    Saturation(u32),
+    /// Levels per channel
+    Posterize(u32),
+    /// Block size in pixels
+    Pixelate(u32),
+    Edge,
+    Sepia,
+}
+
+impl Filter {
+    /// Parses `invert`, `sepia`, `edge`, `gray`, `posterize` and `pixelate`,
+    /// or `name=value` for the ones that take a value
+    pub fn parse(word: &str) -> Option<Filter> {
+        let Some((name, value)) = word.split_once('=') else {
+            return match word {
+                "invert" => Some(Filter::Invert),
+                "sepia" => Some(Filter::Sepia),
+                "edge" | "edges" => Some(Filter::Edge),
+                "gray" | "grey" | "grayscale" => Some(Filter::Saturation(0)),
+                "posterize" => Some(Filter::Posterize(4)),
+                "pixelate" => Some(Filter::Pixelate(4)),
+                _ => None,
+            };
+        };
+
+        let value: i32 = value.trim_end_matches(['%', '°']).parse().ok()?;
+        let in_range = |min: i32, max: i32| (min..=max).contains(&value).then_some(value);
+
+        match name {
+            "brightness" => in_range(-100, 100).map(Filter::Brightness),
+            "contrast" => in_range(0, 300).map(|value| Filter::Contrast(value as u32)),
+            "hue" => Some(Filter::Hue(value.rem_euclid(360))),
+            "saturation" => in_range(0, 300).map(|value| Filter::Saturation(value as u32)),
+            "posterize" => in_range(2, 16).map(|value| Filter::Posterize(value as u32)),
+            "pixelate" => in_range(2, 16).map(|value| Filter::Pixelate(value as u32)),
+            _ => None,
+        }
+    }
+
+    /// The form `parse` reads back
+    pub fn name(self) -> String {
+        match self {
+            Filter::Invert => "invert".to_string(),
+            Filter::Brightness(value) => format!("brightness={value}"),
+            Filter::Contrast(value) => format!("contrast={value}"),
+            Filter::Hue(value) => format!("hue={value}"),
+            Filter::Saturation(value) => format!("saturation={value}"),
+            Filter::Posterize(value) => format!("posterize={value}"),
+            Filter::Pixelate(value) => format!("pixelate={value}"),
+            Filter::Edge => "edge".to_string(),
+            Filter::Sepia => "sepia".to_string(),
+        }
+    }
+
+    /// Works on the premultiplied images that come out of the scaler
+    fn apply(self, image: &mut RgbaImage) {
+        match self {
+            Filter::Invert => map_colors(image, |color| color.map(|c| 255.0 - c)),
+            Filter::Brightness(percent) => {
+                let offset = percent as f32 * 2.55;
+                map_colors(image, |color| color.map(|c| c + offset))
+            }
+            Filter::Contrast(percent) => {
+                let factor = percent as f32 / 100.0;
+                map_colors(image, |color| color.map(|c| (c - 128.0) * factor + 128.0))
+            }
+            Filter::Hue(degrees) => {
+                let (sin, cos) = (degrees as f32).to_radians().sin_cos();
+                let matrix = [
+                    [
+                        LUMA[0] + cos * (1.0 - LUMA[0]) - sin * LUMA[0],
+                        LUMA[1] - cos * LUMA[1] - sin * LUMA[1],
+                        LUMA[2] - cos * LUMA[2] + sin * (1.0 - LUMA[2]),
+                    ],
+                    [
+                        LUMA[0] - cos * LUMA[0] + sin * 0.143,
+                        LUMA[1] + cos * (1.0 - LUMA[1]) + sin * 0.140,
+                        LUMA[2] - cos * LUMA[2] - sin * 0.283,
+                    ],
+                    [
+                        LUMA[0] - cos * LUMA[0] - sin * (1.0 - LUMA[0]),
+                        LUMA[1] - cos * LUMA[1] + sin * LUMA[1],
+                        LUMA[2] + cos * (1.0 - LUMA[2]) + sin * LUMA[2],
+                    ],
+                ];
+                map_colors(image, |color| multiply(&matrix, color))
+            }
+            Filter::Saturation(percent) => {
+                let s = percent as f32 / 100.0;
+                let matrix: [[f32; 3]; 3] = std::array::from_fn(|row| {
+                    std::array::from_fn(|column| {
+                        let identity = if row == column { 1.0 } else { 0.0 };
+                        LUMA[column] + (identity - LUMA[column]) * s
+                    })
+                });
+                map_colors(image, |color| multiply(&matrix, color))
+            }
+            Filter::Posterize(levels) => {
+                let step = 255.0 / (levels - 1) as f32;
+                map_colors(image, |color| color.map(|c| (c / step).round() * step))
+            }
+            Filter::Pixelate(block) => *image = pixelate(image, block),
+            Filter::Edge => *image = edges(image),
+            Filter::Sepia => map_colors(image, |color| multiply(&SEPIA, color)),
+        }
+    }
+}
+
+/// Filters applied in order to every image before it's composited
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Filters(Vec<Filter>);
+
+impl Filters {
+    /// Every word has to be a filter, the error names the first one that isn't
+    pub fn parse(text: &str) -> Result<Filters, String> {
+        let filters = text
+            .split_whitespace()
+            .map(|word| Filter::parse(word).ok_or_else(|| format!("Unknown filter {word}")))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if filters.len() > MAX_FILTERS {
+            return Err(format!("At most {MAX_FILTERS} filters can be chained"));
+        }
+
+        Ok(Filters(filters))
+    }
+
+    /// Picks the filters out of a caption, which may have other words in it.
+    /// `None` if there are none
+    pub fn from_caption(caption: &str) -> Option<Filters> {
+        let filters: Vec<Filter> = caption
+            .split_whitespace()
+            .filter_map(Filter::parse)
+            .take(MAX_FILTERS)
+            .collect();
+
+        (!filters.is_empty()).then_some(Filters(filters))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn describe(&self) -> String {
+        let names: Vec<String> = self.0.iter().map(|filter| filter.name()).collect();
+        names.join(" ")
+    }
+
+    pub fn apply(&self, image: &mut RgbaImage) {
+        for filter in &self.0 {
+            filter.apply(image);
+        }
+    }
+
+    /// The ones set with `/filter`, none if they were never set
+    pub fn load() -> Filters {
+        fs::read_to_string(storage::path(FILTERS_FILE))
+            .ok()
+            .and_then(|text| Filters::parse(&text).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) {
+        if let Err(err) = fs::write(storage::path(FILTERS_FILE), self.describe()) {
+            warn!("Could not save the filters: {:?}", err);
+        }
+    }
+}
+
+fn multiply(matrix: &[[f32; 3]; 3], color: [f32; 3]) -> [f32; 3] {
+    matrix.map(|row| row[0] * color[0] + row[1] * color[1] + row[2] * color[2])
+}
+
+/// Runs `f` on the straight, not premultiplied, color of every visible pixel
+fn map_colors(image: &mut RgbaImage, f: impl Fn([f32; 3]) -> [f32; 3]) {
+    for pixel in image.pixels_mut() {
+        let alpha = pixel[3] as f32;
+        if alpha == 0.0 {
+            continue;
+        }
+
+        let straight = [0, 1, 2].map(|c| pixel[c] as f32 * 255.0 / alpha);
+        let mapped = f(straight);
+
+        for c in 0..3 {
+            pixel[c] = (mapped[c].clamp(0.0, 255.0) * alpha / 255.0).round() as u8;
+        }
+    }
+}
+
+/// Replaces every `block` x `block` square with its average color
+pub fn pixelate<P>(image: &ImageBuffer<P, Vec<u8>>, block: u32) -> ImageBuffer<P, Vec<u8>>
+where
+    P: Pixel<Subpixel = u8>,
+{
+    let block = block.max(1);
+    let (width, height) = image.dimensions();
+    let channels = P::CHANNEL_COUNT as usize;
+    let mut pixelated = ImageBuffer::new(width, height);
+
+    for top in (0..height).step_by(block as usize) {
+        for left in (0..width).step_by(block as usize) {
+            let right = (left + block).min(width);
+            let bottom = (top + block).min(height);
+            let count = (right - left) * (bottom - top);
+
+            let mut sum = [0u32; 4];
+            for y in top..bottom {
+                for x in left..right {
+                    for (sum, &value) in sum.iter_mut().zip(image.get_pixel(x, y).channels()) {
+                        *sum += value as u32;
+                    }
+                }
+            }
+            let average = sum.map(|sum| ((sum + count / 2) / count) as u8);
+            let average = *P::from_slice(&average[..channels]);
+
+            for y in top..bottom {
+                for x in left..right {
+                    pixelated.put_pixel(x, y, average);
+                }
+            }
+        }
+    }
+
+    pixelated
+}
+
+/// Sobel edge magnitude as white lines. Alpha is part of the input,
+/// so the outline of a sticker counts as an edge too
+fn edges(image: &RgbaImage) -> RgbaImage {
+    let (width, height) = image.dimensions();
+
+    // premultiplied luma, transparent pixels are black
+    let luma: Vec<i32> = image
+        .pixels()
+        .map(|pixel| (0..3).map(|c| LUMA[c] * pixel[c] as f32).sum::<f32>() as i32)
+        .collect();
+    let at = |x: i64, y: i64| {
+        let x = x.clamp(0, width as i64 - 1) as usize;
+        let y = y.clamp(0, height as i64 - 1) as usize;
+        luma[y * width as usize + x]
+    };
+
+    RgbaImage::from_fn(width, height, |x, y| {
+        let (x, y) = (x as i64, y as i64);
+        let gx = at(x + 1, y - 1) + 2 * at(x + 1, y) + at(x + 1, y + 1)
+            - at(x - 1, y - 1)
+            - 2 * at(x - 1, y)
+            - at(x - 1, y + 1);
+        let gy = at(x - 1, y + 1) + 2 * at(x, y + 1) + at(x + 1, y + 1)
+            - at(x - 1, y - 1)
+            - 2 * at(x, y - 1)
+            - at(x + 1, y - 1);
+
+        let magnitude = ((gx.abs() + gy.abs()) / 2).min(255) as u8;
+        let alpha = image.get_pixel(x as u32, y as u32)[3].max(magnitude);
+
+        image::Rgba([magnitude, magnitude, magnitude, alpha])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+
+    /// Opaque color gradients
+    fn opaque() -> RgbaImage {
+        RgbaImage::from_fn(32, 32, |x, y| {
+            Rgba([x as u8 * 8, y as u8 * 8, 200 - x as u8 * 4, 255])
+        })
+    }
+
+    /// Premultiplied, from fully transparent on the left to opaque on the right
+    fn translucent() -> RgbaImage {
+        RgbaImage::from_fn(32, 32, |x, y| {
+            let alpha = x * 255 / 31;
+            let straight = [255, y * 8, 128];
+            Rgba([
+                (straight[0] * alpha / 255) as u8,
+                (straight[1] * alpha / 255) as u8,
+                (straight[2] * alpha / 255) as u8,
+                alpha as u8,
+            ])
+        })
+    }
+
+    fn filtered(filters: &str, image: &RgbaImage) -> RgbaImage {
+        let mut image = image.clone();
+        Filters::parse(filters).unwrap().apply(&mut image);
+        image
+    }
+
+    #[test]
+    fn parses_chains() {
+        let filters = Filters::parse("invert hue=90 pixelate=4").unwrap();
+        assert_eq!(
+            filters,
+            Filters(vec![Filter::Invert, Filter::Hue(90), Filter::Pixelate(4)])
+        );
+        assert_eq!(Filters::parse(&filters.describe()), Ok(filters));
+
+        assert_eq!(Filter::parse("hue=-90"), Some(Filter::Hue(270)));
+        assert_eq!(Filter::parse("contrast=150%"), Some(Filter::Contrast(150)));
+        assert_eq!(Filter::parse("gray"), Some(Filter::Saturation(0)));
+    }
+
+    #[test]
+    fn refuses_unknown_filters_and_values() {
+        assert_eq!(
+            Filters::parse("invert blur"),
+            Err("Unknown filter blur".to_string())
+        );
+        assert!(Filters::parse("hue=ninety").is_err());
+        assert!(Filters::parse("pixelate=1").is_err());
+        assert!(Filters::parse("brightness=101").is_err());
+        assert!(Filters::parse("posterize=17").is_err());
+        assert!(Filters::parse(&["invert"; MAX_FILTERS + 1].join(" ")).is_err());
+    }
+
+    #[test]
+    fn picks_filters_out_of_captions() {
+        assert_eq!(
+            Filters::from_caption("my cat, sepia please"),
+            Some(Filters(vec![Filter::Sepia]))
+        );
+        assert_eq!(Filters::from_caption("my cat"), None);
+    }
+
+    #[test]
+    fn invert_twice_changes_nothing() {
+        assert_eq!(filtered("invert invert", &opaque()), opaque());
+    }
+
+    #[test]
+    fn neutral_values_change_nothing() {
+        for neutral in ["hue=0", "saturation=100", "contrast=100", "brightness=0"] {
+            assert_eq!(filtered(neutral, &opaque()), opaque(), "{neutral}");
+            assert_eq!(
+                filtered(neutral, &translucent()),
+                translucent(),
+                "{neutral}"
+            );
+        }
+    }
+
+    #[test]
+    fn posterize_2_leaves_two_levels() {
+        let posterized = filtered("posterize=2", &opaque());
+
+        assert!(posterized
+            .pixels()
+            .all(|pixel| pixel.0[..3].iter().all(|&c| c == 0 || c == 255)));
+    }
+
+    #[test]
+    fn colors_stay_within_alpha() {
+        for filter in [
+            "invert",
+            "brightness=100",
+            "contrast=300",
+            "hue=120",
+            "saturation=300",
+            "posterize=3",
+            "pixelate=5",
+            "edge",
+            "sepia",
+        ] {
+            let image = filtered(filter, &translucent());
+
+            for pixel in image.pixels() {
+                assert!(
+                    pixel.0[..3].iter().all(|&c| c <= pixel[3]),
+                    "{filter}: {pixel:?}"
+                );
+            }
+        }
+    }
+}
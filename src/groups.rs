@@ -0,0 +1,367 @@
+use std::fs;
+
+use frankenstein::{ChatType, Message};
+use log::warn;
+
+use crate::custom_emoji;
+use crate::media;
+use crate::storage;
+
+/// The policy, then a line per group that was turned on or off by hand:
+///
+/// ```text
+/// policy mention
+/// on -1001234567890
+/// off -1009876543210
+/// ```
+const GROUPS_FILE: &str = "groups";
+
+/// Keeps the file small, groups beyond this can't be turned on or off
+pub const MAX_GROUPS: usize = 32;
+
+/// Which images posted in groups make it to the panel
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GroupPolicy {
+    /// Every image
+    All,
+    /// Images that reply to the bot or mention it, and images
+    /// someone replies to while mentioning it
+    Mention,
+    /// Every image, but only in groups turned on with /group on
+    Listed,
+}
+
+impl GroupPolicy {
+    pub fn parse(word: &str) -> Option<GroupPolicy> {
+        match word {
+            "all" => Some(GroupPolicy::All),
+            "mention" | "mentions" => Some(GroupPolicy::Mention),
+            "listed" => Some(GroupPolicy::Listed),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            GroupPolicy::All => "all",
+            GroupPolicy::Mention => "mention",
+            GroupPolicy::Listed => "listed",
+        }
+    }
+}
+
+pub struct Groups {
+    pub policy: GroupPolicy,
+    /// Groups turned on or off with /group, the rest follow the policy
+    overrides: Vec<(i64, bool)>,
+}
+
+impl Groups {
+    pub fn load() -> Groups {
+        Groups::parse(&fs::read_to_string(storage::path(GROUPS_FILE)).unwrap_or_default())
+    }
+
+    fn parse(saved: &str) -> Groups {
+        let mut groups = Groups {
+            policy: GroupPolicy::All,
+            overrides: Vec::new(),
+        };
+
+        for line in saved.lines() {
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+
+            match key {
+                "policy" => {
+                    if let Some(policy) = GroupPolicy::parse(value) {
+                        groups.policy = policy;
+                    }
+                }
+                "on" | "off" => {
+                    if let Ok(chat_id) = value.parse() {
+                        groups.overrides.push((chat_id, key == "on"));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        groups
+    }
+
+    pub fn set_policy(&mut self, policy: GroupPolicy) {
+        self.policy = policy;
+        self.save();
+    }
+
+    /// Whether images from the group `chat_id` can be shown at all
+    pub fn is_enabled(&self, chat_id: i64) -> bool {
+        self.overrides
+            .iter()
+            .find(|(id, _)| *id == chat_id)
+            .map_or(self.policy != GroupPolicy::Listed, |(_, enabled)| *enabled)
+    }
+
+    /// Returns the reason if there is no room for another group
+    pub fn set_enabled(&mut self, chat_id: i64, enabled: bool) -> Result<(), String> {
+        let full = self.overrides.len() >= MAX_GROUPS;
+        match self.overrides.iter_mut().find(|(id, _)| *id == chat_id) {
+            Some(group) => group.1 = enabled,
+            None if full => {
+                return Err(format!("Only {MAX_GROUPS} groups can be turned on or off"))
+            }
+            None => self.overrides.push((chat_id, enabled)),
+        }
+
+        self.save();
+        Ok(())
+    }
+
+    /// The message with the image `message` asks to show, following the policy.
+    /// `username` is the bot's, without the @
+    pub fn image_to_show<'a>(&self, message: &'a Message, username: &str) -> Option<&'a Message> {
+        let has_image = |message: &Message| {
+            media::from_message(message).is_some()
+                || custom_emoji::custom_emoji_ids(message).is_some()
+        };
+
+        let in_group = matches!(
+            message.chat.type_field,
+            ChatType::Group | ChatType::Supergroup
+        );
+        if !in_group {
+            return Some(message).filter(|message| has_image(message));
+        }
+
+        if !self.is_enabled(message.chat.id) {
+            return None;
+        }
+
+        if self.policy != GroupPolicy::Mention {
+            return Some(message).filter(|message| has_image(message));
+        }
+
+        let replied = message.reply_to_message.as_deref();
+        let replies_to_bot = replied
+            .and_then(|replied| replied.from.as_deref())
+            .is_some_and(|user| {
+                user.is_bot
+                    && user
+                        .username
+                        .as_deref()
+                        .is_some_and(|name| name.eq_ignore_ascii_case(username))
+            });
+        let mentions_bot = message
+            .text
+            .as_deref()
+            .or(message.caption.as_deref())
+            .is_some_and(|text| mentions(text, username));
+
+        if has_image(message) && (replies_to_bot || mentions_bot) {
+            Some(message)
+        } else if mentions_bot {
+            // "@bot" in reply to someone else's sticker
+            replied.filter(|replied| has_image(replied))
+        } else {
+            None
+        }
+    }
+
+    /// The way `parse` takes it back
+    fn setting(&self) -> String {
+        let mut saved = format!("policy {}\n", self.policy.name());
+        for (chat_id, enabled) in &self.overrides {
+            saved += &format!("{} {}\n", if *enabled { "on" } else { "off" }, chat_id);
+        }
+        saved
+    }
+
+    fn save(&self) {
+        if let Err(err) = fs::write(storage::path(GROUPS_FILE), self.setting()) {
+            warn!("Could not save the group settings: {:?}", err);
+        }
+    }
+}
+
+/// Whether `text` has `@username` in it, and not just a longer name starting with it
+fn mentions(text: &str, username: &str) -> bool {
+    if username.is_empty() {
+        return false;
+    }
+
+    let text = text.to_lowercase();
+    let mention = format!("@{}", username.to_lowercase());
+
+    text.match_indices(&mention).any(|(at, _)| {
+        !text[at + mention.len()..].starts_with(|next: char| next.is_alphanumeric() || next == '_')
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    const GROUP: i64 = -1001234567890;
+    const BOT: &str = "hub75bot";
+
+    fn groups(saved: &str) -> Groups {
+        Groups::parse(saved)
+    }
+
+    fn message(chat: Value, content: Value) -> Message {
+        let mut message = json!({
+            "message_id": 7,
+            "date": 0,
+            "chat": chat,
+            "from": { "id": 42, "is_bot": false, "first_name": "Alice" },
+        });
+        message
+            .as_object_mut()
+            .unwrap()
+            .extend(content.as_object().unwrap().clone());
+
+        serde_json::from_value(message).unwrap()
+    }
+
+    fn in_group(content: Value) -> Message {
+        message(
+            json!({ "id": GROUP, "type": "supergroup", "title": "friends" }),
+            content,
+        )
+    }
+
+    fn photo(caption: &str) -> Value {
+        json!({
+            "photo": [{ "file_id": "photo", "file_unique_id": "photo", "width": 64, "height": 32 }],
+            "caption": caption,
+        })
+    }
+
+    fn from_bot(username: &str) -> Value {
+        json!({ "id": 1, "is_bot": true, "first_name": "Panel", "username": username })
+    }
+
+    fn shown(groups: &Groups, message: &Message) -> Option<i32> {
+        groups
+            .image_to_show(message, BOT)
+            .map(|message| message.message_id)
+    }
+
+    #[test]
+    fn saved_lines() {
+        let saved = "policy listed\non -1001234567890\noff -100\n";
+        let groups = groups(saved);
+
+        assert_eq!(groups.policy, GroupPolicy::Listed);
+        assert!(groups.is_enabled(GROUP));
+        assert!(!groups.is_enabled(-100));
+        assert_eq!(groups.setting(), saved);
+
+        let groups = Groups::parse("policy maybe\non x\nnonsense\n");
+        assert_eq!(groups.policy, GroupPolicy::All);
+        assert_eq!(groups.setting(), "policy all\n");
+    }
+
+    #[test]
+    fn overrides_beat_the_policy() {
+        for policy in ["all", "mention", "listed"] {
+            let groups = groups(&format!("policy {policy}\non -1\noff -2\n"));
+
+            assert!(groups.is_enabled(-1), "{policy}");
+            assert!(!groups.is_enabled(-2), "{policy}");
+            assert_eq!(groups.is_enabled(-3), policy != "listed", "{policy}");
+        }
+    }
+
+    #[test]
+    fn private_chats_ignore_the_policy() {
+        let private = message(json!({ "id": 42, "type": "private" }), photo(""));
+        let text = message(
+            json!({ "id": 42, "type": "private" }),
+            json!({ "text": "hi" }),
+        );
+
+        for policy in ["all", "mention", "listed"] {
+            let groups = groups(&format!("policy {policy}\n"));
+            assert_eq!(shown(&groups, &private), Some(7));
+            assert_eq!(shown(&groups, &text), None);
+        }
+    }
+
+    #[test]
+    fn all_and_listed() {
+        let image = in_group(photo(""));
+        let text = in_group(json!({ "text": "hi @hub75bot" }));
+
+        assert_eq!(shown(&groups("policy all\n"), &image), Some(7));
+        assert_eq!(shown(&groups("policy all\n"), &text), None);
+        assert_eq!(
+            shown(&groups("policy all\noff -1001234567890\n"), &image),
+            None
+        );
+
+        assert_eq!(shown(&groups("policy listed\n"), &image), None);
+        assert_eq!(
+            shown(&groups("policy listed\non -1001234567890\n"), &image),
+            Some(7)
+        );
+    }
+
+    #[test]
+    fn mention_needs_the_bot_named() {
+        let groups = groups("policy mention\n");
+
+        assert_eq!(shown(&groups, &in_group(photo("look"))), None);
+        assert_eq!(shown(&groups, &in_group(photo("look @Hub75Bot"))), Some(7));
+        assert_eq!(shown(&groups, &in_group(photo("@hub75bot, look"))), Some(7));
+        assert_eq!(shown(&groups, &in_group(photo("look @hub75bot_fan"))), None);
+        assert_eq!(
+            shown(&groups, &in_group(photo("@hub75bot2 or @hub75bot"))),
+            Some(7)
+        );
+        assert_eq!(shown(&groups, &in_group(photo("look @otherbot"))), None);
+
+        // a group turned off stays off, even when mentioned
+        let off = Groups::parse("policy mention\noff -1001234567890\n");
+        assert_eq!(shown(&off, &in_group(photo("look @hub75bot"))), None);
+    }
+
+    #[test]
+    fn mention_by_replying() {
+        let groups = groups("policy mention\n");
+
+        let mut reply_to_bot = photo("");
+        reply_to_bot["reply_to_message"] = json!({
+            "message_id": 3,
+            "date": 0,
+            "chat": { "id": GROUP, "type": "supergroup", "title": "friends" },
+            "from": from_bot("Hub75Bot"),
+            "text": "Sent to the owner for approval",
+        });
+        assert_eq!(shown(&groups, &in_group(reply_to_bot.clone())), Some(7));
+
+        reply_to_bot["reply_to_message"]["from"] = from_bot("otherbot");
+        assert_eq!(shown(&groups, &in_group(reply_to_bot)), None);
+    }
+
+    #[test]
+    fn mention_in_reply_to_an_image() {
+        let groups = groups("policy mention\n");
+
+        let mut replied = in_group(photo("cute"));
+        replied.message_id = 3;
+        let reply = |text: &str| {
+            let mut reply = in_group(json!({ "text": text }));
+            reply.reply_to_message = Some(Box::new(replied.clone()));
+            reply
+        };
+
+        // the image that was replied to is shown
+        assert_eq!(shown(&groups, &reply("@hub75bot show this")), Some(3));
+        assert_eq!(shown(&groups, &reply("nice")), None);
+
+        let mut not_an_image = reply("@hub75bot show this");
+        not_an_image.reply_to_message = Some(Box::new(in_group(json!({ "text": "hi" }))));
+        assert_eq!(shown(&groups, &not_an_image), None);
+    }
+}
@@ -0,0 +1,31 @@
+//! What doesn't need the panel or the radio, so it can be tested on the host.
+//! The firmware in `main.rs` uses these modules as its own
+
+pub mod boot_image;
+pub mod cache;
+pub mod compose;
+pub mod custom_emoji;
+pub mod decode;
+pub mod downscale;
+#[cfg(test)]
+mod fake_api;
+pub mod filters;
+pub mod groups;
+pub mod media;
+pub mod moderation;
+pub mod multipart;
+pub mod panel;
+pub mod playlist;
+pub mod qr;
+pub mod rate_limit;
+pub mod recovery;
+pub mod registry;
+pub mod roles;
+pub mod router;
+pub mod scaling;
+pub mod scene;
+pub mod storage;
+pub mod timezone;
+pub mod transition;
+pub mod updates;
+pub mod webhook;
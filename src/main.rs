@@ -1,64 +1,55 @@
 use anyhow::Result;
 
 use bot_api::Esp32Api;
-use embedded_svc::http::client::Client;
 use esp_idf_hal::task::thread::ThreadSpawnConfiguration;
 use esp_idf_svc::{
-    eventloop::EspSystemEventLoop,
-    hal::peripherals::Peripherals,
-    http::{client::EspHttpConnection, Method},
+    eventloop::EspSystemEventLoop, hal::peripherals::Peripherals, nvs::EspDefaultNvsPartition,
+    sntp::EspSntp,
 };
 use esp_idf_sys::esp_restart;
 
-use frankenstein::{
-    ForwardMessageParams, GetUpdatesParams, SendChatActionParams, SendMessageParams, TelegramApi,
-};
-use log::{error, info};
+use frankenstein::{DeleteWebhookParams, GetUpdatesParams, TelegramApi, Update};
+use log::{error, info, warn};
 use std::sync::RwLock;
-
+use std::time::Instant;
+
+use crate::boot_image::BootImage;
+use crate::bot::Bot;
+use crate::cache::{StickerCache, CACHE_BUDGET};
+use crate::compose::Background;
+use crate::display::Display;
+use crate::filters::Filters;
+use crate::groups::Groups;
+use crate::moderation::Moderation;
+use crate::playlist::Playlist;
+use crate::rate_limit::RateLimiter;
+use crate::recovery::{Failures, MAX_POLL_FAILURES, MAX_UPDATE_FAILURES};
+use crate::roles::{Role, Roles};
+use crate::scaling::{ScaleMode, PANEL_HEIGHT, PANEL_WIDTH};
+use crate::transition::Transition;
 use crate::wifi::my_wifi;
 use crate::{config::get_config, hub75::Hub75};
 
+use hub75_esp32::{
+    boot_image, cache, compose, custom_emoji, decode, filters, groups, media, moderation,
+    multipart, panel, playlist, qr, rate_limit, recovery, registry, roles, router, scaling, scene,
+    storage, timezone, transition, updates, webhook,
+};
+
+mod bot;
 mod bot_api;
+mod canvas;
+mod clock;
+mod commands;
 mod config;
+mod display;
+mod download;
 mod hub75;
+mod marquee;
+mod screenshot;
+mod text;
 mod wifi;
 
-struct BotState {
-    owner_id: i64,
-    bot_token: &'static str,
-}
-
-fn download_file_into_buffer(url: &str, out_buffer: &mut Vec<u8>) -> Result<usize> {
-    info!("Downloading file from {}", url);
-
-    let mut client = Client::wrap(EspHttpConnection::new(&Default::default())?);
-
-    let headers = [("accept", "image/*")];
-
-    let request = client.request(Method::Get, url, &headers)?;
-
-    let mut response = request.submit()?;
-
-    let status = response.status();
-    info!("<- {status}");
-
-    if status != 200 {
-        return Err(anyhow::anyhow!("Status code: {}", status));
-    }
-
-    let mut buffer = [0u8; 1024];
-
-    while let Ok(bytes_read) = response.read(&mut buffer) {
-        if bytes_read == 0 {
-            break;
-        }
-        out_buffer.extend_from_slice(&buffer[0..bytes_read]);
-    }
-
-    Ok(out_buffer.len())
-}
-
 fn main() -> Result<()> {
     esp_idf_svc::sys::link_patches();
     esp_idf_svc::log::EspLogger::initialize_default();
@@ -86,13 +77,30 @@ fn main() -> Result<()> {
 
     let mut h = Hub75 { pins: _pins };
 
-    let image = image::load(
-        std::io::Cursor::new(include_bytes!("color_wheel.webp")),
-        image::ImageFormat::WebP,
-    )
-    .unwrap();
+    if let Err(err) = storage::mount() {
+        error!("Could not mount storage, nothing will be saved: {:?}", err);
+    }
+
+    let cache = StickerCache::open(storage::MOUNT_POINT, CACHE_BUDGET);
+
+    clock::load_timezone();
+
+    let boot_image = BootImage::load_setting();
+
+    let current_frame = boot_image.load_frame().unwrap_or_else(|| {
+        match image::load(
+            std::io::Cursor::new(include_bytes!("color_wheel.webp")),
+            image::ImageFormat::WebP,
+        ) {
+            Ok(wheel) => wheel.to_rgb8(),
+            Err(err) => {
+                error!("Could not load the color wheel: {:?}", err);
+                image::RgbImage::new(PANEL_WIDTH, PANEL_HEIGHT)
+            }
+        }
+    });
 
-    let states = h.render_unoptimized(&image.to_rgb8());
+    let states = h.render_unoptimized(&current_frame);
     info!("states: {:?}", states.len());
     let states = std::sync::Arc::new(RwLock::new(states));
 
@@ -118,6 +126,21 @@ fn main() -> Result<()> {
     });
     ThreadSpawnConfiguration::default().set().unwrap();
 
+    let display = Display::start(h, states, current_frame.clone(), boot_image);
+
+    let brightness = panel::load_brightness();
+    display.set_brightness(panel::brightness_value(brightness));
+
+    let transition = Transition::load();
+    display.set_transition(transition);
+
+    let playlist = Playlist::load();
+    if playlist.playing {
+        if let Some(slideshow) = playlist.slideshow(0, transition) {
+            display.play(slideshow);
+        }
+    }
+
     let wifi = match my_wifi("maolol", "canegatto", peripherals.modem, sysloop) {
         Ok(inner) => inner,
         Err(err) => {
@@ -127,159 +150,171 @@ fn main() -> Result<()> {
         }
     };
 
+    // syncs in the background, the clock shows dashes until it's done
+    let _sntp = match EspSntp::new_default() {
+        Ok(sntp) => Some(sntp),
+        Err(err) => {
+            error!("Could not start SNTP, the clock won't work: {:?}", err);
+            None
+        }
+    };
+
     let config = get_config();
-    let bot_state = BotState {
+    let api = Esp32Api::new(config.bot_token);
+
+    let username = match api.get_me() {
+        Ok(me) => me.result.username.unwrap_or_default(),
+        Err(err) => {
+            error!("Could not get the bot's username: {:?}", err);
+            String::new()
+        }
+    };
+
+    let nvs = EspDefaultNvsPartition::take()
+        .inspect_err(|err| error!("Could not open NVS, roles won't be saved: {:?}", err))
+        .ok();
+    let roles = Roles::load(config.bot_owner_id, nvs);
+
+    // everyone sees what users can do, admins and the owner get their commands too
+    let menus = std::iter::once((None, Role::User))
+        .chain(
+            roles
+                .users(Role::Admin)
+                .map(|(id, _)| (Some(id), Role::Admin)),
+        )
+        .chain(std::iter::once((Some(config.bot_owner_id), Role::Owner)));
+    for (chat_id, role) in menus {
+        if let Err(err) = commands::register(&api, chat_id, role) {
+            error!("{:?}", err);
+        }
+    }
+
+    let mut bot = Bot {
+        api,
         owner_id: config.bot_owner_id,
         bot_token: config.bot_token,
+        username,
+        wifi,
+        roles,
+        rate_limit: RateLimiter::load(),
+        moderation: Moderation::load(),
+        groups: Groups::load(),
+        brightness,
+        blank: false,
+        // the slideshow at boot starts from the first entry
+        slide: 0,
+        slide_started: Instant::now(),
+        display,
+        cache,
+        playlist,
+        scale_mode: ScaleMode::load(),
+        background: Background::load(),
+        transition,
+        filters: Filters::load(),
+        current_frame,
+        current_label: "boot image".to_string(),
     };
 
-    let api = Esp32Api::new(bot_state.bot_token);
+    bot.send_owner_info();
+
+    if let Some(url) = config.webhook_url {
+        let (sender, updates) = std::sync::mpsc::channel();
+
+        // kept alive for as long as updates are handled
+        let _server = match webhook::start(config.webhook_port, config.webhook_secret, sender) {
+            Ok(server) => server,
+            Err(err) => bot.restart(&format!("Could not start the webhook server: {err:?}")),
+        };
 
-    let send_owner_info = |bot_state: &BotState| {
-        let mut rssi = 0;
-        unsafe {
-            esp_idf_sys::esp_wifi_sta_get_rssi(&mut rssi);
+        // until it's set Telegram holds on to the updates, a restart tries again
+        if let Err(err) = webhook::register(&bot.api, url, config.webhook_secret) {
+            bot.restart(&format!("{err:#}"));
         }
-        api.send_message(
-            &SendMessageParams::builder()
-                .chat_id(bot_state.owner_id)
-                .text(format!(
-                    "IP: {}\nRSSI: {}",
-                    wifi.sta_netif().get_ip_info().unwrap().ip,
-                    rssi,
-                ))
-                .build(),
-        )
-        .ok();
-    };
 
-    send_owner_info(&bot_state);
+        let mut failed_updates = Failures::new(MAX_UPDATE_FAILURES);
+        for update in updates {
+            handle_update(&mut bot, update, &mut failed_updates);
+        }
 
-    let updates = api
-        .get_updates(&GetUpdatesParams::builder().limit(1u32).offset(-1).build())
-        .unwrap();
+        bot.restart("The webhook server stopped");
+    }
 
-    let mut offset = if let Some(update) = updates.result.first() {
-        update.update_id as i64 + 1
-    } else {
-        0
-    };
+    // getUpdates is refused while a webhook is set
+    if let Err(err) = bot
+        .api
+        .delete_webhook(&DeleteWebhookParams::builder().build())
+    {
+        error!("Could not remove the webhook: {:?}", err);
+    }
 
-    let mut webp_buffer = Vec::new();
+    let mut failed_polls = Failures::new(MAX_POLL_FAILURES);
+
+    // updates sent while the bot was off are skipped
+    let mut offset = loop {
+        match bot
+            .api
+            .get_updates(&GetUpdatesParams::builder().limit(1u32).offset(-1).build())
+        {
+            Ok(updates) => {
+                break updates
+                    .result
+                    .first()
+                    .map_or(0, |update| update.update_id as i64 + 1)
+            }
+            Err(err) => wait_to_poll(&bot, &mut failed_polls, err),
+        }
+    };
+    failed_polls.succeeded();
 
+    let mut failed_updates = Failures::new(MAX_UPDATE_FAILURES);
     loop {
-        let updates = api
-            .get_updates(
-                &GetUpdatesParams::builder()
-                    .timeout(120u32)
-                    .limit(1u32)
-                    .offset(offset)
-                    .build(),
-            )
-            .unwrap();
+        let updates = match bot.api.get_updates(
+            &GetUpdatesParams::builder()
+                .timeout(120u32)
+                .limit(1u32)
+                .offset(offset)
+                .build(),
+        ) {
+            Ok(updates) => updates,
+            Err(err) => {
+                wait_to_poll(&bot, &mut failed_polls, err);
+                continue;
+            }
+        };
+        failed_polls.succeeded();
 
         for update in updates.result {
             offset = update.update_id as i64 + 1;
 
-            if let frankenstein::UpdateContent::Message(message) = update.content {
-                info!(
-                    "message id {} from chat {}",
-                    message.message_id, message.chat.id
-                );
-
-                if let Some(sticker) = message.sticker {
-                    if sticker.is_animated == false {
-                        let file_id = if let Some(thumbnail) = sticker.thumbnail {
-                            thumbnail.file_id.clone()
-                        } else {
-                            sticker.file_id.clone()
-                        };
-
-                        let file_path = api
-                            .get_file(
-                                &frankenstein::GetFileParams::builder()
-                                    .file_id(file_id)
-                                    .build(),
-                            )
-                            .unwrap();
-
-                        if let Some(file_path) = file_path.result.file_path {
-                            let url = format!(
-                                "https://api.telegram.org/file/bot{}/{}",
-                                bot_state.bot_token, file_path
-                            );
-
-                            api.send_chat_action(
-                                &SendChatActionParams::builder()
-                                    .chat_id(message.chat.id)
-                                    .action(frankenstein::ChatAction::UploadPhoto)
-                                    .build(),
-                            )
-                            .ok();
-
-                            // download the file with esp32 http library
-                            let bytes_read =
-                                download_file_into_buffer(&url, &mut webp_buffer).unwrap();
-
-                            info!("Downloaded {} bytes", bytes_read);
-
-                            info!("Loading image");
-                            let image = image::load(
-                                std::io::Cursor::new(&webp_buffer),
-                                image::ImageFormat::WebP,
-                            )
-                            .unwrap();
-
-                            info!("Resizing image");
-
-                            let scaled =
-                                image.resize_exact(64, 64, image::imageops::FilterType::Lanczos3);
-
-                            let states_ = h.render_unoptimized(&scaled.to_rgb8());
-
-                            *states.write().unwrap() = states_;
-
-                            webp_buffer.clear();
-                        }
-                    } else {
-                        api.send_message(
-                            &SendMessageParams::builder()
-                                .chat_id(message.chat.id)
-                                .text("Animated stickers are not supported!")
-                                .build(),
-                        )
-                        .ok();
-                    }
-                }
-
-                match message.text.unwrap_or_default().as_str() {
-                    "/start" | "/help" => {
-                        api.send_message(
-                            &SendMessageParams::builder()
-                                .chat_id(message.chat.id)
-                                .text("Hello! Send a sticker to display it on the screen")
-                                .build(),
-                        )
-                        .ok();
-
-                        if message.chat.id == bot_state.owner_id {
-                            send_owner_info(&bot_state);
-                        }
-                    }
-                    _ => {}
-                }
-
-                if message.chat.type_field == frankenstein::ChatType::Private {
-                    api.forward_message(
-                        &ForwardMessageParams::builder()
-                            .chat_id(bot_state.owner_id)
-                            .from_chat_id(message.chat.id)
-                            .message_id(message.message_id)
-                            .build(),
-                    )
-                    .ok();
-                }
-            }
+            handle_update(&mut bot, update, &mut failed_updates);
         }
     }
 }
+
+/// Waits longer after each failed poll, restarting once Telegram was unreachable too many times
+fn wait_to_poll(bot: &Bot, failed_polls: &mut Failures, err: impl std::fmt::Debug) {
+    if failed_polls.failed() {
+        bot.restart(&format!(
+            "Could not get updates {} times in a row",
+            failed_polls.count()
+        ));
+    }
+
+    let delay = failed_polls.backoff();
+    warn!(
+        "Could not get updates, retrying in {} seconds: {:?}",
+        delay.as_secs(),
+        err
+    );
+    std::thread::sleep(delay);
+}
+
+/// Handles one update, restarting once too many failed in a row
+fn handle_update(bot: &mut Bot, update: Update, failed_updates: &mut Failures) {
+    if updates::handle(bot, update, failed_updates) {
+        bot.restart(&format!(
+            "{} updates failed in a row",
+            failed_updates.count()
+        ));
+    }
+}
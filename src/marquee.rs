@@ -0,0 +1,124 @@
+use std::time::Duration;
+
+use embedded_graphics::prelude::*;
+use image::{Rgb, RgbImage, RgbaImage};
+
+use crate::canvas::{to_rgb888, Canvas};
+use crate::compose;
+use crate::scaling::{PANEL_HEIGHT, PANEL_WIDTH};
+use crate::scene::Scene;
+use crate::text::{self, FontSize, TextStyle};
+
+/// Pixels per second when no speed is given
+pub const DEFAULT_SPEED: u32 = 20;
+pub const MAX_SPEED: u32 = 200;
+
+/// Rendering the GPIO states takes about this long,
+/// faster speeds move more than one pixel per frame
+const MIN_FRAME_TIME: Duration = Duration::from_millis(40);
+
+struct Line {
+    glyphs: Vec<char>,
+    width: u32,
+}
+
+/// Text scrolling from right to left over a still background.
+///
+/// Each line starts over as soon as it has fully left the panel,
+/// so lines of different lengths scroll independently.
+pub struct Marquee {
+    lines: Vec<Line>,
+    size: FontSize,
+    color: Rgb<u8>,
+    /// Pixels per second
+    speed: u32,
+    background: RgbImage,
+}
+
+/// Splits the options of a `/marquee` argument from the text, on top of
+/// the `/text` ones there is `speed=<pixels per second>`
+pub fn parse_options(argument: &str) -> (TextStyle, u32, &str) {
+    let mut speed = DEFAULT_SPEED;
+
+    let (style, text) = text::parse_options_with(argument, |option, value| match option {
+        "speed" => value
+            .parse()
+            .ok()
+            .map(|value: u32| speed = value.clamp(1, MAX_SPEED)),
+        _ => None,
+    });
+
+    (style, speed, text)
+}
+
+impl Marquee {
+    /// Lines are never wrapped, the biggest font that fits all of them
+    /// vertically is used unless the style asks for a size
+    pub fn new(text: &str, style: &TextStyle, speed: u32, background: RgbImage) -> Marquee {
+        let glyphs = text::glyphs(text);
+        let mut lines: Vec<&[char]> = glyphs
+            .split(|&c| c == '\n')
+            .filter(|line| line.iter().any(|&c| c != ' '))
+            .collect();
+
+        let size = style.size.unwrap_or_else(|| {
+            FontSize::ALL
+                .into_iter()
+                .find(|size| lines.len() as u32 * size.line_height() <= PANEL_HEIGHT)
+                .unwrap_or(FontSize::Tiny)
+        });
+        lines.truncate((PANEL_HEIGHT / size.line_height()) as usize);
+
+        Marquee {
+            lines: lines
+                .into_iter()
+                .map(|glyphs| Line {
+                    glyphs: glyphs.to_vec(),
+                    width: text::line_width(glyphs, size),
+                })
+                .collect(),
+            size,
+            color: style.color,
+            speed: speed.clamp(1, MAX_SPEED),
+            background,
+        }
+    }
+}
+
+impl Scene for Marquee {
+    fn frame(&mut self, elapsed: Duration) -> (RgbImage, Option<Duration>) {
+        let scrolled = (elapsed.as_millis() * self.speed as u128 / 1000) as u32;
+
+        let mut layer = RgbaImage::new(PANEL_WIDTH, PANEL_HEIGHT);
+        let mut canvas = Canvas(&mut layer);
+
+        let advance = self.size.advance() as i32;
+        let line_height = self.size.line_height();
+        let top = PANEL_HEIGHT.saturating_sub(self.lines.len() as u32 * line_height) / 2;
+
+        for (row, line) in self.lines.iter().enumerate() {
+            // enters at the right edge, starts over once it's gone past the left one
+            let x = PANEL_WIDTH as i32 - (scrolled % (line.width + PANEL_WIDTH)) as i32;
+            let y = (top + row as u32 * line_height) as i32;
+
+            // only the glyphs on the panel are drawn
+            let first = ((-x).max(0) / advance) as usize;
+            let last = (((PANEL_WIDTH as i32 - x) / advance + 1) as usize).min(line.glyphs.len());
+            if first >= last {
+                continue;
+            }
+
+            text::draw_line(
+                &mut canvas,
+                &line.glyphs[first..last],
+                self.size,
+                Point::new(x + first as i32 * advance, y),
+                to_rgb888(self.color),
+            );
+        }
+
+        let step = Duration::from_millis(1000 / self.speed as u64).max(MIN_FRAME_TIME);
+
+        (compose::composite(&layer, &self.background), Some(step))
+    }
+}
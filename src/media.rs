@@ -0,0 +1,246 @@
+use frankenstein::{Message, PhotoSize, Sticker};
+use image::ImageFormat;
+
+use crate::scaling::{PANEL_HEIGHT, PANEL_WIDTH};
+
+/// Image-bearing file attached to a message
+pub struct MediaFile {
+    pub file_id: String,
+    pub file_unique_id: String,
+    pub file_size: Option<u64>,
+}
+
+impl From<&PhotoSize> for MediaFile {
+    fn from(photo: &PhotoSize) -> Self {
+        MediaFile {
+            file_id: photo.file_id.clone(),
+            file_unique_id: photo.file_unique_id.clone(),
+            file_size: photo.file_size,
+        }
+    }
+}
+
+/// Looks for something we can show on the panel inside a message.
+///
+/// Returns `None` when the message carries no media at all, and `Some(Err(_))`
+/// with a user facing reason when it does, but we can't display it.
+pub fn from_message(message: &Message) -> Option<Result<MediaFile, String>> {
+    if let Some(sticker) = &message.sticker {
+        return Some(from_sticker(sticker));
+    }
+
+    if let Some(photo) = &message.photo {
+        return pick_photo_size(photo).map(|size| Ok(size.into()));
+    }
+
+    // Telegram converts every GIF to an mp4, we can only show its first frame
+    if let Some(animation) = &message.animation {
+        return Some(match &animation.thumbnail {
+            Some(thumbnail) => Ok(thumbnail.into()),
+            None => Err("This GIF has no preview, video animations are not supported!".to_string()),
+        });
+    }
+
+    // the name and mime type come from the sender's client, the download is
+    // sniffed instead and refused then if it isn't an image
+    if let Some(document) = &message.document {
+        return Some(Ok(MediaFile {
+            file_id: document.file_id.clone(),
+            file_unique_id: document.file_unique_id.clone(),
+            file_size: document.file_size,
+        }));
+    }
+
+    None
+}
+
+/// How an image is called in the playlist and in reviews
+pub fn label(message: &Message) -> String {
+    match from_message(message) {
+        Some(_) => describe(message),
+        None => "custom emoji".to_string(),
+    }
+}
+
+/// Short description of the media in a message, for the playlist listing
+pub fn describe(message: &Message) -> String {
+    if let Some(sticker) = &message.sticker {
+        return match &sticker.emoji {
+            Some(emoji) => format!("sticker {emoji}"),
+            None => "sticker".to_string(),
+        };
+    }
+
+    if message.photo.is_some() {
+        return "photo".to_string();
+    }
+
+    if message.animation.is_some() {
+        return "GIF".to_string();
+    }
+
+    match message
+        .document
+        .as_ref()
+        .and_then(|document| document.file_name.as_ref())
+    {
+        Some(file_name) => format!("file {file_name}"),
+        None => "image".to_string(),
+    }
+}
+
+/// Animated and video stickers can only be shown through their still thumbnail
+pub fn from_sticker(sticker: &Sticker) -> Result<MediaFile, String> {
+    match &sticker.thumbnail {
+        Some(thumbnail) => Ok(thumbnail.into()),
+        None if sticker.is_animated => Err("Animated stickers are not supported!".to_string()),
+        None if sticker.is_video => Err("Video stickers are not supported!".to_string()),
+        None => Ok(MediaFile {
+            file_id: sticker.file_id.clone(),
+            file_unique_id: sticker.file_unique_id.clone(),
+            file_size: sticker.file_size,
+        }),
+    }
+}
+
+/// Telegram sends several resized copies of every photo, take the smallest one
+/// that still covers the whole panel, or the biggest one if none does
+pub fn pick_photo_size(sizes: &[PhotoSize]) -> Option<&PhotoSize> {
+    sizes
+        .iter()
+        .filter(|size| size.width >= PANEL_WIDTH && size.height >= PANEL_HEIGHT)
+        .min_by_key(|size| size.width * size.height)
+        .or_else(|| sizes.iter().max_by_key(|size| size.width * size.height))
+}
+
+/// Detects the image format from the magic bytes at the start of the file,
+/// the file extension and mime type sent by clients can't be trusted
+pub fn sniff_format(data: &[u8]) -> Result<ImageFormat, String> {
+    match data {
+        [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a, ..] => Ok(ImageFormat::Png),
+        [0xff, 0xd8, 0xff, ..] => Ok(ImageFormat::Jpeg),
+        [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Ok(ImageFormat::Gif),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Ok(ImageFormat::WebP),
+        // the rest are recognized only to give a better error message
+        [0x1f, 0x8b, ..] => Err("Animated stickers are not supported!".to_string()),
+        [0x1a, 0x45, 0xdf, 0xa3, ..] => Err("WebM videos are not supported!".to_string()),
+        [_, _, _, _, b'f', b't', b'y', b'p', ..] => {
+            Err("MP4 videos and HEIC photos are not supported!".to_string())
+        }
+        [b'B', b'M', ..] => Err("BMP images are not supported!".to_string()),
+        _ => Err("Unrecognized file type, send a PNG, JPEG, GIF or WebP image".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn message(content: Value) -> Message {
+        let mut message = json!({
+            "message_id": 7,
+            "date": 0,
+            "chat": { "id": 42, "type": "private" },
+        });
+        message
+            .as_object_mut()
+            .unwrap()
+            .extend(content.as_object().unwrap().clone());
+
+        serde_json::from_value(message).unwrap()
+    }
+
+    fn size(width: u32, height: u32) -> PhotoSize {
+        PhotoSize {
+            file_id: format!("{width}x{height}"),
+            file_unique_id: format!("{width}x{height}"),
+            width,
+            height,
+            file_size: None,
+        }
+    }
+
+    #[test]
+    fn sniffs_the_supported_formats() {
+        let png = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a, 0, 0];
+        assert_eq!(sniff_format(&png), Ok(ImageFormat::Png));
+        assert_eq!(sniff_format(b"GIF89a\x40\x00"), Ok(ImageFormat::Gif));
+        assert_eq!(sniff_format(b"GIF87a\x40\x00"), Ok(ImageFormat::Gif));
+        assert_eq!(
+            sniff_format(b"RIFF\x24\x00\x00\x00WEBPVP8 "),
+            Ok(ImageFormat::WebP)
+        );
+        assert_eq!(
+            sniff_format(&[0xff, 0xd8, 0xff, 0xe0]),
+            Ok(ImageFormat::Jpeg)
+        );
+    }
+
+    #[test]
+    fn refuses_everything_else() {
+        assert!(sniff_format(b"%PDF-1.7").is_err());
+        assert!(sniff_format(b"RIFF\x24\x00\x00\x00WAVEfmt ").is_err());
+        // too short to be sure
+        assert!(sniff_format(b"GIF8").is_err());
+        assert!(sniff_format(&[]).is_err());
+    }
+
+    #[test]
+    fn picks_the_smallest_photo_that_covers_the_panel() {
+        let sizes = [size(90, 60), size(320, 213), size(1280, 853), size(128, 85)];
+
+        assert_eq!(pick_photo_size(&sizes).unwrap().file_id, "128x85");
+    }
+
+    #[test]
+    fn picks_the_biggest_photo_when_none_covers_the_panel() {
+        let sizes = [size(40, 20), size(90, 45), size(60, 30)];
+
+        assert_eq!(pick_photo_size(&sizes).unwrap().file_id, "90x45");
+        assert!(pick_photo_size(&[]).is_none());
+    }
+
+    #[test]
+    fn leaves_documents_to_the_magic_bytes() {
+        let document = message(json!({
+            "document": {
+                "file_id": "file",
+                "file_unique_id": "unique",
+                "file_name": "cat.png",
+                "mime_type": "application/octet-stream",
+            },
+        }));
+
+        let media_file = from_message(&document).unwrap().unwrap();
+        assert_eq!(media_file.file_id, "file");
+
+        assert!(from_message(&message(json!({ "text": "hello" }))).is_none());
+    }
+
+    #[test]
+    fn shows_animated_stickers_through_their_thumbnail() {
+        let sticker = |thumbnail: Value| {
+            message(json!({
+                "sticker": {
+                    "file_id": "tgs",
+                    "file_unique_id": "tgs",
+                    "type": "regular",
+                    "width": 512,
+                    "height": 512,
+                    "is_animated": true,
+                    "is_video": false,
+                    "thumbnail": thumbnail,
+                },
+            }))
+        };
+
+        let thumbnail =
+            json!({ "file_id": "thumb", "file_unique_id": "thumb", "width": 128, "height": 128 });
+        let media_file = from_message(&sticker(thumbnail)).unwrap().unwrap();
+        assert_eq!(media_file.file_id, "thumb");
+
+        assert!(from_message(&sticker(Value::Null)).unwrap().is_err());
+    }
+}
@@ -0,0 +1,315 @@
+use std::collections::VecDeque;
+use std::fs;
+
+use frankenstein::{InlineKeyboardButton, InlineKeyboardMarkup, Message};
+use log::warn;
+
+use crate::storage;
+
+/// `1` while images from users wait for approval
+const MODERATION_FILE: &str = "moderation";
+
+/// Held messages are small, but a flood could still fill the memory
+pub const MAX_PENDING: usize = 16;
+
+/// What the buttons under a review send back, `mod <decision> <id>`
+const CALLBACK_PREFIX: &str = "mod";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Decision {
+    Approve,
+    Reject,
+    /// Rejects and bans the sender, dropping what else they have waiting
+    Ban,
+}
+
+impl Decision {
+    fn parse(word: &str) -> Option<Decision> {
+        match word {
+            "approve" => Some(Decision::Approve),
+            "reject" => Some(Decision::Reject),
+            "ban" => Some(Decision::Ban),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Decision::Approve => "approve",
+            Decision::Reject => "reject",
+            Decision::Ban => "ban",
+        }
+    }
+}
+
+/// An image waiting for a decision
+pub struct Pending {
+    pub id: u32,
+    pub message: Message,
+    /// What the review says, like `sticker 😀 from Alice (123)`
+    pub summary: String,
+    /// Chat and id of the review message, to edit in the decision
+    pub review: Option<(i64, i32)>,
+}
+
+/// Images from users are held here until an admin approves them.
+/// Only the setting is saved, waiting images are lost at restart
+pub struct Moderation {
+    pub enabled: bool,
+    queue: VecDeque<Pending>,
+    next_id: u32,
+}
+
+impl Moderation {
+    pub fn load() -> Moderation {
+        let enabled = fs::read_to_string(storage::path(MODERATION_FILE))
+            .is_ok_and(|saved| saved.trim() == "1");
+
+        Moderation {
+            enabled,
+            queue: VecDeque::new(),
+            next_id: 0,
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+
+        if let Err(err) = fs::write(
+            storage::path(MODERATION_FILE),
+            if enabled { "1" } else { "0" },
+        ) {
+            warn!("Could not save the moderation setting: {:?}", err);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Holds `message` until a decision, returns its id or the reason it can't
+    pub fn push(&mut self, message: Message, summary: String) -> Result<u32, String> {
+        if self.queue.len() >= MAX_PENDING {
+            return Err("Too many images are waiting for approval, try again later".to_string());
+        }
+
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.queue.push_back(Pending {
+            id,
+            message,
+            summary,
+            review: None,
+        });
+
+        Ok(id)
+    }
+
+    pub fn set_review(&mut self, id: u32, chat_id: i64, message_id: i32) {
+        if let Some(pending) = self.queue.iter_mut().find(|pending| pending.id == id) {
+            pending.review = Some((chat_id, message_id));
+        }
+    }
+
+    /// Removes an image from the queue to act on it, `None` if it was already decided
+    pub fn take(&mut self, id: u32) -> Option<Pending> {
+        let index = self.queue.iter().position(|pending| pending.id == id)?;
+        self.queue.remove(index)
+    }
+
+    /// Removes everything `user_id` sent
+    pub fn take_from(&mut self, user_id: i64) -> Vec<Pending> {
+        let (taken, kept) = std::mem::take(&mut self.queue)
+            .into_iter()
+            .partition(|pending| {
+                pending
+                    .message
+                    .from
+                    .as_ref()
+                    .is_some_and(|user| user.id as i64 == user_id)
+            });
+
+        self.queue = kept;
+        taken.into()
+    }
+}
+
+/// Approve / Reject / Ban sender buttons for the image with `id`
+pub fn keyboard(id: u32) -> InlineKeyboardMarkup {
+    let button = |text: &str, decision: Decision| {
+        InlineKeyboardButton::builder()
+            .text(text)
+            .callback_data(format!("{} {} {}", CALLBACK_PREFIX, decision.name(), id))
+            .build()
+    };
+
+    InlineKeyboardMarkup::builder()
+        .inline_keyboard(vec![vec![
+            button("Approve", Decision::Approve),
+            button("Reject", Decision::Reject),
+            button("Ban sender", Decision::Ban),
+        ]])
+        .build()
+}
+
+/// The decision a review button sent, `None` if `data` isn't from one
+pub fn parse_callback(data: &str) -> Option<(Decision, u32)> {
+    let mut words = data.split(' ');
+
+    if words.next()? != CALLBACK_PREFIX {
+        return None;
+    }
+
+    let decision = Decision::parse(words.next()?)?;
+    let id = words.next()?.parse().ok()?;
+
+    if words.next().is_some() {
+        return None;
+    }
+
+    Some((decision, id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn message(message_id: i32, from: Option<u64>) -> Message {
+        let mut message = json!({
+            "message_id": message_id,
+            "date": 0,
+            "chat": { "id": -100, "type": "supergroup", "title": "friends" },
+            "sticker": {
+                "file_id": "file",
+                "file_unique_id": "unique",
+                "type": "regular",
+                "width": 512,
+                "height": 512,
+                "is_animated": false,
+                "is_video": false,
+            },
+        });
+        if let Some(id) = from {
+            message["from"] = json!({ "id": id, "is_bot": false, "first_name": "Alice" });
+        }
+
+        serde_json::from_value(message).unwrap()
+    }
+
+    fn queue() -> Moderation {
+        Moderation {
+            enabled: true,
+            queue: VecDeque::new(),
+            next_id: 0,
+        }
+    }
+
+    #[test]
+    fn buttons_parse_back() {
+        let keyboard = keyboard(7);
+        let decisions: Vec<_> = keyboard.inline_keyboard[0]
+            .iter()
+            .map(|button| parse_callback(button.callback_data.as_deref().unwrap()))
+            .collect();
+
+        assert_eq!(
+            decisions,
+            [
+                Some((Decision::Approve, 7)),
+                Some((Decision::Reject, 7)),
+                Some((Decision::Ban, 7))
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_callbacks() {
+        for data in [
+            "",
+            "mod",
+            "mod approve",
+            "mod approve x",
+            "mod approve -1",
+            "mod approve 1 2",
+            "mod maybe 1",
+            "mod  approve 1",
+            "panel approve 1",
+            "MOD approve 1",
+        ] {
+            assert_eq!(parse_callback(data), None, "{data:?}");
+        }
+    }
+
+    #[test]
+    fn take_by_id() {
+        let mut moderation = queue();
+        let first = moderation
+            .push(message(1, Some(10)), "first".to_string())
+            .unwrap();
+        let second = moderation
+            .push(message(2, Some(10)), "second".to_string())
+            .unwrap();
+        assert_ne!(first, second);
+
+        moderation.set_review(second, 1, 99);
+        let taken = moderation.take(second).unwrap();
+        assert_eq!(taken.summary, "second");
+        assert_eq!(taken.review, Some((1, 99)));
+
+        // pressing the button twice
+        assert!(moderation.take(second).is_none());
+        assert_eq!(moderation.len(), 1);
+    }
+
+    #[test]
+    fn take_from_one_sender() {
+        let mut moderation = queue();
+        moderation
+            .push(message(1, Some(10)), "a".to_string())
+            .unwrap();
+        moderation
+            .push(message(2, Some(20)), "b".to_string())
+            .unwrap();
+        moderation.push(message(3, None), "c".to_string()).unwrap();
+        moderation
+            .push(message(4, Some(10)), "d".to_string())
+            .unwrap();
+
+        let taken: Vec<_> = moderation
+            .take_from(10)
+            .into_iter()
+            .map(|pending| pending.message.message_id)
+            .collect();
+        assert_eq!(taken, [1, 4]);
+
+        assert_eq!(moderation.len(), 2);
+        assert!(moderation.take_from(10).is_empty());
+        assert_eq!(moderation.take_from(20).len(), 1);
+        assert_eq!(moderation.take_from(0).len(), 0);
+        assert!(!moderation.is_empty());
+    }
+
+    #[test]
+    fn the_queue_is_capped() {
+        let mut moderation = queue();
+        for id in 0..MAX_PENDING {
+            moderation
+                .push(message(id as i32, Some(10)), String::new())
+                .unwrap();
+        }
+
+        assert!(moderation
+            .push(message(99, Some(10)), String::new())
+            .is_err());
+
+        moderation.take(0);
+        assert!(moderation
+            .push(message(99, Some(10)), String::new())
+            .is_ok());
+    }
+}
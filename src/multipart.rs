@@ -0,0 +1,280 @@
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Files are copied to the connection through a buffer this big
+const CHUNK_SIZE: usize = 2048;
+
+/// Contents of a file part
+pub enum FileSource {
+    /// A file on flash, read while the request is being sent
+    Path(PathBuf),
+    /// Bytes generated on the fly, like a screenshot
+    Memory { file_name: String, data: Vec<u8> },
+}
+
+impl FileSource {
+    fn file_name(&self) -> String {
+        let name = match self {
+            FileSource::Path(path) => path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            FileSource::Memory { file_name, .. } => file_name.clone(),
+        };
+
+        // it goes inside a quoted header value
+        name.replace(['"', '\r', '\n'], "_")
+    }
+
+    fn len(&self) -> std::io::Result<u64> {
+        match self {
+            FileSource::Path(path) => Ok(std::fs::metadata(path)?.len()),
+            FileSource::Memory { data, .. } => Ok(data.len() as u64),
+        }
+    }
+}
+
+enum PartBody {
+    Text(String),
+    File(FileSource),
+}
+
+/// A `multipart/form-data` body that is written out part by part,
+/// files are never loaded in memory as a whole
+pub struct Multipart {
+    boundary: String,
+    parts: Vec<(String, PartBody)>,
+}
+
+impl Multipart {
+    /// `boundary` must not show up in any part, a random one is good enough
+    pub fn new(boundary: impl Into<String>) -> Multipart {
+        Multipart {
+            boundary: boundary.into(),
+            parts: Vec::new(),
+        }
+    }
+
+    pub fn text(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.parts.push((name.into(), PartBody::Text(value.into())));
+    }
+
+    pub fn file(&mut self, name: impl Into<String>, source: FileSource) {
+        self.parts.push((name.into(), PartBody::File(source)));
+    }
+
+    pub fn content_type(&self) -> String {
+        format!("multipart/form-data; boundary={}", self.boundary)
+    }
+
+    /// Exact size of the body, so it can be sent without chunked encoding
+    pub fn content_length(&self) -> std::io::Result<u64> {
+        let mut length = 0;
+
+        for (name, body) in &self.parts {
+            length += self.part_header(name, body).len() as u64;
+            length += match body {
+                PartBody::Text(text) => text.len() as u64,
+                PartBody::File(source) => source.len()?,
+            };
+            length += 2;
+        }
+
+        Ok(length + self.closing().len() as u64)
+    }
+
+    pub fn write_to(&self, out: &mut impl Write) -> std::io::Result<()> {
+        for (name, body) in &self.parts {
+            out.write_all(self.part_header(name, body).as_bytes())?;
+
+            match body {
+                PartBody::Text(text) => out.write_all(text.as_bytes())?,
+                PartBody::File(FileSource::Memory { data, .. }) => out.write_all(data)?,
+                PartBody::File(FileSource::Path(path)) => copy_file(path, out)?,
+            }
+
+            out.write_all(b"\r\n")?;
+        }
+
+        out.write_all(self.closing().as_bytes())?;
+        Ok(())
+    }
+
+    fn part_header(&self, name: &str, body: &PartBody) -> String {
+        match body {
+            PartBody::Text(_) => format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n",
+                self.boundary, name
+            ),
+            PartBody::File(source) => {
+                let file_name = source.file_name();
+                format!(
+                    "--{}\r\nContent-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\nContent-Type: {}\r\n\r\n",
+                    self.boundary,
+                    name,
+                    file_name,
+                    content_type(&file_name)
+                )
+            }
+        }
+    }
+
+    fn closing(&self) -> String {
+        format!("--{}--\r\n", self.boundary)
+    }
+}
+
+fn copy_file(path: &Path, out: &mut impl Write) -> std::io::Result<()> {
+    let mut file = File::open(path)?;
+    let mut buffer = [0u8; CHUNK_SIZE];
+
+    loop {
+        match file.read(&mut buffer)? {
+            0 => return Ok(()),
+            read => out.write_all(&buffer[..read])?,
+        }
+    }
+}
+
+/// Telegram looks at the contents, this only has to be plausible
+fn content_type(file_name: &str) -> &'static str {
+    let extension = file_name
+        .rsplit_once('.')
+        .map(|(_, extension)| extension.to_ascii_lowercase())
+        .unwrap_or_default();
+
+    match extension.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "txt" => "text/plain",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOUNDARY: &str = "----hub75botTEST";
+
+    /// Splits a `multipart/form-data` body into (headers, contents) the way a server would
+    fn parse(body: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let delimiter = format!("--{BOUNDARY}").into_bytes();
+        let closing = format!("--{BOUNDARY}--\r\n").into_bytes();
+        assert!(body.ends_with(&closing), "the body isn't closed");
+
+        let mut body = &body[..body.len() - closing.len()];
+        let mut parts = Vec::new();
+
+        while !body.is_empty() {
+            assert!(body.starts_with(&delimiter), "missing delimiter");
+            body = &body[delimiter.len()..];
+            assert!(body.starts_with(b"\r\n"));
+            body = &body[2..];
+
+            let next = find(body, &[b"\r\n".as_slice(), &delimiter].concat())
+                .map(|at| at + 2)
+                .unwrap_or(body.len());
+            let part = &body[..next];
+            body = &body[next..];
+
+            let headers_end = find(part, b"\r\n\r\n").expect("part without headers");
+            let headers = String::from_utf8(part[..headers_end].to_vec()).unwrap();
+            let contents = part[headers_end + 4..]
+                .strip_suffix(b"\r\n")
+                .expect("part not terminated");
+            parts.push((headers, contents.to_vec()));
+        }
+
+        parts
+    }
+
+    fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+        haystack
+            .windows(needle.len())
+            .position(|window| window == needle)
+    }
+
+    fn written(form: &Multipart) -> Vec<u8> {
+        let mut body = Vec::new();
+        form.write_to(&mut body).unwrap();
+        body
+    }
+
+    #[test]
+    fn text_and_file_parts() {
+        let dir = std::env::temp_dir().join(format!("multipart-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // bigger than a chunk and with a line break in it, like any image
+        let photo: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+        let path = dir.join("frame.PNG");
+        std::fs::write(&path, &photo).unwrap();
+
+        let mut form = Multipart::new(BOUNDARY);
+        form.text("chat_id", "42");
+        form.file("photo", FileSource::Path(path));
+        form.file(
+            "document",
+            FileSource::Memory {
+                file_name: "log \"today\".txt".to_string(),
+                data: b"hello\r\n".to_vec(),
+            },
+        );
+
+        let body = written(&form);
+        assert_eq!(form.content_length().unwrap(), body.len() as u64);
+        assert_eq!(
+            form.content_type(),
+            "multipart/form-data; boundary=----hub75botTEST"
+        );
+
+        let parts = parse(&body);
+        assert_eq!(parts.len(), 3);
+
+        assert_eq!(
+            parts[0].0,
+            "Content-Disposition: form-data; name=\"chat_id\""
+        );
+        assert_eq!(parts[0].1, b"42");
+
+        assert_eq!(
+            parts[1].0,
+            "Content-Disposition: form-data; name=\"photo\"; filename=\"frame.PNG\"\r\nContent-Type: image/png"
+        );
+        assert_eq!(parts[1].1, photo);
+
+        assert_eq!(
+            parts[2].0,
+            "Content-Disposition: form-data; name=\"document\"; filename=\"log _today_.txt\"\r\nContent-Type: text/plain"
+        );
+        assert_eq!(parts[2].1, b"hello\r\n");
+
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn empty_form() {
+        let form = Multipart::new(BOUNDARY);
+        let body = written(&form);
+
+        assert_eq!(body, b"------hub75botTEST--\r\n");
+        assert_eq!(form.content_length().unwrap(), body.len() as u64);
+    }
+
+    #[test]
+    fn missing_file() {
+        let mut form = Multipart::new(BOUNDARY);
+        form.file(
+            "photo",
+            FileSource::Path(PathBuf::from("/nonexistent/frame.png")),
+        );
+
+        assert!(form.content_length().is_err());
+        assert!(form.write_to(&mut Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,258 @@
+use std::fs;
+
+use frankenstein::{InlineKeyboardButton, InlineKeyboardMarkup};
+use log::warn;
+
+use crate::storage;
+
+/// The brightness level, from 1 to `BRIGHTNESS_LEVELS`
+const BRIGHTNESS_FILE: &str = "brightness";
+
+pub const BRIGHTNESS_LEVELS: u8 = 8;
+
+/// What the `/panel` buttons send back, `panel <action>`
+const CALLBACK_PREFIX: &str = "panel";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    BrightnessDown,
+    BrightnessUp,
+    Previous,
+    Next,
+    /// Freezes or resumes whatever is moving
+    Pause,
+    Clock,
+    /// Turns the panel off and back on, without losing what it shows
+    Blank,
+}
+
+impl Action {
+    fn parse(word: &str) -> Option<Action> {
+        match word {
+            "dimmer" => Some(Action::BrightnessDown),
+            "brighter" => Some(Action::BrightnessUp),
+            "previous" => Some(Action::Previous),
+            "next" => Some(Action::Next),
+            "pause" => Some(Action::Pause),
+            "clock" => Some(Action::Clock),
+            "blank" => Some(Action::Blank),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Action::BrightnessDown => "dimmer",
+            Action::BrightnessUp => "brighter",
+            Action::Previous => "previous",
+            Action::Next => "next",
+            Action::Pause => "pause",
+            Action::Clock => "clock",
+            Action::Blank => "blank",
+        }
+    }
+}
+
+/// What the `/panel` message shows above the buttons
+pub struct State {
+    pub brightness: u8,
+    pub blank: bool,
+    pub paused: bool,
+    /// Number of the slide shown, the playlist length and whether it plays
+    pub slide: Option<(usize, usize, bool)>,
+}
+
+impl State {
+    pub fn describe(&self) -> String {
+        let mut text = format!("Brightness: {} of {}", self.brightness, BRIGHTNESS_LEVELS);
+        if self.blank {
+            text += ", panel off";
+        }
+        if self.paused {
+            text += "\nPaused";
+        }
+
+        if let Some((number, count, playing)) = self.slide {
+            text += &format!(
+                "\nPlaylist: image {} of {}, {}",
+                number,
+                count,
+                if playing { "playing" } else { "stopped" }
+            );
+        }
+
+        text
+    }
+
+    pub fn keyboard(&self) -> InlineKeyboardMarkup {
+        let button = |text: &str, action: Action| {
+            InlineKeyboardButton::builder()
+                .text(text)
+                .callback_data(format!("{} {}", CALLBACK_PREFIX, action.name()))
+                .build()
+        };
+
+        InlineKeyboardMarkup::builder()
+            .inline_keyboard(vec![
+                vec![
+                    button("🔅 Dimmer", Action::BrightnessDown),
+                    button("🔆 Brighter", Action::BrightnessUp),
+                ],
+                vec![
+                    button("⏮ Previous", Action::Previous),
+                    button(
+                        if self.paused {
+                            "▶ Resume"
+                        } else {
+                            "⏸ Pause"
+                        },
+                        Action::Pause,
+                    ),
+                    button("⏭ Next", Action::Next),
+                ],
+                vec![
+                    button("🕒 Clock", Action::Clock),
+                    button(
+                        if self.blank {
+                            "💡 Turn on"
+                        } else {
+                            "⬛ Blank"
+                        },
+                        Action::Blank,
+                    ),
+                ],
+            ])
+            .build()
+    }
+}
+
+/// The action a `/panel` button sent, `None` if `data` isn't from one
+pub fn parse_callback(data: &str) -> Option<Action> {
+    let (prefix, action) = data.split_once(' ')?;
+
+    if prefix != CALLBACK_PREFIX {
+        return None;
+    }
+
+    Action::parse(action)
+}
+
+pub fn load_brightness() -> u8 {
+    fs::read_to_string(storage::path(BRIGHTNESS_FILE))
+        .ok()
+        .and_then(|saved| saved.trim().parse().ok())
+        .unwrap_or(BRIGHTNESS_LEVELS)
+        .clamp(1, BRIGHTNESS_LEVELS)
+}
+
+pub fn save_brightness(level: u8) {
+    if let Err(err) = fs::write(storage::path(BRIGHTNESS_FILE), level.to_string()) {
+        warn!("Could not save the brightness: {:?}", err);
+    }
+}
+
+/// One level brighter or dimmer than `level`, staying between 1 and `BRIGHTNESS_LEVELS`
+pub fn step_brightness(level: u8, brighter: bool) -> u8 {
+    if brighter {
+        (level + 1).min(BRIGHTNESS_LEVELS)
+    } else {
+        level.saturating_sub(1).max(1)
+    }
+}
+
+/// What the display is told for a brightness `level`, the steps look even to the eye
+pub fn brightness_value(level: u8) -> u8 {
+    let level = level.min(BRIGHTNESS_LEVELS) as u32;
+    let levels = BRIGHTNESS_LEVELS as u32;
+
+    (255 * level * level / (levels * levels)) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(paused: bool, blank: bool) -> State {
+        State {
+            brightness: 3,
+            blank,
+            paused,
+            slide: Some((2, 5, true)),
+        }
+    }
+
+    #[test]
+    fn buttons_parse_back() {
+        for keyboard in [state(false, false).keyboard(), state(true, true).keyboard()] {
+            let actions: Vec<_> = keyboard
+                .inline_keyboard
+                .iter()
+                .flatten()
+                .map(|button| parse_callback(button.callback_data.as_deref().unwrap()))
+                .collect();
+
+            assert_eq!(
+                actions,
+                [
+                    Some(Action::BrightnessDown),
+                    Some(Action::BrightnessUp),
+                    Some(Action::Previous),
+                    Some(Action::Pause),
+                    Some(Action::Next),
+                    Some(Action::Clock),
+                    Some(Action::Blank),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_callbacks() {
+        for data in [
+            "",
+            "panel",
+            "panel ",
+            "panel brighter now",
+            "panel louder",
+            "panelbrighter",
+            "mod brighter",
+            "mod approve 1",
+            "PANEL brighter",
+        ] {
+            assert_eq!(parse_callback(data), None, "{data:?}");
+        }
+    }
+
+    #[test]
+    fn brightness_steps_stay_in_range() {
+        assert_eq!(step_brightness(BRIGHTNESS_LEVELS, true), BRIGHTNESS_LEVELS);
+        assert_eq!(step_brightness(1, false), 1);
+        assert_eq!(step_brightness(3, true), 4);
+        assert_eq!(step_brightness(3, false), 2);
+    }
+
+    #[test]
+    fn brightness_values() {
+        assert_eq!(brightness_value(0), 0);
+        assert_eq!(brightness_value(BRIGHTNESS_LEVELS), 255);
+        assert_eq!(brightness_value(u8::MAX), 255);
+
+        // never off while turned on, and every step is brighter than the last
+        assert!(brightness_value(1) > 0);
+        for level in 1..BRIGHTNESS_LEVELS {
+            assert!(brightness_value(level) < brightness_value(level + 1));
+        }
+    }
+
+    #[test]
+    fn describe() {
+        assert_eq!(
+            state(true, true).describe(),
+            "Brightness: 3 of 8, panel off\nPaused\nPlaylist: image 2 of 5, playing"
+        );
+
+        let mut state = state(false, false);
+        state.slide = None;
+        assert_eq!(state.describe(), "Brightness: 3 of 8");
+    }
+}
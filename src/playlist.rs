@@ -0,0 +1,415 @@
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use image::RgbImage;
+use log::warn;
+
+use crate::scaling::{PANEL_HEIGHT, PANEL_WIDTH};
+use crate::scene::Scene;
+use crate::storage;
+use crate::transition::{self, Transition};
+
+/// A line per setting and per entry, oldest entry first:
+///
+/// ```text
+/// interval 30
+/// playing 1
+/// keep 10
+/// entry 17 sticker 😀
+/// ```
+const INDEX_FILE: &str = "playlist";
+
+/// Every entry is a 12KB frame, kept in memory while the slideshow runs
+pub const MAX_ENTRIES: usize = 32;
+
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(30);
+pub const MIN_INTERVAL: Duration = Duration::from_secs(2);
+pub const MAX_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+struct Entry {
+    id: u32,
+    label: String,
+}
+
+/// Frames shown in rotation, saved on flash as they were displayed.
+///
+/// Entries are either picked by hand with `/playlist add`, or, with
+/// `keep_last` set, every displayed image joins and the oldest ones drop out.
+pub struct Playlist {
+    /// Where the index and the frames are
+    dir: PathBuf,
+    entries: Vec<Entry>,
+    pub interval: Duration,
+    keep_last: Option<usize>,
+    /// Resumed at boot when set
+    pub playing: bool,
+    next_id: u32,
+}
+
+impl Playlist {
+    pub fn load() -> Playlist {
+        Playlist::open(storage::MOUNT_POINT)
+    }
+
+    /// Reads the index in `dir`, entries whose frame went missing are forgotten
+    pub fn open(dir: impl Into<PathBuf>) -> Playlist {
+        let mut playlist = Playlist {
+            dir: dir.into(),
+            entries: Vec::new(),
+            interval: DEFAULT_INTERVAL,
+            keep_last: None,
+            playing: false,
+            next_id: 0,
+        };
+
+        let index = fs::read_to_string(playlist.dir.join(INDEX_FILE)).unwrap_or_default();
+
+        for line in index.lines() {
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+
+            match key {
+                "interval" => {
+                    if let Ok(seconds) = value.parse() {
+                        playlist.interval = Duration::from_secs(seconds);
+                    }
+                }
+                "keep" => {
+                    playlist.keep_last = value
+                        .parse()
+                        .ok()
+                        .map(|keep_last: usize| keep_last.clamp(1, MAX_ENTRIES))
+                }
+                "playing" => playlist.playing = value == "1",
+                "entry" => {
+                    let (id, label) = value.split_once(' ').unwrap_or((value, ""));
+                    let Ok(id) = id.parse() else { continue };
+
+                    if fs::metadata(playlist.dir.join(frame_file(id))).is_ok() {
+                        playlist.entries.push(Entry {
+                            id,
+                            label: label.to_string(),
+                        });
+                        playlist.next_id = playlist.next_id.max(id + 1);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        playlist
+    }
+
+    pub fn keep_last(&self) -> Option<usize> {
+        self.keep_last
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Oldest first
+    pub fn labels(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().map(|entry| entry.label.as_str())
+    }
+
+    /// Adds a frame at the end, returns the reason if it can't
+    pub fn add(&mut self, frame: &RgbImage, label: &str) -> Result<(), String> {
+        let limit = self.keep_last.unwrap_or(MAX_ENTRIES);
+        if self.keep_last.is_none() && self.entries.len() >= limit {
+            return Err(format!(
+                "The playlist is full, it holds {MAX_ENTRIES} images. Remove some first"
+            ));
+        }
+
+        let id = self.next_id;
+        if let Err(err) = fs::write(self.dir.join(frame_file(id)), frame.as_raw()) {
+            warn!("Could not save playlist frame {}: {:?}", id, err);
+            return Err("Could not save the image, the storage may be full".to_string());
+        }
+
+        self.next_id += 1;
+        self.entries.push(Entry {
+            id,
+            // the index is line based
+            label: label.replace(['\n', '\r'], " "),
+        });
+
+        while self.entries.len() > limit {
+            self.remove(0);
+        }
+
+        self.save();
+        Ok(())
+    }
+
+    /// `index` starts at 0 and counts from the oldest entry
+    pub fn remove(&mut self, index: usize) -> bool {
+        if index >= self.entries.len() {
+            return false;
+        }
+
+        let entry = self.entries.remove(index);
+        fs::remove_file(self.dir.join(frame_file(entry.id))).ok();
+
+        if self.entries.is_empty() {
+            self.playing = false;
+        }
+
+        self.save();
+        true
+    }
+
+    pub fn clear(&mut self) {
+        while !self.entries.is_empty() {
+            self.remove(0);
+        }
+    }
+
+    /// `None` keeps only the entries added by hand, `Some(n)` makes every
+    /// displayed image join and drops the oldest beyond `n`
+    pub fn set_keep_last(&mut self, keep_last: Option<usize>) {
+        self.keep_last = keep_last.map(|keep_last| keep_last.clamp(1, MAX_ENTRIES));
+
+        if let Some(keep_last) = self.keep_last {
+            while self.entries.len() > keep_last {
+                self.remove(0);
+            }
+        }
+
+        self.save();
+    }
+
+    pub fn stop(&mut self) {
+        if self.playing {
+            self.playing = false;
+            self.save();
+        }
+    }
+
+    /// Frame and label of entry `index`, counting from the oldest
+    pub fn entry(&self, index: usize) -> Option<(RgbImage, &str)> {
+        let entry = self.entries.get(index)?;
+        let pixels = fs::read(self.dir.join(frame_file(entry.id))).ok()?;
+        let frame = RgbImage::from_raw(PANEL_WIDTH, PANEL_HEIGHT, pixels)?;
+
+        Some((frame, &entry.label))
+    }
+
+    /// Loads the frames for a slideshow beginning at entry `start`,
+    /// `None` if there is nothing to show
+    pub fn slideshow(&self, start: usize, transition: Transition) -> Option<Slideshow> {
+        let frames: Vec<RgbImage> = (0..self.entries.len())
+            .filter_map(|index| self.entry(index).map(|(frame, _)| frame))
+            .collect();
+
+        if frames.is_empty() {
+            return None;
+        }
+
+        Some(Slideshow {
+            start: start % frames.len(),
+            frames,
+            interval: self.interval,
+            transition,
+        })
+    }
+
+    pub fn save(&self) {
+        let mut index = format!(
+            "interval {}\nplaying {}\n",
+            self.interval.as_secs(),
+            self.playing as u8
+        );
+        if let Some(keep_last) = self.keep_last {
+            index += &format!("keep {keep_last}\n");
+        }
+        for entry in &self.entries {
+            index += &format!("entry {} {}\n", entry.id, entry.label);
+        }
+
+        if let Err(err) = fs::write(self.dir.join(INDEX_FILE), index) {
+            warn!("Could not save the playlist: {:?}", err);
+        }
+    }
+}
+
+fn frame_file(id: u32) -> String {
+    format!("playlist_{id}")
+}
+
+/// Cycles through the playlist frames, one every `interval`
+pub struct Slideshow {
+    frames: Vec<RgbImage>,
+    interval: Duration,
+    start: usize,
+    transition: Transition,
+}
+
+impl Scene for Slideshow {
+    fn frame(&mut self, elapsed: Duration) -> (RgbImage, Option<Duration>) {
+        let interval = self.interval.as_millis().max(1);
+        let step = elapsed.as_millis() / interval;
+        let index = (self.start + step as usize) % self.frames.len();
+        let into_slide = Duration::from_millis((elapsed.as_millis() % interval) as u64);
+
+        // the display thread already transitions into the first slide
+        if step > 0 && self.frames.len() > 1 {
+            if let Some(progress) = self.transition.progress(into_slide) {
+                let previous = (index + self.frames.len() - 1) % self.frames.len();
+                let frame =
+                    self.transition
+                        .blend(&self.frames[previous], &self.frames[index], progress);
+
+                return (frame, Some(transition::FRAME_TIME));
+            }
+        }
+
+        // a single frame never changes
+        let next = (self.frames.len() > 1)
+            .then(|| Duration::from_millis((interval - elapsed.as_millis() % interval) as u64));
+
+        (self.frames[index].clone(), next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use image::Rgb;
+
+    use super::*;
+
+    /// An empty directory of its own for every test
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("playlist-{}-{}", std::process::id(), name));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn frame(value: u8) -> RgbImage {
+        RgbImage::from_pixel(PANEL_WIDTH, PANEL_HEIGHT, Rgb([value, value, value]))
+    }
+
+    fn labels(playlist: &Playlist) -> Vec<&str> {
+        playlist.labels().collect()
+    }
+
+    fn frame_files(dir: &Path) -> usize {
+        fs::read_dir(dir)
+            .unwrap()
+            .filter(|file| file.as_ref().unwrap().file_name() != INDEX_FILE)
+            .count()
+    }
+
+    #[test]
+    fn reads_back_what_it_saved() {
+        let dir = temp_dir("round-trip");
+        let mut playlist = Playlist::open(&dir);
+        playlist.add(&frame(1), "sticker 😀").unwrap();
+        playlist.add(&frame(2), "photo\nwith a newline").unwrap();
+        playlist.interval = Duration::from_secs(90);
+        playlist.playing = true;
+        playlist.set_keep_last(Some(5));
+
+        let loaded = Playlist::open(&dir);
+        assert_eq!(labels(&loaded), ["sticker 😀", "photo with a newline"]);
+        assert_eq!(loaded.interval, Duration::from_secs(90));
+        assert!(loaded.playing);
+        assert_eq!(loaded.keep_last(), Some(5));
+
+        let (first, label) = loaded.entry(0).unwrap();
+        assert_eq!(first, frame(1));
+        assert_eq!(label, "sticker 😀");
+
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn forgets_entries_whose_frame_is_gone() {
+        let dir = temp_dir("missing");
+        let mut playlist = Playlist::open(&dir);
+        playlist.add(&frame(1), "one").unwrap();
+        playlist.add(&frame(2), "two").unwrap();
+
+        fs::remove_file(dir.join(frame_file(0))).unwrap();
+
+        let mut loaded = Playlist::open(&dir);
+        assert_eq!(labels(&loaded), ["two"]);
+
+        // and new ids don't reuse the ones still on flash
+        loaded.add(&frame(3), "three").unwrap();
+        assert_eq!(loaded.entry(0).unwrap().0, frame(2));
+        assert_eq!(loaded.entry(1).unwrap().0, frame(3));
+
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn holds_up_to_max_entries() {
+        let dir = temp_dir("full");
+        let mut playlist = Playlist::open(&dir);
+
+        for index in 0..MAX_ENTRIES {
+            playlist.add(&frame(index as u8), "image").unwrap();
+        }
+        assert!(playlist.add(&frame(0), "one too many").is_err());
+        assert_eq!(playlist.len(), MAX_ENTRIES);
+        assert_eq!(frame_files(&dir), MAX_ENTRIES);
+
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn keeps_the_last_images_shown() {
+        let dir = temp_dir("keep-last");
+        let mut playlist = Playlist::open(&dir);
+        for index in 0..5 {
+            playlist.add(&frame(index), &index.to_string()).unwrap();
+        }
+
+        playlist.set_keep_last(Some(3));
+        assert_eq!(labels(&playlist), ["2", "3", "4"]);
+
+        // full, but the oldest makes room instead of refusing
+        playlist.add(&frame(5), "5").unwrap();
+        assert_eq!(labels(&playlist), ["3", "4", "5"]);
+        assert_eq!(frame_files(&dir), 3);
+
+        playlist.set_keep_last(Some(0));
+        assert_eq!(playlist.keep_last(), Some(1));
+        assert_eq!(labels(&playlist), ["5"]);
+
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn removes_and_clears() {
+        let dir = temp_dir("remove");
+        let mut playlist = Playlist::open(&dir);
+        for index in 0..3 {
+            playlist.add(&frame(index), &index.to_string()).unwrap();
+        }
+        playlist.playing = true;
+
+        assert!(playlist.remove(1));
+        assert!(!playlist.remove(2));
+        assert_eq!(labels(&playlist), ["0", "2"]);
+        assert_eq!(frame_files(&dir), 2);
+        assert_eq!(labels(&Playlist::open(&dir)), ["0", "2"]);
+
+        playlist.clear();
+        assert!(playlist.is_empty());
+        assert!(!playlist.playing);
+        assert_eq!(frame_files(&dir), 0);
+        assert!(Playlist::open(&dir).is_empty());
+
+        fs::remove_dir_all(dir).ok();
+    }
+}
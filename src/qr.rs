@@ -0,0 +1,143 @@
+use image::{Rgb, RgbImage};
+use qrcode::{Color, EcLevel, QrCode};
+
+use crate::scaling::{PANEL_HEIGHT, PANEL_WIDTH};
+
+/// The standard asks for 4 modules, phone cameras are happy with 2
+/// and the space goes to a bigger version or module size
+const MIN_QUIET_ZONE: u32 = 2;
+
+/// Version 10 is 57 modules wide, the biggest that fits with its quiet zone.
+/// At the lowest error correction it holds this many bytes
+pub const MAX_BYTES: usize = 271;
+
+const LIGHT: Rgb<u8> = Rgb([255, 255, 255]);
+const DARK: Rgb<u8> = Rgb([0, 0, 0]);
+
+/// Encodes `text` in the QR code with the biggest modules that fits the panel,
+/// and the highest error correction among those.
+/// The error is a user facing reason when the text is too long
+pub fn render(text: &str) -> Result<RgbImage, String> {
+    let (code, scale) = choose(text).ok_or_else(|| {
+        format!(
+            "This is too long for a QR code the panel can show, it fits at most {MAX_BYTES} bytes of text ({} were sent)",
+            text.len()
+        )
+    })?;
+
+    let size = code.width() as u32 * scale;
+    let left = (PANEL_WIDTH - size) / 2;
+    let top = (PANEL_HEIGHT - size) / 2;
+
+    let colors = code.to_colors();
+    let mut image = RgbImage::from_pixel(PANEL_WIDTH, PANEL_HEIGHT, LIGHT);
+
+    for (index, color) in colors.iter().enumerate() {
+        if *color != Color::Dark {
+            continue;
+        }
+
+        let x = left + (index % code.width()) as u32 * scale;
+        let y = top + (index / code.width()) as u32 * scale;
+        for dy in 0..scale {
+            for dx in 0..scale {
+                image.put_pixel(x + dx, y + dy, DARK);
+            }
+        }
+    }
+
+    Ok(image)
+}
+
+/// The code for `text` and how many pixels wide its modules are drawn
+fn choose(text: &str) -> Option<(QrCode, u32)> {
+    let panel = PANEL_WIDTH.min(PANEL_HEIGHT);
+
+    [EcLevel::H, EcLevel::Q, EcLevel::M, EcLevel::L]
+        .into_iter()
+        // picks the smallest version that holds the text at this level
+        .filter_map(|level| QrCode::with_error_correction_level(text, level).ok())
+        .filter_map(|code| {
+            let scale = panel / (code.width() as u32 + 2 * MIN_QUIET_ZONE);
+            (scale >= 1).then_some((code, scale))
+        })
+        // max_by_key keeps the last of equal scales, reversed that's the highest level
+        .rev()
+        .max_by_key(|(_, scale)| *scale)
+}
+
+/// The text phones recognize as a network to join
+pub fn wifi(ssid: &str, password: &str) -> String {
+    let escape = |text: &str| {
+        text.chars()
+            .flat_map(|c| match c {
+                '\\' | ';' | ',' | ':' | '"' => vec!['\\', c],
+                c => vec![c],
+            })
+            .collect::<String>()
+    };
+
+    if password.is_empty() {
+        format!("WIFI:T:nopass;S:{};;", escape(ssid))
+    } else {
+        format!("WIFI:T:WPA;S:{};P:{};;", escape(ssid), escape(password))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use qrcode::Version;
+
+    use super::*;
+
+    #[test]
+    fn fits_up_to_max_bytes() {
+        // lowercase letters can only be encoded as bytes
+        let longest = "x".repeat(MAX_BYTES);
+        let (code, scale) = choose(&longest).unwrap();
+        assert_eq!(code.version(), Version::Normal(10));
+        assert_eq!(code.error_correction_level(), EcLevel::L);
+        assert_eq!(scale, 1);
+        assert!(render(&longest).is_ok());
+
+        assert!(choose(&"x".repeat(MAX_BYTES + 1)).is_none());
+        assert!(render(&"x".repeat(MAX_BYTES + 1))
+            .unwrap_err()
+            .contains("272 were sent"));
+    }
+
+    #[test]
+    fn prefers_big_modules_then_error_correction() {
+        // version 1 even at H, 21 modules and the quiet zone fit twice
+        let (code, scale) = choose("hi").unwrap();
+        assert_eq!(code.error_correction_level(), EcLevel::H);
+        assert_eq!(scale, 2);
+
+        // 15 bytes need version 3 at H, too big for 2 pixel modules,
+        // but still fit version 2 at Q
+        let (code, scale) = choose(&"x".repeat(15)).unwrap();
+        assert_eq!(code.version(), Version::Normal(2));
+        assert_eq!(code.error_correction_level(), EcLevel::Q);
+        assert_eq!(scale, 2);
+    }
+
+    #[test]
+    fn centers_the_code() {
+        let image = render("hi").unwrap();
+
+        // 42 pixels wide, the finder pattern's corner is dark
+        assert_eq!(*image.get_pixel(11, 11), DARK);
+        assert_eq!(*image.get_pixel(10, 10), LIGHT);
+        assert_eq!(*image.get_pixel(52, 11), DARK);
+        assert_eq!(*image.get_pixel(53, 11), LIGHT);
+    }
+
+    #[test]
+    fn escapes_wifi_fields() {
+        assert_eq!(
+            wifi("a;b", r#"p,w:\""#),
+            r#"WIFI:T:WPA;S:a\;b;P:p\,w\:\\\";;"#
+        );
+        assert_eq!(wifi("open", ""), "WIFI:T:nopass;S:open;;");
+    }
+}
@@ -0,0 +1,385 @@
+use std::collections::HashMap;
+use std::fs;
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use crate::storage;
+
+/// A line per limit, `off` when there is none:
+///
+/// ```text
+/// user 5 30
+/// global off
+/// ```
+const LIMITS_FILE: &str = "rate_limit";
+
+pub const DEFAULT_USER_LIMIT: Limit = Limit {
+    count: 5,
+    window: Duration::from_secs(30),
+};
+pub const DEFAULT_GLOBAL_LIMIT: Limit = Limit {
+    count: 20,
+    window: Duration::from_secs(60),
+};
+
+pub const MAX_COUNT: u32 = 100;
+pub const MAX_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Past this many users, the ones with a full bucket are forgotten
+const MAX_TRACKED: usize = 64;
+
+/// At most `count` actions in a row, then one every `window / count`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limit {
+    pub count: u32,
+    pub window: Duration,
+}
+
+impl Limit {
+    /// `count seconds`, the reason if it isn't one
+    pub fn parse(text: &str) -> Result<Limit, String> {
+        let mut words = text.split_whitespace();
+        let (Some(count), Some(seconds), None) = (words.next(), words.next(), words.next()) else {
+            return Err(
+                "A limit is a number of actions followed by seconds, like 5 30".to_string(),
+            );
+        };
+
+        match (count.parse(), seconds.parse().map(Duration::from_secs)) {
+            (Ok(count), Ok(window))
+                if (1..=MAX_COUNT).contains(&count)
+                    && (Duration::from_secs(1)..=MAX_WINDOW).contains(&window) =>
+            {
+                Ok(Limit { count, window })
+            }
+            _ => Err(format!(
+                "Use from 1 to {} actions in 1 to {} seconds",
+                MAX_COUNT,
+                MAX_WINDOW.as_secs()
+            )),
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "{} times every {} seconds",
+            self.count,
+            self.window.as_secs()
+        )
+    }
+
+    fn interval(&self) -> Duration {
+        self.window / self.count
+    }
+}
+
+/// Token bucket kept as the time it will be full again,
+/// the generic cell rate algorithm
+#[derive(Clone, Copy, Default)]
+struct Bucket {
+    /// `None` until the first action
+    full_at: Option<Instant>,
+    /// Whether the last denied action was already told about
+    warned: bool,
+}
+
+impl Bucket {
+    /// Where `full_at` moves to if an action is taken at `now`, or how long until one can be
+    fn take(&self, limit: &Limit, now: Instant) -> Result<Instant, Duration> {
+        let start = self.full_at.map_or(now, |full_at| full_at.max(now));
+        let next = start + limit.interval();
+        let burst = limit.interval() * limit.count;
+
+        match next.checked_duration_since(now) {
+            Some(ahead) if ahead > burst => Err(ahead - burst),
+            _ => Ok(next),
+        }
+    }
+}
+
+/// A display action that has to wait
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limited {
+    pub retry_in: Duration,
+    /// The panel as a whole is busy, rather than this user
+    pub global: bool,
+    /// Set on the first denied action in a row, so the cooldown message is sent once
+    pub first: bool,
+}
+
+/// Limits how often users, one by one and all together, change the panel.
+///
+/// Time is passed in rather than read, so any clock will do
+pub struct RateLimiter {
+    pub user_limit: Option<Limit>,
+    pub global_limit: Option<Limit>,
+    users: HashMap<i64, Bucket>,
+    global: Bucket,
+}
+
+impl RateLimiter {
+    pub fn new(user_limit: Option<Limit>, global_limit: Option<Limit>) -> RateLimiter {
+        RateLimiter {
+            user_limit,
+            global_limit,
+            users: HashMap::new(),
+            global: Bucket::default(),
+        }
+    }
+
+    pub fn load() -> RateLimiter {
+        let mut user_limit = Some(DEFAULT_USER_LIMIT);
+        let mut global_limit = Some(DEFAULT_GLOBAL_LIMIT);
+
+        let saved = fs::read_to_string(storage::path(LIMITS_FILE)).unwrap_or_default();
+        for line in saved.lines() {
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            let limit = match value {
+                "off" => None,
+                value => match Limit::parse(value) {
+                    Ok(limit) => Some(limit),
+                    Err(_) => continue,
+                },
+            };
+
+            match key {
+                "user" => user_limit = limit,
+                "global" => global_limit = limit,
+                _ => {}
+            }
+        }
+
+        RateLimiter::new(user_limit, global_limit)
+    }
+
+    pub fn save(&self) {
+        let describe = |limit: Option<Limit>| match limit {
+            Some(limit) => format!("{} {}", limit.count, limit.window.as_secs()),
+            None => "off".to_string(),
+        };
+
+        let saved = format!(
+            "user {}\nglobal {}\n",
+            describe(self.user_limit),
+            describe(self.global_limit)
+        );
+
+        if let Err(err) = fs::write(storage::path(LIMITS_FILE), saved) {
+            warn!("Could not save the rate limits: {:?}", err);
+        }
+    }
+
+    /// Takes an action for `user_id` at `now` if both limits allow it,
+    /// neither bucket is drained otherwise
+    pub fn check(&mut self, user_id: i64, now: Instant) -> Result<(), Limited> {
+        if self.users.len() > MAX_TRACKED {
+            self.users
+                .retain(|_, bucket| bucket.full_at.is_some_and(|full_at| full_at > now));
+        }
+
+        let user = self.users.get(&user_id).copied().unwrap_or_default();
+
+        let user_next = match &self.user_limit {
+            Some(limit) => match user.take(limit, now) {
+                Ok(next) => Some(next),
+                Err(retry_in) => {
+                    self.users.insert(
+                        user_id,
+                        Bucket {
+                            warned: true,
+                            ..user
+                        },
+                    );
+                    return Err(Limited {
+                        retry_in,
+                        global: false,
+                        first: !user.warned,
+                    });
+                }
+            },
+            None => None,
+        };
+
+        let global_next = match &self.global_limit {
+            Some(limit) => match self.global.take(limit, now) {
+                Ok(next) => Some(next),
+                Err(retry_in) => {
+                    self.users.insert(
+                        user_id,
+                        Bucket {
+                            warned: true,
+                            ..user
+                        },
+                    );
+                    return Err(Limited {
+                        retry_in,
+                        global: true,
+                        first: !user.warned,
+                    });
+                }
+            },
+            None => None,
+        };
+
+        self.users.insert(
+            user_id,
+            Bucket {
+                full_at: user_next,
+                warned: false,
+            },
+        );
+        self.global = Bucket {
+            full_at: global_next,
+            warned: false,
+        };
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn limit(count: u32, seconds: u64) -> Option<Limit> {
+        Some(Limit {
+            count,
+            window: Duration::from_secs(seconds),
+        })
+    }
+
+    #[test]
+    fn allows_a_burst_then_denies() {
+        let mut limiter = RateLimiter::new(limit(5, 30), None);
+        let start = Instant::now();
+
+        for _ in 0..5 {
+            assert_eq!(limiter.check(1, start), Ok(()));
+        }
+
+        assert_eq!(
+            limiter.check(1, start),
+            Err(Limited {
+                retry_in: 6 * SECOND,
+                global: false,
+                first: true,
+            })
+        );
+    }
+
+    #[test]
+    fn retry_in_counts_down_and_only_the_first_denial_warns() {
+        let mut limiter = RateLimiter::new(limit(2, 10), None);
+        let start = Instant::now();
+
+        limiter.check(1, start).unwrap();
+        limiter.check(1, start).unwrap();
+
+        let first = limiter.check(1, start).unwrap_err();
+        assert_eq!(first.retry_in, 5 * SECOND);
+        assert!(first.first);
+
+        let second = limiter.check(1, start + 2 * SECOND).unwrap_err();
+        assert_eq!(second.retry_in, 3 * SECOND);
+        assert!(!second.first);
+    }
+
+    #[test]
+    fn recovers_after_waiting() {
+        let mut limiter = RateLimiter::new(limit(2, 10), None);
+        let start = Instant::now();
+
+        limiter.check(1, start).unwrap();
+        limiter.check(1, start).unwrap();
+        let retry_in = limiter.check(1, start).unwrap_err().retry_in;
+
+        assert_eq!(limiter.check(1, start + retry_in), Ok(()));
+        // the next denial is told about again
+        assert!(limiter.check(1, start + retry_in).unwrap_err().first);
+
+        // a whole window later the burst is back
+        let later = start + 20 * SECOND;
+        assert_eq!(limiter.check(1, later), Ok(()));
+        assert_eq!(limiter.check(1, later), Ok(()));
+        assert!(limiter.check(1, later).is_err());
+    }
+
+    #[test]
+    fn users_are_limited_separately() {
+        let mut limiter = RateLimiter::new(limit(1, 10), None);
+        let start = Instant::now();
+
+        assert_eq!(limiter.check(1, start), Ok(()));
+        assert!(limiter.check(1, start).is_err());
+        assert_eq!(limiter.check(2, start), Ok(()));
+    }
+
+    #[test]
+    fn global_limit_covers_everyone() {
+        let mut limiter = RateLimiter::new(limit(5, 30), limit(2, 10));
+        let start = Instant::now();
+
+        assert_eq!(limiter.check(1, start), Ok(()));
+        assert_eq!(limiter.check(2, start), Ok(()));
+
+        let limited = limiter.check(3, start).unwrap_err();
+        assert!(limited.global);
+        assert_eq!(limited.retry_in, 5 * SECOND);
+
+        assert_eq!(limiter.check(3, start + limited.retry_in), Ok(()));
+    }
+
+    #[test]
+    fn a_global_denial_doesnt_drain_the_user() {
+        let mut limiter = RateLimiter::new(limit(2, 10), limit(1, 10));
+        let start = Instant::now();
+
+        limiter.check(1, start).unwrap();
+        assert!(limiter.check(2, start).unwrap_err().global);
+
+        // user 2 still has their whole burst once the panel is free
+        let free = start + 10 * SECOND;
+        assert_eq!(limiter.check(2, free), Ok(()));
+        assert!(limiter.check(2, free).unwrap_err().global);
+    }
+
+    #[test]
+    fn no_limits_allow_anything() {
+        let mut limiter = RateLimiter::new(None, None);
+        let start = Instant::now();
+
+        for _ in 0..1000 {
+            assert_eq!(limiter.check(1, start), Ok(()));
+        }
+    }
+
+    #[test]
+    fn forgets_users_with_a_full_bucket() {
+        let mut limiter = RateLimiter::new(limit(1, 10), None);
+        let start = Instant::now();
+
+        for user in 0..=MAX_TRACKED as i64 {
+            limiter.check(user, start).unwrap();
+        }
+
+        limiter.check(-1, start + 10 * SECOND).unwrap();
+        assert_eq!(limiter.users.len(), 1);
+    }
+
+    #[test]
+    fn parses_limits() {
+        assert_eq!(Limit::parse("5 30"), Ok(limit(5, 30).unwrap()));
+        assert_eq!(Limit::parse(" 1   1 "), Ok(limit(1, 1).unwrap()));
+
+        assert!(Limit::parse("5").is_err());
+        assert!(Limit::parse("5 30 1").is_err());
+        assert!(Limit::parse("0 30").is_err());
+        assert!(Limit::parse("5 0").is_err());
+        assert!(Limit::parse("101 30").is_err());
+        assert!(Limit::parse("5 3601").is_err());
+        assert!(Limit::parse("five 30").is_err());
+    }
+}
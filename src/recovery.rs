@@ -0,0 +1,90 @@
+use std::time::Duration;
+
+/// Wait after the first failed poll, doubled after each failure in a row
+const FIRST_DELAY: Duration = Duration::from_secs(1);
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Polls that can fail in a row before restarting, about five minutes of waiting
+pub const MAX_POLL_FAILURES: u32 = 10;
+
+/// Updates that can fail in a row before restarting,
+/// one bad file shouldn't be enough but a broken state should
+pub const MAX_UPDATE_FAILURES: u32 = 5;
+
+/// Counts failures in a row, so the firmware only restarts when they keep happening
+pub struct Failures {
+    count: u32,
+    limit: u32,
+}
+
+impl Failures {
+    pub const fn new(limit: u32) -> Failures {
+        Failures { count: 0, limit }
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    pub fn succeeded(&mut self) {
+        self.count = 0;
+    }
+
+    /// Counts a failure, true once there were `limit` of them in a row
+    pub fn failed(&mut self) -> bool {
+        self.count += 1;
+        self.count >= self.limit
+    }
+
+    /// How long to wait before trying again, doubling with each failure in a row
+    pub fn backoff(&self) -> Duration {
+        let doublings = self.count.saturating_sub(1).min(16);
+
+        (FIRST_DELAY * (1 << doublings)).min(MAX_DELAY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restarts_after_the_limit_in_a_row() {
+        let mut failures = Failures::new(3);
+
+        assert!(!failures.failed());
+        assert!(!failures.failed());
+        failures.succeeded();
+
+        assert!(!failures.failed());
+        assert!(!failures.failed());
+        assert!(failures.failed());
+        assert_eq!(failures.count(), 3);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_a_minute() {
+        let mut failures = Failures::new(MAX_POLL_FAILURES);
+        let mut delays = Vec::new();
+        for _ in 0..MAX_POLL_FAILURES {
+            failures.failed();
+            delays.push(failures.backoff().as_secs());
+        }
+
+        assert_eq!(delays, [1, 2, 4, 8, 16, 32, 60, 60, 60, 60]);
+
+        failures.succeeded();
+        failures.failed();
+        assert_eq!(failures.backoff(), FIRST_DELAY);
+    }
+
+    #[test]
+    fn backoff_stays_capped_after_many_failures() {
+        let mut failures = Failures::new(u32::MAX);
+        for _ in 0..1000 {
+            failures.failed();
+        }
+
+        assert_eq!(failures.backoff(), MAX_DELAY);
+    }
+}
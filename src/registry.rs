@@ -0,0 +1,560 @@
+use std::str::FromStr;
+
+use thiserror::Error;
+
+use crate::roles::Role;
+
+const HELP_INTRO: &str =
+    "Hello! Send a sticker, photo, GIF or custom emoji to display it on the screen
+
+Add fit, crop, stretch or pixel to the caption of a photo to choose how it is scaled
+Add filters like invert or hue=90 to the caption to change how it looks";
+
+/// What a command takes after its name. `check` looks at it before the
+/// handler runs, and answers anything that doesn't fit with the usage
+#[derive(Clone, Copy)]
+pub enum Arguments {
+    /// No arguments, anything after the name is ignored
+    Nothing,
+    /// Text that can't be empty
+    Text,
+    /// Anything or nothing, the handler makes sense of it
+    Any,
+    /// Nothing, or one of these words followed by whatever it takes
+    Words(&'static [&'static str]),
+}
+
+impl Arguments {
+    fn parse(self, argument: &str) -> Result<Args<'_>, Usage> {
+        let argument = argument.trim();
+
+        match self {
+            Arguments::Nothing => Ok(Args {
+                word: None,
+                rest: "",
+            }),
+            Arguments::Text if argument.is_empty() => Err(Usage),
+            Arguments::Text | Arguments::Any => Ok(Args {
+                word: None,
+                rest: argument,
+            }),
+            Arguments::Words(words) => {
+                let (first, rest) = argument
+                    .split_once(char::is_whitespace)
+                    .unwrap_or((argument, ""));
+                if first.is_empty() {
+                    return Ok(Args {
+                        word: None,
+                        rest: "",
+                    });
+                }
+
+                let word = words
+                    .iter()
+                    .copied()
+                    .find(|word| *word == first)
+                    .ok_or(Usage)?;
+                Ok(Args {
+                    word: Some(word),
+                    rest: rest.trim(),
+                })
+            }
+        }
+    }
+}
+
+/// The text after a command, split the way its `Arguments` say
+#[derive(Debug, PartialEq)]
+pub struct Args<'a> {
+    /// Which of `Arguments::Words` was given, if any
+    pub word: Option<&'static str>,
+    /// Everything else, trimmed
+    pub rest: &'a str,
+}
+
+impl Args<'_> {
+    /// `rest` as a number
+    pub fn number<T: FromStr>(&self) -> Result<T, Usage> {
+        self.rest.parse().map_err(|_| Usage)
+    }
+}
+
+/// Arguments that don't fit the command, answered with its usage
+#[derive(Debug, Error)]
+#[error("these arguments don't fit the command")]
+pub struct Usage;
+
+/// Which handler runs a command
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Help,
+    Scale,
+    Background,
+    Text,
+    Qr,
+    Marquee,
+    Clock,
+    Timezone,
+    Filter,
+    Transition,
+    Playlist,
+    Panel,
+    Screenshot,
+    BootImage,
+    Allow,
+    Ban,
+    Admins,
+    Moderation,
+    Group,
+    Groups,
+    RateLimit,
+}
+
+pub struct Command {
+    /// Without the slash
+    pub name: &'static str,
+    /// What the arguments look like, shown after the name in /help
+    pub usage: &'static str,
+    pub description: &'static str,
+    /// The least trusted role that can run it
+    pub permission: Role,
+    /// Changes the panel, and counts against the rate limits
+    pub displays: bool,
+    pub kind: Kind,
+    arguments: Arguments,
+}
+
+impl Command {
+    /// What a wrong argument gets answered with
+    pub fn usage_reply(&self) -> String {
+        format!("Use /{} {}", self.name, self.usage)
+    }
+}
+
+pub static COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        usage: "",
+        description: "what this bot can do",
+        permission: Role::Guest,
+        displays: false,
+        kind: Kind::Help,
+        arguments: Arguments::Nothing,
+    },
+    Command {
+        name: "scale",
+        usage: "fit | crop | stretch | pixel",
+        description: "change the default scaling",
+        permission: Role::Admin,
+        displays: false,
+        kind: Kind::Scale,
+        arguments: Arguments::Any,
+    },
+    Command {
+        name: "background",
+        usage: "#rrggbb [#rrggbb] | previous",
+        description: "what shows through transparent areas",
+        permission: Role::Admin,
+        displays: false,
+        kind: Kind::Background,
+        arguments: Arguments::Text,
+    },
+    Command {
+        name: "text",
+        usage: "[color=#rrggbb] [align=left|center|right] [size=tiny|small|medium|large|huge] <message>",
+        description: "show some text",
+        permission: Role::User,
+        displays: true,
+        kind: Kind::Text,
+        arguments: Arguments::Text,
+    },
+    Command {
+        name: "qr",
+        usage: "<text> | wifi <network> <password>",
+        description: "show a QR code, /qr wifi <network> <password> to share a Wi-Fi network",
+        permission: Role::User,
+        displays: true,
+        kind: Kind::Qr,
+        arguments: Arguments::Text,
+    },
+    Command {
+        name: "marquee",
+        usage: "[speed=N] [color=#rrggbb] [size=tiny|small|medium|large|huge] <message>",
+        description: "scroll text across the screen, reply to an image to scroll over it",
+        permission: Role::User,
+        displays: true,
+        kind: Kind::Marquee,
+        arguments: Arguments::Text,
+    },
+    Command {
+        name: "clock",
+        usage: "[digital | analog | overlay]",
+        description: "show the time, overlay draws it over the current image",
+        permission: Role::User,
+        displays: true,
+        kind: Kind::Clock,
+        arguments: Arguments::Words(&["digital", "analog", "overlay"]),
+    },
+    Command {
+        name: "timezone",
+        usage: "<+2 | -5:30 | POSIX TZ>",
+        description: "set the timezone of the clock",
+        permission: Role::Admin,
+        displays: false,
+        kind: Kind::Timezone,
+        arguments: Arguments::Any,
+    },
+    Command {
+        name: "filter",
+        usage: "<filters> | off",
+        description: "filters for every image: invert, brightness=N, contrast=N, hue=N, saturation=N, posterize[=N], pixelate[=N], edge, sepia",
+        permission: Role::Admin,
+        displays: false,
+        kind: Kind::Filter,
+        arguments: Arguments::Any,
+    },
+    Command {
+        name: "transition",
+        usage: "cut | crossfade | wipe | slide | dissolve | pixelate [milliseconds]",
+        description: "how images change",
+        permission: Role::Admin,
+        displays: false,
+        kind: Kind::Transition,
+        arguments: Arguments::Any,
+    },
+    Command {
+        name: "playlist",
+        usage: "add | remove <n> | list | clear | interval <seconds> | auto <n | off> | play | stop",
+        description: "rotate through saved images",
+        permission: Role::Admin,
+        displays: false,
+        kind: Kind::Playlist,
+        arguments: Arguments::Words(&[
+            "add", "remove", "list", "clear", "interval", "auto", "play", "stop",
+        ]),
+    },
+    Command {
+        name: "panel",
+        usage: "",
+        description: "buttons for brightness, the playlist, pausing, the clock and turning the panel off",
+        permission: Role::User,
+        displays: false,
+        kind: Kind::Panel,
+        arguments: Arguments::Nothing,
+    },
+    Command {
+        name: "screenshot",
+        usage: "[led]",
+        description: "get a picture of what the panel is showing, led makes it look like the real thing",
+        permission: Role::User,
+        displays: false,
+        kind: Kind::Screenshot,
+        arguments: Arguments::Words(&["led"]),
+    },
+    Command {
+        name: "bootimage",
+        usage: "last | default | current | pinned",
+        description: "what the panel shows after a restart",
+        permission: Role::Admin,
+        displays: false,
+        kind: Kind::BootImage,
+        arguments: Arguments::Words(&["last", "default", "current", "pinned"]),
+    },
+    Command {
+        name: "allow",
+        usage: "<user id> | remove <user id> | listed | everyone",
+        description: "let a user in, listed lets in only allowed users. Reply to one of their messages instead of the id",
+        permission: Role::Admin,
+        displays: false,
+        kind: Kind::Allow,
+        arguments: Arguments::Any,
+    },
+    Command {
+        name: "ban",
+        usage: "<user id>",
+        description: "ignore everything from a user, reply to one of their messages instead of the id",
+        permission: Role::Admin,
+        displays: false,
+        kind: Kind::Ban,
+        arguments: Arguments::Any,
+    },
+    Command {
+        name: "admins",
+        usage: "add <user id> | remove <user id>",
+        description: "who can change settings and allow or ban users",
+        permission: Role::Owner,
+        displays: false,
+        kind: Kind::Admins,
+        arguments: Arguments::Words(&["add", "remove"]),
+    },
+    Command {
+        name: "moderation",
+        usage: "on | off",
+        description: "send images from users to the owner for approval before they are shown",
+        permission: Role::Admin,
+        displays: false,
+        kind: Kind::Moderation,
+        arguments: Arguments::Words(&["on", "off"]),
+    },
+    Command {
+        name: "group",
+        usage: "on | off",
+        description: "let images from this group on the panel or keep them off, for group admins",
+        permission: Role::User,
+        displays: false,
+        kind: Kind::Group,
+        arguments: Arguments::Words(&["on", "off"]),
+    },
+    Command {
+        name: "groups",
+        usage: "all | mention | listed",
+        description: "which images from groups are shown: all of them, the ones that mention or reply to the bot, or all in groups turned on with /group on",
+        permission: Role::Admin,
+        displays: false,
+        kind: Kind::Groups,
+        arguments: Arguments::Any,
+    },
+    Command {
+        name: "ratelimit",
+        usage: "<count> <seconds> | global <count> <seconds> | off",
+        description: "how often each user, or everyone together, can change the panel",
+        permission: Role::Admin,
+        displays: false,
+        kind: Kind::RateLimit,
+        arguments: Arguments::Any,
+    },
+];
+
+/// The command name and the text after it, `None` when `text` isn't a command
+/// or is one meant for another bot, like `/help@other_bot` in a group
+pub fn parse<'a>(text: &'a str, username: &str) -> Option<(&'a str, &'a str)> {
+    let text = text.strip_prefix('/')?;
+    let (word, argument) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+
+    let name = match word.split_once('@') {
+        Some((name, mention)) if mention.eq_ignore_ascii_case(username) => name,
+        Some(_) => return None,
+        None => word,
+    };
+
+    Some((name, argument))
+}
+
+pub fn find(name: &str) -> Option<&'static Command> {
+    // /start is what Telegram sends when a chat with the bot is opened
+    let name = if name == "start" { "help" } else { name };
+
+    COMMANDS
+        .iter()
+        .find(|command| command.name.eq_ignore_ascii_case(name))
+}
+
+/// What to do with a message, decided before any handler runs
+pub enum Checked<'a> {
+    /// Not a command, one for another bot or from a banned user
+    Ignored,
+    /// From someone not on the allowlist
+    NotAllowed,
+    /// Not allowed or not used right, the reply says why
+    Refused(String),
+    Run(&'static Command, Args<'a>),
+}
+
+/// Finds the command in `text` and checks that `role` can run it with
+/// the arguments it was given
+pub fn check<'a>(text: &'a str, username: &str, role: Role) -> Checked<'a> {
+    let Some((name, argument)) = parse(text, username) else {
+        return Checked::Ignored;
+    };
+
+    let Some(command) = find(name) else {
+        return Checked::Ignored;
+    };
+
+    match role {
+        role if role >= command.permission => {}
+        Role::Banned => return Checked::Ignored,
+        Role::Guest => return Checked::NotAllowed,
+        _ => {
+            let who = match command.permission {
+                Role::Owner => "the owner",
+                _ => "admins",
+            };
+            return Checked::Refused(format!("Only {} can use /{}", who, command.name));
+        }
+    }
+
+    // checked before the rate limits, so a typo doesn't count against them
+    match command.arguments.parse(argument) {
+        Ok(args) => Checked::Run(command, args),
+        Err(Usage) => Checked::Refused(command.usage_reply()),
+    }
+}
+
+/// The /help text, with only the commands `role` can run
+pub fn help_text(role: Role) -> String {
+    let mut help = HELP_INTRO.to_string();
+
+    for command in COMMANDS
+        .iter()
+        .filter(|command| command.name != "help" && command.permission <= role)
+    {
+        help += &format!("\n/{}", command.name);
+        if !command.usage.is_empty() {
+            help += &format!(" {}", command.usage);
+        }
+        help += &format!(" - {}", command.description);
+    }
+
+    help
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run<'a>(text: &'a str, role: Role) -> (Kind, Args<'a>) {
+        match check(text, "hub75bot", role) {
+            Checked::Run(command, args) => (command.kind, args),
+            _ => panic!("{text} didn't run"),
+        }
+    }
+
+    fn refused(text: &str, role: Role) -> String {
+        match check(text, "hub75bot", role) {
+            Checked::Refused(reply) => reply,
+            _ => panic!("{text} wasn't refused"),
+        }
+    }
+
+    #[test]
+    fn commands_for_other_bots_are_ignored() {
+        assert_eq!(parse("/scale@OtherBot fit", "hub75bot"), None);
+        assert!(matches!(
+            check("/scale@OtherBot fit", "hub75bot", Role::Owner),
+            Checked::Ignored
+        ));
+
+        assert_eq!(
+            parse("/scale@Hub75Bot fit", "hub75bot"),
+            Some(("scale", "fit"))
+        );
+        assert_eq!(parse("/scale fit", "hub75bot"), Some(("scale", "fit")));
+        assert_eq!(parse("scale fit", "hub75bot"), None);
+    }
+
+    #[test]
+    fn unknown_commands_are_ignored() {
+        assert!(matches!(
+            check("/nope", "hub75bot", Role::Owner),
+            Checked::Ignored
+        ));
+        assert!(matches!(
+            check("hello", "hub75bot", Role::Owner),
+            Checked::Ignored
+        ));
+    }
+
+    #[test]
+    fn start_is_help() {
+        assert_eq!(find("start").unwrap().kind, Kind::Help);
+        assert_eq!(run("/start", Role::Guest).0, Kind::Help);
+    }
+
+    #[test]
+    fn wrong_arguments_get_the_usage() {
+        let usage = "Use /text [color=#rrggbb] [align=left|center|right] [size=tiny|small|medium|large|huge] <message>";
+        assert_eq!(refused("/text", Role::User), usage);
+        assert_eq!(refused("/text   ", Role::User), usage);
+
+        // a word that isn't one of the choices
+        assert_eq!(
+            refused("/clock sundial", Role::User),
+            "Use /clock [digital | analog | overlay]"
+        );
+
+        assert_eq!(
+            Args {
+                word: None,
+                rest: "x"
+            }
+            .number::<u32>()
+            .ok(),
+            None
+        );
+    }
+
+    #[test]
+    fn arguments_are_split() {
+        let (kind, args) = run("/playlist remove  3 ", Role::Admin);
+        assert_eq!(kind, Kind::Playlist);
+        assert_eq!(
+            args,
+            Args {
+                word: Some("remove"),
+                rest: "3"
+            }
+        );
+        assert_eq!(args.number::<usize>().unwrap(), 3);
+
+        // words are optional
+        assert_eq!(
+            run("/clock", Role::User).1,
+            Args {
+                word: None,
+                rest: ""
+            }
+        );
+
+        // and commands without arguments ignore extra ones
+        assert_eq!(
+            run("/panel now please", Role::User).1,
+            Args {
+                word: None,
+                rest: ""
+            }
+        );
+    }
+
+    #[test]
+    fn permissions() {
+        assert_eq!(
+            refused("/scale fit", Role::User),
+            "Only admins can use /scale"
+        );
+        assert_eq!(
+            refused("/admins add 1", Role::Admin),
+            "Only the owner can use /admins"
+        );
+        assert!(matches!(
+            check("/text hi", "hub75bot", Role::Guest),
+            Checked::NotAllowed
+        ));
+        assert!(matches!(
+            check("/help", "hub75bot", Role::Banned),
+            Checked::Ignored
+        ));
+
+        // refused before the arguments are looked at
+        assert_eq!(refused("/scale", Role::User), "Only admins can use /scale");
+    }
+
+    #[test]
+    fn help_lists_what_the_role_can_run() {
+        let user = help_text(Role::User);
+        assert!(user.contains("\n/text "));
+        assert!(user.contains("\n/panel - "));
+        assert!(!user.contains("/scale"));
+        assert!(!user.contains("/ban"));
+        assert!(!user.contains("/admins"));
+        assert!(!user.contains("\n/help"));
+
+        let admin = help_text(Role::Admin);
+        assert!(
+            admin.contains("\n/scale fit | crop | stretch | pixel - change the default scaling")
+        );
+        assert!(!admin.contains("/admins"));
+
+        assert!(help_text(Role::Owner).contains("\n/admins "));
+    }
+}
@@ -0,0 +1,353 @@
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use frankenstein::Message;
+#[cfg(target_os = "espidf")]
+use log::warn;
+
+#[cfg(target_os = "espidf")]
+const NAMESPACE: &str = "roles";
+
+/// A line for the mode and one per user, with the name they had when listed:
+///
+/// ```text
+/// allowlist 1
+/// admin 123456789 Alice
+/// banned 987654321 spammer
+/// ```
+#[cfg(target_os = "espidf")]
+const KEY: &str = "list";
+
+/// NVS strings top out at 4000 bytes, with names cut to `MAX_NAME`
+/// bytes this many users always fit
+pub const MAX_USERS: usize = 64;
+const MAX_NAME: usize = 24;
+#[cfg(target_os = "espidf")]
+const BUFFER_SIZE: usize = 4000;
+
+/// From least to most trusted, comparing roles checks permissions
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    /// Ignored altogether
+    Banned,
+    /// Not listed while the allowlist is on, only told how to get in
+    Guest,
+    User,
+    /// Can change settings and allow or ban users
+    Admin,
+    /// The one in the config, can name admins
+    Owner,
+}
+
+impl Role {
+    pub fn name(self) -> &'static str {
+        match self {
+            Role::Banned => "banned",
+            Role::Guest => "guest",
+            Role::User => "user",
+            Role::Admin => "admin",
+            Role::Owner => "owner",
+        }
+    }
+
+    fn parse(word: &str) -> Option<Role> {
+        match word {
+            "banned" => Some(Role::Banned),
+            "user" => Some(Role::User),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+}
+
+struct Entry {
+    id: i64,
+    role: Role,
+    name: String,
+}
+
+/// Who can do what, saved in NVS so a restart doesn't let banned users back in
+pub struct Roles {
+    owner_id: i64,
+    entries: Vec<Entry>,
+    /// Only listed users can use the bot
+    allowlist: bool,
+    #[cfg(target_os = "espidf")]
+    nvs: Option<EspNvs<NvsDefault>>,
+}
+
+impl Roles {
+    /// Without `partition` roles still work, they are just forgotten at restart
+    #[cfg(target_os = "espidf")]
+    pub fn load(owner_id: i64, partition: Option<EspDefaultNvsPartition>) -> Roles {
+        let nvs = partition.and_then(|partition| {
+            EspNvs::new(partition, NAMESPACE, true)
+                .inspect_err(|err| warn!("Could not open the roles in NVS: {:?}", err))
+                .ok()
+        });
+
+        let mut buffer = vec![0u8; BUFFER_SIZE];
+        let saved = match &nvs {
+            Some(nvs) => nvs
+                .get_str(KEY, &mut buffer)
+                .ok()
+                .flatten()
+                .unwrap_or_default(),
+            None => "",
+        };
+
+        let mut roles = Roles::parse(owner_id, saved);
+        roles.nvs = nvs;
+        roles
+    }
+
+    /// Roles from what `setting` wrote, kept only in memory
+    pub fn parse(owner_id: i64, saved: &str) -> Roles {
+        let mut roles = Roles {
+            owner_id,
+            entries: Vec::new(),
+            allowlist: false,
+            #[cfg(target_os = "espidf")]
+            nvs: None,
+        };
+
+        for line in saved.lines() {
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+
+            match key {
+                "allowlist" => roles.allowlist = value == "1",
+                role => {
+                    let Some(role) = Role::parse(role) else {
+                        continue;
+                    };
+                    let (id, name) = value.split_once(' ').unwrap_or((value, ""));
+                    let Ok(id) = id.parse() else { continue };
+
+                    roles.entries.push(Entry {
+                        id,
+                        role,
+                        name: name.to_string(),
+                    });
+                }
+            }
+        }
+
+        roles
+    }
+
+    pub fn role(&self, user_id: i64) -> Role {
+        if user_id == self.owner_id {
+            return Role::Owner;
+        }
+
+        match self.entries.iter().find(|entry| entry.id == user_id) {
+            Some(entry) => entry.role,
+            None if self.allowlist => Role::Guest,
+            None => Role::User,
+        }
+    }
+
+    /// Role of whoever sent `message`, messages sent on behalf of a chat have none
+    pub fn sender_role(&self, message: &Message) -> Role {
+        match &message.from {
+            Some(user) => self.role(user.id as i64),
+            None => Role::Guest,
+        }
+    }
+
+    pub fn allowlist(&self) -> bool {
+        self.allowlist
+    }
+
+    pub fn set_allowlist(&mut self, allowlist: bool) {
+        self.allowlist = allowlist;
+        self.save();
+    }
+
+    /// Lists `user_id` with `role`, `User` also lifts a ban.
+    /// Returns the reason if the list is full
+    pub fn set(&mut self, user_id: i64, role: Role, name: &str) -> Result<(), String> {
+        // the list is line based
+        let mut name = name.replace(['\n', '\r'], " ");
+        if name.len() > MAX_NAME {
+            let mut end = MAX_NAME;
+            while !name.is_char_boundary(end) {
+                end -= 1;
+            }
+            name.truncate(end);
+        }
+
+        let full = self.entries.len() >= MAX_USERS;
+        match self.entries.iter_mut().find(|entry| entry.id == user_id) {
+            Some(entry) => {
+                entry.role = role;
+                entry.name = name;
+            }
+            None if full => {
+                return Err(format!(
+                    "The list is full, it holds {MAX_USERS} users. Remove some with /allow remove first"
+                ));
+            }
+            None => self.entries.push(Entry {
+                id: user_id,
+                role,
+                name,
+            }),
+        }
+
+        self.save();
+        Ok(())
+    }
+
+    /// Back to a guest, or a user with the allowlist off
+    pub fn remove(&mut self, user_id: i64) -> bool {
+        let before = self.entries.len();
+        self.entries.retain(|entry| entry.id != user_id);
+
+        let removed = self.entries.len() != before;
+        if removed {
+            self.save();
+        }
+        removed
+    }
+
+    /// Ids and names of the users listed with `role`
+    pub fn users(&self, role: Role) -> impl Iterator<Item = (i64, &str)> {
+        self.entries
+            .iter()
+            .filter(move |entry| entry.role == role)
+            .map(|entry| (entry.id, entry.name.as_str()))
+    }
+
+    /// The way `parse` takes it back
+    #[cfg(any(target_os = "espidf", test))]
+    fn setting(&self) -> String {
+        let mut saved = format!("allowlist {}\n", self.allowlist as u8);
+        for entry in &self.entries {
+            saved += &format!("{} {} {}\n", entry.role.name(), entry.id, entry.name);
+        }
+        saved
+    }
+
+    fn save(&mut self) {
+        #[cfg(target_os = "espidf")]
+        if let Some(nvs) = &mut self.nvs {
+            if let Err(err) = nvs.set_str(KEY, &self.setting()) {
+                warn!("Could not save the roles: {:?}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OWNER: i64 = 1;
+
+    #[test]
+    fn everyone_is_a_user_without_the_allowlist() {
+        let roles = Roles::parse(OWNER, "");
+        assert!(!roles.allowlist());
+        assert_eq!(roles.role(42), Role::User);
+        assert_eq!(roles.role(OWNER), Role::Owner);
+    }
+
+    #[test]
+    fn the_allowlist_makes_strangers_guests() {
+        let mut roles = Roles::parse(OWNER, "");
+        roles.set_allowlist(true);
+        roles.set(42, Role::User, "Alice").unwrap();
+        roles.set(43, Role::Admin, "Bob").unwrap();
+
+        assert_eq!(roles.role(42), Role::User);
+        assert_eq!(roles.role(43), Role::Admin);
+        assert_eq!(roles.role(44), Role::Guest);
+
+        assert!(roles.remove(42));
+        assert!(!roles.remove(42));
+        assert_eq!(roles.role(42), Role::Guest);
+    }
+
+    #[test]
+    fn banned_beats_the_allowlist() {
+        let mut roles = Roles::parse(OWNER, "");
+        roles.set(42, Role::Banned, "spammer").unwrap();
+        assert_eq!(roles.role(42), Role::Banned);
+
+        roles.set_allowlist(true);
+        assert_eq!(roles.role(42), Role::Banned);
+
+        // allowing lifts the ban
+        roles.set(42, Role::User, "spammer").unwrap();
+        assert_eq!(roles.role(42), Role::User);
+    }
+
+    #[test]
+    fn the_owner_is_always_the_owner() {
+        let mut roles = Roles::parse(OWNER, "allowlist 1\nbanned 1 me\n");
+        assert_eq!(roles.role(OWNER), Role::Owner);
+
+        roles.set(OWNER, Role::Banned, "me").unwrap();
+        assert_eq!(roles.role(OWNER), Role::Owner);
+    }
+
+    #[test]
+    fn saved_lines() {
+        let saved = "allowlist 1\nadmin 123456789 Alice\nbanned 987654321 spammer\n";
+        let roles = Roles::parse(OWNER, saved);
+
+        assert!(roles.allowlist());
+        assert_eq!(roles.role(123456789), Role::Admin);
+        assert_eq!(roles.role(987654321), Role::Banned);
+        assert_eq!(
+            roles.users(Role::Admin).collect::<Vec<_>>(),
+            [(123456789, "Alice")]
+        );
+        assert_eq!(roles.setting(), saved);
+
+        // names can have spaces, lines that make no sense are skipped
+        let roles = Roles::parse(
+            OWNER,
+            "owner 5 me\nadmin x Bob\nuser 7 Mary Jane\nallowlist 0\n",
+        );
+        assert!(!roles.allowlist());
+        assert_eq!(roles.role(5), Role::User);
+        assert_eq!(
+            roles.users(Role::User).collect::<Vec<_>>(),
+            [(7, "Mary Jane")]
+        );
+        assert_eq!(roles.setting(), "allowlist 0\nuser 7 Mary Jane\n");
+    }
+
+    #[test]
+    fn names_stay_on_their_line() {
+        let mut roles = Roles::parse(OWNER, "");
+        roles.set(42, Role::User, "two\nlines").unwrap();
+        roles.set(43, Role::User, &"é".repeat(20)).unwrap();
+
+        let names: Vec<_> = roles.users(Role::User).map(|(_, name)| name).collect();
+        assert_eq!(names[0], "two lines");
+        // cut to MAX_NAME bytes without splitting a character
+        assert_eq!(names[1], "é".repeat(MAX_NAME / 2));
+
+        assert_eq!(
+            Roles::parse(OWNER, &roles.setting()).setting(),
+            roles.setting()
+        );
+    }
+
+    #[test]
+    fn the_list_is_capped() {
+        let mut roles = Roles::parse(OWNER, "");
+        for id in 0..MAX_USERS as i64 {
+            roles.set(100 + id, Role::User, "user").unwrap();
+        }
+
+        assert!(roles.set(1000, Role::Banned, "one more").is_err());
+        assert_eq!(roles.role(1000), Role::User);
+
+        // changing someone already listed still works
+        roles.set(100, Role::Admin, "user").unwrap();
+        assert_eq!(roles.role(100), Role::Admin);
+    }
+}
@@ -0,0 +1,650 @@
+use anyhow::{Context, Result};
+use frankenstein::{
+    AnswerCallbackQueryParams, CallbackQuery, ChatType, ForwardMessageParams, Message, ReplyMarkup,
+    ReplyParameters, SendMessageParams, TelegramApi,
+};
+use log::info;
+
+use crate::groups::Groups;
+use crate::media;
+use crate::moderation::{self, Decision, Moderation};
+use crate::panel::{self, Action};
+use crate::registry::{self, Args, Checked, Kind, Usage};
+use crate::roles::{Role, Roles};
+
+/// What routing needs from the bot. The firmware puts images on the panel,
+/// tests only remember what they were asked to do
+pub trait Bot {
+    type Api: TelegramApi<Error = Self::ApiError>;
+    /// Kept as the cause when a call to Telegram fails
+    type ApiError: std::error::Error + Send + Sync + 'static;
+
+    fn api(&self) -> &Self::Api;
+    fn owner_id(&self) -> i64;
+    /// Without the @, commands addressed to other bots are ignored
+    fn username(&self) -> &str;
+    fn roles(&self) -> &Roles;
+    fn groups(&self) -> &Groups;
+    fn moderation(&mut self) -> &mut Moderation;
+
+    /// Whether the sender of `message` can change the panel right now,
+    /// telling them how long to wait the first time they can't
+    fn allow_display(&mut self, message: &Message) -> bool;
+    /// Puts the sticker, photo, GIF or custom emoji in `message` on the panel
+    fn display_image(&mut self, message: &Message) -> Result<()>;
+    /// Runs the handler of a command that passed its checks, the reply is sent back to the chat
+    fn run_command(&mut self, kind: Kind, message: &Message, args: Args) -> Result<Option<String>>;
+    /// Acts on a review button, returns what to tell whoever pressed it
+    fn decide(&mut self, decision: Decision, id: u32) -> String;
+    /// Does what a `/panel` button pressed by `user_id` says, returns what to tell them
+    fn panel_action(&mut self, action: Action, user_id: i64) -> String;
+    /// Edits the `/panel` message the button was under to show the new state
+    fn update_panel(&self, query: &CallbackQuery);
+}
+
+pub fn reply(api: &impl TelegramApi, message: &Message, text: impl Into<String>) {
+    api.send_message(
+        &SendMessageParams::builder()
+            .chat_id(message.chat.id)
+            .text(text.into())
+            .build(),
+    )
+    .ok();
+}
+
+/// What users that aren't on the allowlist get told
+pub fn reply_not_allowed(api: &impl TelegramApi, message: &Message) {
+    let id = message.from.as_ref().map_or(0, |user| user.id);
+    reply(
+        api,
+        message,
+        format!("This bot is private, ask an admin to /allow you. Your user id is {id}"),
+    );
+}
+
+pub fn handle_message(bot: &mut impl Bot, message: &Message) -> Result<()> {
+    info!(
+        "message id {} from chat {}",
+        message.message_id, message.chat.id
+    );
+
+    let role = bot.roles().sender_role(message);
+    if role == Role::Banned {
+        return Ok(());
+    }
+
+    // commands still run and the owner still gets the message when it can't be shown
+    let mut shown = Ok(());
+
+    // guests never get to show anything, /help and private chats tell them how to get in
+    let image = bot.groups().image_to_show(message, bot.username());
+    if let Some(image) = image.filter(|_| role >= Role::User) {
+        // only what is shown right away counts against the rate limits,
+        // not files the panel can't show or images waiting for review
+        match media::from_message(image) {
+            Some(Err(reason)) => reply(bot.api(), image, reason),
+            // the review replaces the plain forward to the owner
+            _ if bot.moderation().enabled && role < Role::Admin => {
+                return hold_for_review(bot, image);
+            }
+            _ if bot.allow_display(message) => shown = bot.display_image(image),
+            _ => {}
+        }
+    }
+
+    let is_command = message
+        .text
+        .as_deref()
+        .is_some_and(|text| text.starts_with('/'));
+    if role == Role::Guest && message.chat.type_field == ChatType::Private && !is_command {
+        reply_not_allowed(bot.api(), message);
+    }
+
+    if message.chat.type_field == ChatType::Private {
+        bot.api()
+            .forward_message(
+                &ForwardMessageParams::builder()
+                    .chat_id(bot.owner_id())
+                    .from_chat_id(message.chat.id)
+                    .message_id(message.message_id)
+                    .build(),
+            )
+            .ok();
+    }
+
+    dispatch(bot, message, role)?;
+
+    shown
+}
+
+/// Runs the command in `message`, if there is one. Usage and permissions are
+/// answered here, what went wrong in the handler is returned for the caller to tell
+fn dispatch(bot: &mut impl Bot, message: &Message, role: Role) -> Result<()> {
+    let Some(text) = message.text.as_deref() else {
+        return Ok(());
+    };
+
+    let (command, args) = match registry::check(text, bot.username(), role) {
+        Checked::Run(command, args) => (command, args),
+        Checked::Ignored => return Ok(()),
+        Checked::NotAllowed => {
+            reply_not_allowed(bot.api(), message);
+            return Ok(());
+        }
+        Checked::Refused(answer) => {
+            reply(bot.api(), message, answer);
+            return Ok(());
+        }
+    };
+
+    if command.displays && !bot.allow_display(message) {
+        return Ok(());
+    }
+
+    match bot.run_command(command.kind, message, args) {
+        Ok(Some(answer)) => reply(bot.api(), message, answer),
+        Ok(None) => {}
+        // a typo isn't a failure, it gets the usage like one caught before running
+        Err(err) if err.is::<Usage>() => reply(bot.api(), message, command.usage_reply()),
+        Err(err) => return Err(err),
+    }
+
+    Ok(())
+}
+
+/// Sends the image in `message` to the owner with buttons to decide on it
+fn hold_for_review(bot: &mut impl Bot, message: &Message) -> Result<()> {
+    let sender = match &message.from {
+        Some(user) => format!("{} ({})", user.first_name, user.id),
+        None => message.chat.id.to_string(),
+    };
+    let summary = format!("{} from {}", media::label(message), sender);
+
+    let id = match bot.moderation().push(message.clone(), summary.clone()) {
+        Ok(id) => id,
+        Err(reason) => {
+            reply(bot.api(), message, reason);
+            return Ok(());
+        }
+    };
+
+    let owner_id = bot.owner_id();
+    let forwarded = bot
+        .api()
+        .forward_message(
+            &ForwardMessageParams::builder()
+                .chat_id(owner_id)
+                .from_chat_id(message.chat.id)
+                .message_id(message.message_id)
+                .build(),
+        )
+        .ok()
+        .map(|response| response.result.message_id);
+
+    let mut params = SendMessageParams::builder()
+        .chat_id(owner_id)
+        .text(format!("{summary} is waiting for approval"))
+        .reply_markup(ReplyMarkup::InlineKeyboardMarkup(moderation::keyboard(id)))
+        .build();
+    params.reply_parameters =
+        forwarded.map(|message_id| ReplyParameters::builder().message_id(message_id).build());
+
+    let review = match bot.api().send_message(&params) {
+        Ok(review) => review,
+        Err(err) => {
+            bot.moderation().take(id);
+            return Err(err).context("Could not send this for approval, try again later");
+        }
+    };
+
+    bot.moderation()
+        .set_review(id, owner_id, review.result.message_id);
+    reply(bot.api(), message, "Sent to the owner for approval");
+
+    Ok(())
+}
+
+pub fn handle_callback(bot: &mut impl Bot, query: &CallbackQuery) -> Result<()> {
+    info!("callback query from {}", query.from.id);
+
+    let role = bot.roles().role(query.from.id as i64);
+    let data = query.data.as_deref().unwrap_or_default();
+
+    let answer = if let Some((decision, id)) = moderation::parse_callback(data) {
+        if role >= Role::Admin {
+            bot.decide(decision, id)
+        } else {
+            "Only admins can decide on images".to_string()
+        }
+    } else if let Some(action) = panel::parse_callback(data) {
+        if role >= Role::User {
+            let answer = bot.panel_action(action, query.from.id as i64);
+            bot.update_panel(query);
+            answer
+        } else {
+            "You can't use this panel".to_string()
+        }
+    } else {
+        "This button doesn't do anything anymore".to_string()
+    };
+
+    bot.api()
+        .answer_callback_query(
+            &AnswerCallbackQueryParams::builder()
+                .callback_query_id(query.id.clone())
+                .text(answer)
+                .build(),
+        )
+        .ok();
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::fake_api::FakeApi;
+    use crate::groups::GroupPolicy;
+
+    const OWNER: i64 = 1;
+    const ADMIN: u64 = 2;
+    const USER: u64 = 3;
+    const BANNED: u64 = 4;
+    const GUEST: u64 = 5;
+    const GROUP: i64 = -100;
+
+    /// Remembers what it was asked to do instead of doing it
+    struct FakeBot {
+        api: FakeApi,
+        roles: Roles,
+        groups: Groups,
+        moderation: Moderation,
+        /// What `allow_display` answers
+        allowed: bool,
+        turns: usize,
+        shown: Vec<i32>,
+        ran: Vec<(Kind, String)>,
+        decided: Vec<(Decision, u32)>,
+        actions: Vec<Action>,
+    }
+
+    impl FakeBot {
+        fn new(api: FakeApi) -> FakeBot {
+            let saved =
+                format!("allowlist 1\nadmin {ADMIN} Ann\nuser {USER} Ulla\nbanned {BANNED} Ben\n");
+            let mut groups = Groups::load();
+            groups.policy = GroupPolicy::All;
+            let mut moderation = Moderation::load();
+            moderation.enabled = false;
+
+            FakeBot {
+                api,
+                roles: Roles::parse(OWNER, &saved),
+                groups,
+                moderation,
+                allowed: true,
+                turns: 0,
+                shown: Vec::new(),
+                ran: Vec::new(),
+                decided: Vec::new(),
+                actions: Vec::new(),
+            }
+        }
+
+        fn methods(&self) -> Vec<String> {
+            self.api
+                .calls
+                .borrow()
+                .iter()
+                .map(|(method, _)| method.clone())
+                .collect()
+        }
+
+        fn texts(&self) -> Vec<String> {
+            self.api
+                .calls
+                .borrow()
+                .iter()
+                .filter(|(method, _)| method == "sendMessage")
+                .map(|(_, params)| params["text"].as_str().unwrap().to_string())
+                .collect()
+        }
+    }
+
+    impl Bot for FakeBot {
+        type Api = FakeApi;
+        type ApiError = serde_json::Error;
+
+        fn api(&self) -> &FakeApi {
+            &self.api
+        }
+
+        fn owner_id(&self) -> i64 {
+            OWNER
+        }
+
+        fn username(&self) -> &str {
+            "hub75bot"
+        }
+
+        fn roles(&self) -> &Roles {
+            &self.roles
+        }
+
+        fn groups(&self) -> &Groups {
+            &self.groups
+        }
+
+        fn moderation(&mut self) -> &mut Moderation {
+            &mut self.moderation
+        }
+
+        fn allow_display(&mut self, _: &Message) -> bool {
+            self.turns += 1;
+            self.allowed
+        }
+
+        fn display_image(&mut self, message: &Message) -> Result<()> {
+            self.shown.push(message.message_id);
+            Ok(())
+        }
+
+        fn run_command(&mut self, kind: Kind, _: &Message, args: Args) -> Result<Option<String>> {
+            self.ran.push((kind, args.rest.to_string()));
+
+            match args.rest {
+                "typo" => Err(Usage.into()),
+                "" => Ok(None),
+                rest => Ok(Some(format!("ran with {rest}"))),
+            }
+        }
+
+        fn decide(&mut self, decision: Decision, id: u32) -> String {
+            self.decided.push((decision, id));
+            "Approved".to_string()
+        }
+
+        fn panel_action(&mut self, action: Action, _: i64) -> String {
+            self.actions.push(action);
+            "Brightness 5 of 8".to_string()
+        }
+
+        fn update_panel(&self, _: &CallbackQuery) {}
+    }
+
+    fn message(from: u64, chat: Value, content: Value) -> Message {
+        let mut message = json!({
+            "message_id": 7,
+            "date": 0,
+            "chat": chat,
+            "from": { "id": from, "is_bot": false, "first_name": "Alice" },
+        });
+        message
+            .as_object_mut()
+            .unwrap()
+            .extend(content.as_object().unwrap().clone());
+
+        serde_json::from_value(message).unwrap()
+    }
+
+    fn private(from: u64, content: Value) -> Message {
+        message(from, json!({ "id": from, "type": "private" }), content)
+    }
+
+    fn in_group(from: u64, content: Value) -> Message {
+        message(
+            from,
+            json!({ "id": GROUP, "type": "group", "title": "friends" }),
+            content,
+        )
+    }
+
+    fn sticker() -> Value {
+        json!({
+            "sticker": {
+                "file_id": "file",
+                "file_unique_id": "unique",
+                "type": "regular",
+                "width": 512,
+                "height": 512,
+                "is_animated": false,
+                "is_video": false,
+                "emoji": "😀",
+            },
+        })
+    }
+
+    fn text(text: &str) -> Value {
+        json!({ "text": text })
+    }
+
+    fn callback(from: u64, data: &str) -> CallbackQuery {
+        serde_json::from_value(json!({
+            "id": "query",
+            "from": { "id": from, "is_bot": false, "first_name": "Alice" },
+            "chat_instance": "chat",
+            "data": data,
+        }))
+        .unwrap()
+    }
+
+    const NOT_ALLOWED: &str = "This bot is private, ask an admin to /allow you. Your user id is 5";
+
+    #[test]
+    fn banned_users_are_ignored() {
+        let mut bot = FakeBot::new(FakeApi::new());
+
+        handle_message(&mut bot, &private(BANNED, sticker())).unwrap();
+        handle_message(&mut bot, &private(BANNED, text("/help"))).unwrap();
+
+        assert!(bot.api.calls.borrow().is_empty());
+        assert!(bot.shown.is_empty());
+        assert!(bot.ran.is_empty());
+    }
+
+    #[test]
+    fn guests_are_told_how_to_get_in() {
+        let mut bot = FakeBot::new(FakeApi::new());
+
+        handle_message(&mut bot, &private(GUEST, sticker())).unwrap();
+        assert!(bot.shown.is_empty());
+        assert_eq!(bot.turns, 0);
+        assert_eq!(bot.texts(), [NOT_ALLOWED]);
+        // the owner still sees what they sent
+        assert_eq!(bot.methods(), ["sendMessage", "forwardMessage"]);
+
+        // commands they can't run get the same answer, once
+        let mut bot = FakeBot::new(FakeApi::new());
+        handle_message(&mut bot, &private(GUEST, text("/text hi"))).unwrap();
+        assert_eq!(bot.texts(), [NOT_ALLOWED]);
+        assert!(bot.ran.is_empty());
+
+        // and in groups only commands are answered
+        let mut bot = FakeBot::new(FakeApi::new());
+        handle_message(&mut bot, &in_group(GUEST, sticker())).unwrap();
+        assert!(bot.api.calls.borrow().is_empty());
+    }
+
+    #[test]
+    fn images_are_shown() {
+        let mut bot = FakeBot::new(FakeApi::new());
+
+        handle_message(&mut bot, &private(USER, sticker())).unwrap();
+        assert_eq!(bot.shown, [7]);
+        assert_eq!(bot.turns, 1);
+        assert_eq!(bot.methods(), ["forwardMessage"]);
+
+        bot.allowed = false;
+        handle_message(&mut bot, &private(USER, sticker())).unwrap();
+        assert_eq!(bot.shown, [7]);
+        assert_eq!(bot.turns, 2);
+    }
+
+    #[test]
+    fn the_group_policy_decides_what_is_shown() {
+        let mut bot = FakeBot::new(FakeApi::new());
+        bot.groups.policy = GroupPolicy::Mention;
+
+        handle_message(&mut bot, &in_group(USER, sticker())).unwrap();
+        assert!(bot.shown.is_empty());
+        assert_eq!(bot.turns, 0);
+
+        let mut replied = in_group(USER, sticker());
+        replied.message_id = 3;
+        let mut mention = in_group(USER, text("@hub75bot"));
+        mention.reply_to_message = Some(Box::new(replied));
+        handle_message(&mut bot, &mention).unwrap();
+        assert_eq!(bot.shown, [3]);
+
+        // nothing is forwarded from groups
+        assert!(bot.api.calls.borrow().is_empty());
+    }
+
+    #[test]
+    fn images_are_held_for_review() {
+        let review = json!({
+            "ok": true,
+            "result": {
+                "message_id": 99,
+                "date": 0,
+                "chat": { "id": OWNER, "type": "private" },
+                "text": "review",
+            },
+        });
+        let mut bot = FakeBot::new(FakeApi::answering(review));
+        bot.moderation.enabled = true;
+
+        handle_message(&mut bot, &private(USER, sticker())).unwrap();
+
+        assert!(bot.shown.is_empty());
+        // it isn't shown yet, so it doesn't take a turn
+        assert_eq!(bot.turns, 0);
+        assert_eq!(bot.moderation.len(), 1);
+        assert_eq!(
+            bot.texts(),
+            [
+                "sticker 😀 from Alice (3) is waiting for approval",
+                "Sent to the owner for approval"
+            ]
+        );
+
+        let calls = bot.api.calls.borrow();
+        assert_eq!(calls[0].0, "forwardMessage");
+        assert_eq!(calls[0].1["chat_id"], OWNER);
+        assert_eq!(calls[1].1["chat_id"], OWNER);
+        assert_eq!(calls[1].1["reply_parameters"]["message_id"], 99);
+        assert_eq!(
+            calls[1].1["reply_markup"]["inline_keyboard"][0][0]["callback_data"],
+            "mod approve 0"
+        );
+        drop(calls);
+
+        assert_eq!(bot.moderation.take(0).unwrap().review, Some((OWNER, 99)));
+
+        // admins skip the review
+        handle_message(&mut bot, &private(ADMIN, sticker())).unwrap();
+        assert_eq!(bot.shown, [7]);
+    }
+
+    #[test]
+    fn a_failed_review_is_dropped() {
+        // every call fails to parse, so the review can't be sent
+        let mut bot = FakeBot::new(FakeApi::answering(json!({ "ok": false })));
+        bot.moderation.enabled = true;
+
+        assert!(handle_message(&mut bot, &private(USER, sticker())).is_err());
+        assert!(bot.moderation.is_empty());
+        assert!(bot.shown.is_empty());
+    }
+
+    #[test]
+    fn unsupported_files_are_refused_before_taking_a_turn() {
+        let mut bot = FakeBot::new(FakeApi::new());
+        let video_sticker = private(
+            USER,
+            json!({
+                "sticker": {
+                    "file_id": "webm",
+                    "file_unique_id": "webm",
+                    "type": "regular",
+                    "width": 512,
+                    "height": 512,
+                    "is_animated": false,
+                    "is_video": true,
+                },
+            }),
+        );
+
+        handle_message(&mut bot, &video_sticker).unwrap();
+        assert!(bot.shown.is_empty());
+        assert_eq!(bot.turns, 0);
+        assert_eq!(bot.texts().len(), 1);
+    }
+
+    #[test]
+    fn commands() {
+        let mut bot = FakeBot::new(FakeApi::new());
+
+        handle_message(&mut bot, &in_group(USER, text("/text hello"))).unwrap();
+        assert_eq!(bot.ran, [(Kind::Text, "hello".to_string())]);
+        // /text changes the panel
+        assert_eq!(bot.turns, 1);
+        assert_eq!(bot.texts(), ["ran with hello"]);
+
+        handle_message(&mut bot, &in_group(USER, text("/panel"))).unwrap();
+        assert_eq!(bot.turns, 1);
+
+        handle_message(&mut bot, &in_group(USER, text("/scale fit"))).unwrap();
+        handle_message(&mut bot, &in_group(ADMIN, text("/scale typo"))).unwrap();
+        handle_message(&mut bot, &in_group(ADMIN, text("/scale@otherbot fit"))).unwrap();
+        assert_eq!(
+            bot.texts(),
+            [
+                "ran with hello",
+                "Only admins can use /scale",
+                "Use /scale fit | crop | stretch | pixel"
+            ]
+        );
+
+        // a command that changes the panel doesn't run without a turn
+        bot.allowed = false;
+        handle_message(&mut bot, &in_group(USER, text("/qr hi"))).unwrap();
+        assert_eq!(bot.ran.len(), 3);
+    }
+
+    #[test]
+    fn callbacks_are_answered() {
+        let mut bot = FakeBot::new(FakeApi::new());
+
+        handle_callback(&mut bot, &callback(USER, "panel brighter")).unwrap();
+        handle_callback(&mut bot, &callback(GUEST, "panel brighter")).unwrap();
+        handle_callback(&mut bot, &callback(USER, "mod approve 3")).unwrap();
+        handle_callback(&mut bot, &callback(ADMIN, "mod approve 3")).unwrap();
+        handle_callback(&mut bot, &callback(ADMIN, "mod approve")).unwrap();
+
+        assert_eq!(bot.actions, [Action::BrightnessUp]);
+        assert_eq!(bot.decided, [(Decision::Approve, 3)]);
+
+        let calls = bot.api.calls.borrow();
+        let answers: Vec<_> = calls
+            .iter()
+            .map(|(method, params)| {
+                assert_eq!(method, "answerCallbackQuery");
+                assert_eq!(params["callback_query_id"], "query");
+                params["text"].as_str().unwrap()
+            })
+            .collect();
+        assert_eq!(
+            answers,
+            [
+                "Brightness 5 of 8",
+                "You can't use this panel",
+                "Only admins can decide on images",
+                "Approved",
+                "This button doesn't do anything anymore"
+            ]
+        );
+    }
+}
@@ -0,0 +1,346 @@
+use std::fs;
+
+use image::{DynamicImage, Rgb, RgbaImage};
+use log::warn;
+
+use crate::downscale::{BoxDownscaler, Crop};
+use crate::storage;
+
+/// The `/scale` mode, by name
+const SCALE_FILE: &str = "scale";
+
+pub const PANEL_WIDTH: u32 = 64;
+pub const PANEL_HEIGHT: u32 = 64;
+
+/// How an image that isn't panel shaped gets mapped onto the panel
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScaleMode {
+    /// Fit the whole image, letterboxing the leftover space
+    Contain,
+    /// Fill the whole panel, cropping the image around its center
+    Cover,
+    /// Fill the whole panel, ignoring the aspect ratio
+    Stretch,
+    /// Scale by a whole factor with nearest neighbour and center it, for pixel art
+    Integer,
+}
+
+impl ScaleMode {
+    pub fn parse(word: &str) -> Option<ScaleMode> {
+        match word.to_lowercase().as_str() {
+            "fit" | "contain" => Some(ScaleMode::Contain),
+            "crop" | "cover" | "fill" => Some(ScaleMode::Cover),
+            "stretch" => Some(ScaleMode::Stretch),
+            "pixel" | "integer" | "center" => Some(ScaleMode::Integer),
+            _ => None,
+        }
+    }
+
+    /// Looks for a mode name among the words of a caption
+    pub fn from_caption(caption: &str) -> Option<ScaleMode> {
+        caption.split_whitespace().find_map(ScaleMode::parse)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ScaleMode::Contain => "fit",
+            ScaleMode::Cover => "crop",
+            ScaleMode::Stretch => "stretch",
+            ScaleMode::Integer => "pixel",
+        }
+    }
+
+    /// The one set with `/scale`, fit if it was never set
+    pub fn load() -> ScaleMode {
+        fs::read_to_string(storage::path(SCALE_FILE))
+            .ok()
+            .and_then(|name| ScaleMode::parse(name.trim()))
+            .unwrap_or(ScaleMode::Contain)
+    }
+
+    pub fn save(self) {
+        if let Err(err) = fs::write(storage::path(SCALE_FILE), self.name()) {
+            warn!("Could not save the scale mode: {:?}", err);
+        }
+    }
+}
+
+/// Parses colors in the `#rrggbb` or `rrggbb` form
+pub fn parse_color(text: &str) -> Option<Rgb<u8>> {
+    let hex = text.trim().trim_start_matches('#');
+    // from_str_radix would also take a sign
+    if hex.len() != 6 || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let value = u32::from_str_radix(hex, 16).ok()?;
+    Some(Rgb([(value >> 16) as u8, (value >> 8) as u8, value as u8]))
+}
+
+/// Where and how big an image ends up inside an area, usually the whole panel
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Placement {
+    pub area_width: u32,
+    pub area_height: u32,
+    pub crop: Crop,
+    pub width: u32,
+    pub height: u32,
+    pub x: u32,
+    pub y: u32,
+}
+
+impl Placement {
+    pub fn new(
+        source_width: u32,
+        source_height: u32,
+        mode: ScaleMode,
+        area_width: u32,
+        area_height: u32,
+    ) -> Placement {
+        let source_width = source_width.max(1);
+        let source_height = source_height.max(1);
+
+        let full = Crop {
+            x: 0,
+            y: 0,
+            width: source_width,
+            height: source_height,
+        };
+
+        let (crop, width, height) = match mode {
+            ScaleMode::Contain => {
+                // compare the aspect ratios without going through floats
+                let (width, height) = if source_width * area_height >= source_height * area_width {
+                    (
+                        area_width,
+                        div_round(source_height * area_width, source_width),
+                    )
+                } else {
+                    (
+                        div_round(source_width * area_height, source_height),
+                        area_height,
+                    )
+                };
+                (full, width.max(1), height.max(1))
+            }
+            ScaleMode::Cover => {
+                let (width, height) = if source_width * area_height >= source_height * area_width {
+                    (
+                        div_round(source_height * area_width, area_height),
+                        source_height,
+                    )
+                } else {
+                    (
+                        source_width,
+                        div_round(source_width * area_height, area_width),
+                    )
+                };
+                (
+                    centered_crop(source_width, source_height, width.max(1), height.max(1)),
+                    area_width,
+                    area_height,
+                )
+            }
+            ScaleMode::Stretch => (full, area_width, area_height),
+            ScaleMode::Integer => {
+                let factor = (area_width / source_width).min(area_height / source_height);
+                if factor >= 1 {
+                    (full, source_width * factor, source_height * factor)
+                } else {
+                    // drop the leftover pixels so every output pixel averages a whole block
+                    let divisor = source_width
+                        .div_ceil(area_width)
+                        .max(source_height.div_ceil(area_height));
+                    let width = (source_width / divisor).max(1);
+                    let height = (source_height / divisor).max(1);
+                    (
+                        centered_crop(
+                            source_width,
+                            source_height,
+                            (width * divisor).min(source_width),
+                            (height * divisor).min(source_height),
+                        ),
+                        width,
+                        height,
+                    )
+                }
+            }
+        };
+
+        Placement {
+            area_width,
+            area_height,
+            crop,
+            width,
+            height,
+            x: (area_width - width) / 2,
+            y: (area_height - height) / 2,
+        }
+    }
+
+    pub fn downscaler(&self) -> BoxDownscaler {
+        BoxDownscaler::new(self.crop, self.width, self.height)
+    }
+
+    /// Puts the scaled image on a transparent canvas as big as the area
+    pub fn place(&self, scaled: &RgbaImage) -> RgbaImage {
+        let mut canvas = RgbaImage::new(self.area_width, self.area_height);
+        image::imageops::replace(&mut canvas, scaled, self.x as i64, self.y as i64);
+        canvas
+    }
+}
+
+/// Scales the image to a `width` x `height` area according to `mode`.
+///
+/// The result is premultiplied RGBA, the space the image doesn't cover is left transparent
+pub fn fit(image: &DynamicImage, mode: ScaleMode, width: u32, height: u32) -> RgbaImage {
+    let placement = Placement::new(image.width(), image.height(), mode, width, height);
+    let mut downscaler = placement.downscaler();
+
+    // feed the common layouts directly instead of converting the whole image
+    match image {
+        DynamicImage::ImageRgba8(rgba) => {
+            for row in rgba.chunks_exact(rgba.width() as usize * 4) {
+                downscaler.push_row(row);
+            }
+        }
+        DynamicImage::ImageRgb8(rgb) => {
+            for row in rgb.chunks_exact(rgb.width() as usize * 3) {
+                downscaler.push_rgb_row(row);
+            }
+        }
+        other => {
+            let rgba = other.to_rgba8();
+            for row in rgba.chunks_exact(rgba.width() as usize * 4) {
+                downscaler.push_row(row);
+            }
+        }
+    }
+
+    placement.place(&downscaler.finish())
+}
+
+fn div_round(numerator: u32, denominator: u32) -> u32 {
+    (numerator + denominator / 2) / denominator
+}
+
+fn centered_crop(source_width: u32, source_height: u32, width: u32, height: u32) -> Crop {
+    Crop {
+        x: (source_width - width) / 2,
+        y: (source_height - height) / 2,
+        width,
+        height,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
+
+    fn solid(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_pixel(
+            width,
+            height,
+            Rgba([200, 100, 50, 255]),
+        ))
+    }
+
+    #[test]
+    fn letterboxes_wide_images_above_and_below() {
+        let placement = Placement::new(128, 64, ScaleMode::Contain, 64, 64);
+        assert_eq!((placement.width, placement.height), (64, 32));
+        assert_eq!((placement.x, placement.y), (0, 16));
+
+        let fitted = fit(&solid(128, 64), ScaleMode::Contain, 64, 64);
+        assert_eq!(fitted.get_pixel(0, 15)[3], 0);
+        assert_eq!(*fitted.get_pixel(0, 16), Rgba([200, 100, 50, 255]));
+        assert_eq!(*fitted.get_pixel(63, 47), Rgba([200, 100, 50, 255]));
+        assert_eq!(fitted.get_pixel(63, 48)[3], 0);
+    }
+
+    #[test]
+    fn letterboxes_tall_images_left_and_right() {
+        let placement = Placement::new(32, 128, ScaleMode::Contain, 64, 64);
+        assert_eq!((placement.width, placement.height), (16, 64));
+        assert_eq!((placement.x, placement.y), (24, 0));
+
+        let fitted = fit(&solid(32, 128), ScaleMode::Contain, 64, 64);
+        assert_eq!(fitted.get_pixel(23, 0)[3], 0);
+        assert_eq!(fitted.get_pixel(24, 0)[3], 255);
+        assert_eq!(fitted.get_pixel(39, 63)[3], 255);
+        assert_eq!(fitted.get_pixel(40, 63)[3], 0);
+    }
+
+    #[test]
+    fn crops_around_the_center_to_cover() {
+        let wide = Placement::new(200, 100, ScaleMode::Cover, 64, 64);
+        let expected = Crop {
+            x: 50,
+            y: 0,
+            width: 100,
+            height: 100,
+        };
+        assert_eq!(wide.crop, expected);
+        assert_eq!((wide.width, wide.height, wide.x, wide.y), (64, 64, 0, 0));
+
+        let tall = Placement::new(100, 300, ScaleMode::Cover, 64, 64);
+        let expected = Crop {
+            x: 0,
+            y: 100,
+            width: 100,
+            height: 100,
+        };
+        assert_eq!(tall.crop, expected);
+
+        let fitted = fit(&solid(200, 100), ScaleMode::Cover, 64, 64);
+        assert!(fitted.pixels().all(|pixel| pixel[3] == 255));
+    }
+
+    #[test]
+    fn scales_pixel_art_by_a_whole_factor() {
+        let placement = Placement::new(20, 10, ScaleMode::Integer, 64, 64);
+        assert_eq!((placement.width, placement.height), (60, 30));
+        assert_eq!((placement.x, placement.y), (2, 17));
+
+        // a 2x2 checkerboard comes out as four sharp 32x32 blocks
+        let mut checkerboard = RgbaImage::new(2, 2);
+        checkerboard.put_pixel(0, 0, Rgba([255, 255, 255, 255]));
+        checkerboard.put_pixel(1, 1, Rgba([255, 255, 255, 255]));
+        checkerboard.put_pixel(1, 0, Rgba([0, 0, 0, 255]));
+        checkerboard.put_pixel(0, 1, Rgba([0, 0, 0, 255]));
+
+        let fitted = fit(
+            &DynamicImage::ImageRgba8(checkerboard),
+            ScaleMode::Integer,
+            64,
+            64,
+        );
+        assert_eq!(*fitted.get_pixel(31, 31), Rgba([255, 255, 255, 255]));
+        assert_eq!(*fitted.get_pixel(32, 31), Rgba([0, 0, 0, 255]));
+        assert_eq!(*fitted.get_pixel(31, 32), Rgba([0, 0, 0, 255]));
+        assert_eq!(*fitted.get_pixel(32, 32), Rgba([255, 255, 255, 255]));
+    }
+
+    #[test]
+    fn shrinks_big_pixel_art_by_a_whole_divisor() {
+        let placement = Placement::new(200, 100, ScaleMode::Integer, 64, 64);
+
+        assert_eq!((placement.width, placement.height), (50, 25));
+        assert_eq!((placement.x, placement.y), (7, 19));
+    }
+
+    #[test]
+    fn parses_hex_colors() {
+        assert_eq!(parse_color("#ff8000"), Some(Rgb([255, 128, 0])));
+        assert_eq!(parse_color("00ff00"), Some(Rgb([0, 255, 0])));
+        assert_eq!(parse_color(" #0000FF "), Some(Rgb([0, 0, 255])));
+
+        assert_eq!(parse_color("#fff"), None);
+        assert_eq!(parse_color("#ff80001"), None);
+        assert_eq!(parse_color("zzzzzz"), None);
+        assert_eq!(parse_color("+12345"), None);
+        assert_eq!(parse_color(""), None);
+    }
+}
@@ -0,0 +1,10 @@
+use std::time::Duration;
+
+use image::RgbImage;
+
+/// Something that changes over time, drawn by the display thread
+pub trait Scene: Send {
+    /// The frame to show `elapsed` after the scene started, and how long it
+    /// stays up before the next one is needed. `None` ends the scene on this frame.
+    fn frame(&mut self, elapsed: Duration) -> (RgbImage, Option<Duration>);
+}
@@ -0,0 +1,52 @@
+use std::io::Cursor;
+
+use image::{ImageFormat, ImageResult, Rgb, RgbImage};
+
+/// Screen pixels per panel pixel, Telegram would blur a 64x64 photo
+pub const SCALE: u32 = 8;
+
+/// What shows between the LEDs of a real panel
+const GAP_COLOR: Rgb<u8> = Rgb([16, 16, 16]);
+
+/// Diameter of an LED as a fraction of the pixel pitch
+const DOT_SIZE: f32 = 0.8;
+
+/// Blows the frame up to `SCALE` with square pixels,
+/// or with round dots on a dark board when `led_look` is set
+pub fn render(frame: &RgbImage, led_look: bool) -> RgbImage {
+    if !led_look {
+        return image::imageops::resize(
+            frame,
+            frame.width() * SCALE,
+            frame.height() * SCALE,
+            image::imageops::FilterType::Nearest,
+        );
+    }
+
+    // how much of each screen pixel in a cell an LED covers, with antialiased edges
+    let center = SCALE as f32 / 2.0;
+    let radius = SCALE as f32 * DOT_SIZE / 2.0;
+    let coverage: Vec<u32> = (0..SCALE * SCALE)
+        .map(|index| {
+            let dx = (index % SCALE) as f32 + 0.5 - center;
+            let dy = (index / SCALE) as f32 + 0.5 - center;
+            let distance = (dx * dx + dy * dy).sqrt();
+            ((radius + 0.5 - distance).clamp(0.0, 1.0) * 255.0) as u32
+        })
+        .collect();
+
+    RgbImage::from_fn(frame.width() * SCALE, frame.height() * SCALE, |x, y| {
+        let led = frame.get_pixel(x / SCALE, y / SCALE);
+        let covered = coverage[((y % SCALE) * SCALE + x % SCALE) as usize];
+
+        Rgb(std::array::from_fn(|c| {
+            ((led[c] as u32 * covered + GAP_COLOR[c] as u32 * (255 - covered)) / 255) as u8
+        }))
+    })
+}
+
+pub fn encode_png(image: &RgbImage) -> ImageResult<Vec<u8>> {
+    let mut png = Vec::new();
+    image.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+    Ok(png)
+}
@@ -0,0 +1,38 @@
+use std::path::{Path, PathBuf};
+
+#[cfg(target_os = "espidf")]
+use esp_idf_sys::{esp, esp_spiffs_info, esp_vfs_spiffs_conf_t, esp_vfs_spiffs_register, EspError};
+#[cfg(target_os = "espidf")]
+use log::info;
+
+/// Where the `storage` partition shows up in the filesystem
+pub const MOUNT_POINT: &str = "/storage";
+
+/// Mounts the SPIFFS `storage` partition, formatting it the first time.
+///
+/// After this the partition can be used with `std::fs`. SPIFFS has no
+/// directories and names are limited to 31 characters, mount point excluded.
+#[cfg(target_os = "espidf")]
+pub fn mount() -> Result<(), EspError> {
+    let config = esp_vfs_spiffs_conf_t {
+        base_path: c"/storage".as_ptr(),
+        partition_label: c"storage".as_ptr(),
+        max_files: 4,
+        format_if_mount_failed: true,
+    };
+
+    esp!(unsafe { esp_vfs_spiffs_register(&config) })?;
+
+    let mut total = 0;
+    let mut used = 0;
+    esp!(unsafe { esp_spiffs_info(config.partition_label, &mut total, &mut used) })?;
+
+    info!("Storage mounted, {} of {} bytes used", used, total);
+
+    Ok(())
+}
+
+/// Full path of a file on the storage partition
+pub fn path(name: &str) -> PathBuf {
+    Path::new(MOUNT_POINT).join(name)
+}
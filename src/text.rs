@@ -0,0 +1,339 @@
+use embedded_graphics::{
+    mono_font::{iso_8859_1, iso_8859_5, iso_8859_7, MonoFont, MonoTextStyle},
+    pixelcolor::Rgb888,
+    prelude::*,
+    primitives::{Circle, PrimitiveStyle},
+    text::{Baseline, Text},
+};
+use image::{Rgb, RgbaImage};
+
+use crate::canvas::{to_rgb888, Canvas};
+use crate::scaling::{parse_color, PANEL_HEIGHT, PANEL_WIDTH};
+
+/// Every size comes in Cyrillic, Latin-1 and Greek variants of the same
+/// X11 fixed font, picked per character. All of them include ASCII.
+static FONTS: [[&MonoFont<'static>; 3]; 5] = [
+    [
+        &iso_8859_5::FONT_10X20,
+        &iso_8859_1::FONT_10X20,
+        &iso_8859_7::FONT_10X20,
+    ],
+    [
+        &iso_8859_5::FONT_7X14,
+        &iso_8859_1::FONT_7X14,
+        &iso_8859_7::FONT_7X14,
+    ],
+    [
+        &iso_8859_5::FONT_6X10,
+        &iso_8859_1::FONT_6X10,
+        &iso_8859_7::FONT_6X10,
+    ],
+    [
+        &iso_8859_5::FONT_5X8,
+        &iso_8859_1::FONT_5X8,
+        &iso_8859_7::FONT_5X8,
+    ],
+    [
+        &iso_8859_5::FONT_4X6,
+        &iso_8859_1::FONT_4X6,
+        &iso_8859_7::FONT_4X6,
+    ],
+];
+
+const EMOJI_COLOR: Rgb888 = Rgb888::new(255, 200, 0);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FontSize {
+    Huge,
+    Large,
+    Medium,
+    Small,
+    Tiny,
+}
+
+impl FontSize {
+    /// Biggest first
+    pub const ALL: [FontSize; 5] = [
+        FontSize::Huge,
+        FontSize::Large,
+        FontSize::Medium,
+        FontSize::Small,
+        FontSize::Tiny,
+    ];
+
+    pub fn parse(word: &str) -> Option<FontSize> {
+        match word {
+            "huge" => Some(FontSize::Huge),
+            "large" => Some(FontSize::Large),
+            "medium" => Some(FontSize::Medium),
+            "small" => Some(FontSize::Small),
+            "tiny" => Some(FontSize::Tiny),
+            _ => None,
+        }
+    }
+
+    fn fonts(self) -> &'static [&'static MonoFont<'static>; 3] {
+        &FONTS[self as usize]
+    }
+
+    /// Horizontal distance between two characters
+    pub fn advance(self) -> u32 {
+        let font = self.fonts()[0];
+        font.character_size.width + font.character_spacing
+    }
+
+    pub fn line_height(self) -> u32 {
+        self.fonts()[0].character_size.height
+    }
+
+    /// How many characters fit in `width` pixels
+    pub fn columns(self, width: u32) -> usize {
+        let font = self.fonts()[0];
+        ((width + font.character_spacing) / self.advance()) as usize
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Align {
+    Left,
+    Center,
+    Right,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TextStyle {
+    pub color: Rgb<u8>,
+    pub align: Align,
+    /// `None` picks the biggest size that fits
+    pub size: Option<FontSize>,
+}
+
+impl Default for TextStyle {
+    fn default() -> Self {
+        TextStyle {
+            color: Rgb([255, 255, 255]),
+            align: Align::Center,
+            size: None,
+        }
+    }
+}
+
+/// Splits the `color=#rrggbb align=left size=tiny` options at the start of a
+/// `/text` argument from the text itself
+pub fn parse_options(argument: &str) -> (TextStyle, &str) {
+    parse_options_with(argument, |_, _| None)
+}
+
+/// Like [`parse_options`], options the text style doesn't know about are
+/// passed to `other`, which returns `None` if it doesn't know them either
+pub fn parse_options_with(
+    argument: &str,
+    mut other: impl FnMut(&str, &str) -> Option<()>,
+) -> (TextStyle, &str) {
+    let mut style = TextStyle::default();
+    let mut rest = argument.trim_start();
+
+    while let Some((option, value)) = rest
+        .split(char::is_whitespace)
+        .next()
+        .and_then(|word| word.split_once('='))
+    {
+        let parsed = match option {
+            "color" => parse_color(value).map(|color| style.color = color),
+            "align" => match value {
+                "left" => Some(Align::Left),
+                "center" => Some(Align::Center),
+                "right" => Some(Align::Right),
+                _ => None,
+            }
+            .map(|align| style.align = align),
+            "size" => FontSize::parse(value).map(|size| style.size = Some(size)),
+            _ => other(option, value),
+        };
+
+        if parsed.is_none() {
+            break;
+        }
+
+        rest = rest[option.len() + 1 + value.len()..].trim_start();
+    }
+
+    (style, rest)
+}
+
+/// Characters as they will be drawn, one per cell.
+///
+/// Emoji modifiers are dropped and emoji joined with a zero width joiner
+/// collapse to their first emoji, since all of them get the same fallback glyph.
+pub fn glyphs(text: &str) -> Vec<char> {
+    let mut glyphs = Vec::new();
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\u{200d}' => {
+                chars.next();
+            }
+            // variation selectors and skin tones
+            '\u{fe00}'..='\u{fe0f}' | '\u{1f3fb}'..='\u{1f3ff}' => {}
+            '\t' => glyphs.push(' '),
+            c if c.is_control() && c != '\n' => {}
+            c => glyphs.push(c),
+        }
+    }
+
+    glyphs
+}
+
+fn is_emoji(c: char) -> bool {
+    matches!(c,
+        '\u{2600}'..='\u{27bf}'
+        | '\u{1f000}'..='\u{1faff}'
+        | '\u{2b00}'..='\u{2bff}')
+}
+
+/// Greedy word wrap, the second value is true if a word had to be broken
+pub fn wrap(glyphs: &[char], columns: usize) -> (Vec<Vec<char>>, bool) {
+    let columns = columns.max(1);
+    let mut lines = Vec::new();
+    let mut broke_words = false;
+
+    for paragraph in glyphs.split(|&c| c == '\n') {
+        let mut line: Vec<char> = Vec::new();
+
+        for word in paragraph
+            .split(|&c| c == ' ')
+            .filter(|word| !word.is_empty())
+        {
+            if !line.is_empty() && line.len() + 1 + word.len() <= columns {
+                line.push(' ');
+                line.extend_from_slice(word);
+                continue;
+            }
+
+            if !line.is_empty() {
+                lines.push(std::mem::take(&mut line));
+            }
+
+            let mut word = word;
+            while word.len() > columns {
+                broke_words = true;
+                lines.push(word[..columns].to_vec());
+                word = &word[columns..];
+            }
+            line.extend_from_slice(word);
+        }
+
+        lines.push(line);
+    }
+
+    (lines, broke_words)
+}
+
+/// Picks the biggest font the text fits in without breaking words,
+/// if there is none the smallest one is used and the text is cut
+fn layout(glyphs: &[char], size: Option<FontSize>) -> (FontSize, Vec<Vec<char>>) {
+    let fits = |size: FontSize| {
+        let (lines, broke_words) = wrap(glyphs, size.columns(PANEL_WIDTH));
+        let rows = (PANEL_HEIGHT / size.line_height()) as usize;
+        (lines.len() <= rows && !broke_words).then_some(lines)
+    };
+
+    let sizes = match size {
+        Some(size) => vec![size],
+        None => FontSize::ALL.to_vec(),
+    };
+
+    for &size in &sizes {
+        if let Some(lines) = fits(size) {
+            return (size, lines);
+        }
+    }
+
+    let size = *sizes.last().unwrap();
+    let (mut lines, _) = wrap(glyphs, size.columns(PANEL_WIDTH));
+    lines.truncate((PANEL_HEIGHT / size.line_height()) as usize);
+
+    (size, lines)
+}
+
+/// Draws one line of glyphs with its top left corner at `origin`
+pub fn draw_line<D>(target: &mut D, glyphs: &[char], size: FontSize, origin: Point, color: Rgb888)
+where
+    D: DrawTarget<Color = Rgb888>,
+{
+    let advance = size.advance() as i32;
+    let line_height = size.line_height();
+    let mut buffer = [0u8; 4];
+
+    for (i, &c) in glyphs.iter().enumerate() {
+        let position = origin + Point::new(i as i32 * advance, 0);
+
+        let font = size.fonts().iter().find(|font| {
+            // unknown characters map to the same glyph as '?'
+            c == '?' || font.glyph_mapping.index(c) != font.glyph_mapping.index('?')
+        });
+
+        match font {
+            Some(font) => {
+                let style = MonoTextStyle::new(font, color);
+                Text::with_baseline(c.encode_utf8(&mut buffer), position, style, Baseline::Top)
+                    .draw(target)
+                    .ok();
+            }
+            None if is_emoji(c) => {
+                // a yellow blob reads as "some emoji" even at 4 pixels
+                let diameter = (advance as u32).min(line_height).saturating_sub(1).max(2);
+                let top = (line_height - diameter) as i32 / 2;
+                Circle::new(position + Point::new(0, top), diameter)
+                    .into_styled(PrimitiveStyle::with_fill(EMOJI_COLOR))
+                    .draw(target)
+                    .ok();
+            }
+            None => {
+                let style = MonoTextStyle::new(size.fonts()[0], color);
+                Text::with_baseline("?", position, style, Baseline::Top)
+                    .draw(target)
+                    .ok();
+            }
+        }
+    }
+}
+
+/// Width in pixels of a line of glyphs
+pub fn line_width(glyphs: &[char], size: FontSize) -> u32 {
+    let font = size.fonts()[0];
+    (glyphs.len() as u32 * size.advance()).saturating_sub(font.character_spacing)
+}
+
+/// Renders text to fit the panel, on a transparent background
+pub fn render(text: &str, style: &TextStyle) -> RgbaImage {
+    let glyphs = glyphs(text);
+    let (size, lines) = layout(&glyphs, style.size);
+
+    let mut image = RgbaImage::new(PANEL_WIDTH, PANEL_HEIGHT);
+    let mut canvas = Canvas(&mut image);
+
+    let line_height = size.line_height();
+    let top = (PANEL_HEIGHT.saturating_sub(lines.len() as u32 * line_height)) / 2;
+
+    for (row, line) in lines.iter().enumerate() {
+        let width = line_width(line, size);
+        let x = match style.align {
+            Align::Left => 0,
+            Align::Center => (PANEL_WIDTH.saturating_sub(width)) / 2,
+            Align::Right => PANEL_WIDTH.saturating_sub(width),
+        };
+        let y = top + row as u32 * line_height;
+
+        draw_line(
+            &mut canvas,
+            line,
+            size,
+            Point::new(x as i32, y as i32),
+            to_rgb888(style.color),
+        );
+    }
+
+    image
+}
@@ -0,0 +1,117 @@
+use std::fs;
+
+use log::warn;
+
+use crate::storage;
+
+const TIMEZONE_FILE: &str = "timezone";
+pub const DEFAULT: &str = "UTC0";
+
+/// Turns `+2`, `-5:30` or `UTC+1` into a POSIX TZ string,
+/// anything else is taken to already be one, like `CET-1CEST,M3.5.0,M10.5.0/3`
+pub fn parse(text: &str) -> Option<String> {
+    let text = text.trim();
+    if text.is_empty() || text.len() > 64 || !text.is_ascii() || text.contains(char::is_whitespace)
+    {
+        return None;
+    }
+
+    let offset = text
+        .strip_prefix("UTC")
+        .or_else(|| text.strip_prefix("GMT"))
+        .unwrap_or(text);
+
+    // POSIX counts west of Greenwich as positive, the opposite of how offsets are written
+    let flipped = match offset.chars().next() {
+        None => return Some(DEFAULT.to_string()),
+        Some('+') => Some('-'),
+        Some('-') => Some('+'),
+        _ => None,
+    };
+
+    match flipped {
+        Some(sign) => {
+            let (hours, minutes) = offset[1..].split_once(':').unwrap_or((&offset[1..], "0"));
+            // parse would also take a second sign
+            let number = |digits: &str| {
+                digits
+                    .bytes()
+                    .all(|byte| byte.is_ascii_digit())
+                    .then(|| digits.parse::<u32>().ok())
+                    .flatten()
+            };
+            let hours = number(hours)?;
+            let minutes = number(minutes)?;
+            (hours <= 14 && minutes < 60).then(|| format!("UTC{sign}{hours}:{minutes:02}"))
+        }
+        // the name is followed by an offset, IANA names like Europe/Rome
+        // need a database newlib doesn't have
+        None => {
+            let zone = text.split(',').next().unwrap_or_default();
+            (zone.starts_with(|c: char| c.is_ascii_alphabetic() || c == '<')
+                && zone.contains(|c: char| c.is_ascii_digit())
+                && !zone.contains('/'))
+            .then(|| text.to_string())
+        }
+    }
+}
+
+/// Saves `timezone` for the next boot, `Display::set_timezone` applies it
+pub fn save(timezone: &str) {
+    if let Err(err) = fs::write(storage::path(TIMEZONE_FILE), timezone) {
+        warn!("Could not save the timezone: {:?}", err);
+    }
+}
+
+/// The saved timezone, read from storage as TZ belongs to the display thread
+pub fn saved() -> String {
+    fs::read_to_string(storage::path(TIMEZONE_FILE))
+        .ok()
+        .and_then(|timezone| parse(&timezone))
+        .unwrap_or_else(|| DEFAULT.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flips_written_offsets_for_posix() {
+        assert_eq!(parse("UTC+2").as_deref(), Some("UTC-2:00"));
+        assert_eq!(parse("+05:30").as_deref(), Some("UTC-5:30"));
+        assert_eq!(parse("-3").as_deref(), Some("UTC+3:00"));
+        assert_eq!(parse("GMT-10").as_deref(), Some("UTC+10:00"));
+        assert_eq!(parse(" UTC+14 ").as_deref(), Some("UTC-14:00"));
+        assert_eq!(parse("UTC").as_deref(), Some(DEFAULT));
+    }
+
+    #[test]
+    fn keeps_posix_strings() {
+        for posix in [
+            "CET-1CEST,M3.5.0,M10.5.0/3",
+            "EST5EDT",
+            "<+0330>-3:30",
+            "UTC0",
+        ] {
+            assert_eq!(parse(posix).as_deref(), Some(posix));
+        }
+    }
+
+    #[test]
+    fn refuses_the_rest() {
+        for invalid in [
+            "",
+            "Europe/Rome",
+            "CET",
+            "+15",
+            "+5:60",
+            "++5",
+            "+-5",
+            "UTC+two",
+            "UTC +2",
+            "MSK−3",
+        ] {
+            assert_eq!(parse(invalid), None, "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,272 @@
+use std::fs;
+use std::time::Duration;
+
+use image::{Rgb, RgbImage};
+use log::warn;
+
+use crate::filters::pixelate;
+use crate::storage;
+
+/// The `/transition`, its name and duration in milliseconds
+const TRANSITION_FILE: &str = "transition";
+
+/// How often intermediate frames are drawn, rendering the GPIO states
+/// of a frame takes about this long anyway
+pub const FRAME_TIME: Duration = Duration::from_millis(40);
+
+pub const DEFAULT_DURATION: Duration = Duration::from_millis(600);
+pub const MIN_DURATION: Duration = Duration::from_millis(100);
+pub const MAX_DURATION: Duration = Duration::from_secs(5);
+
+/// `progress` goes from 0 (only the old image) to this (only the new one)
+pub const DONE: u32 = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransitionKind {
+    /// The new image replaces the old one at once
+    Cut,
+    Crossfade,
+    /// The new image is uncovered from left to right
+    Wipe,
+    /// The new image pushes the old one out to the left
+    Slide,
+    /// Pixels switch over one at a time in random order
+    Dissolve,
+    /// The old image breaks up in blocks that resolve into the new one
+    Pixelate,
+}
+
+impl TransitionKind {
+    pub fn parse(word: &str) -> Option<TransitionKind> {
+        match word {
+            "cut" | "none" => Some(TransitionKind::Cut),
+            "crossfade" | "fade" => Some(TransitionKind::Crossfade),
+            "wipe" => Some(TransitionKind::Wipe),
+            "slide" => Some(TransitionKind::Slide),
+            "dissolve" => Some(TransitionKind::Dissolve),
+            "pixelate" => Some(TransitionKind::Pixelate),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TransitionKind::Cut => "cut",
+            TransitionKind::Crossfade => "crossfade",
+            TransitionKind::Wipe => "wipe",
+            TransitionKind::Slide => "slide",
+            TransitionKind::Dissolve => "dissolve",
+            TransitionKind::Pixelate => "pixelate",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Transition {
+    pub kind: TransitionKind,
+    pub duration: Duration,
+}
+
+impl Default for Transition {
+    fn default() -> Self {
+        Transition {
+            kind: TransitionKind::Crossfade,
+            duration: DEFAULT_DURATION,
+        }
+    }
+}
+
+impl Transition {
+    /// The one set with `/transition`, the default if it was never set
+    pub fn load() -> Transition {
+        fs::read_to_string(storage::path(TRANSITION_FILE))
+            .ok()
+            .and_then(|text| Transition::parse_setting(&text))
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) {
+        if let Err(err) = fs::write(storage::path(TRANSITION_FILE), self.setting()) {
+            warn!("Could not save the transition: {:?}", err);
+        }
+    }
+
+    /// The way `parse_setting` takes it back
+    fn setting(&self) -> String {
+        format!("{} {}", self.kind.name(), self.duration.as_millis())
+    }
+
+    fn parse_setting(text: &str) -> Option<Transition> {
+        let (kind, millis) = text.trim().split_once(' ')?;
+        let duration = Duration::from_millis(millis.parse().ok()?);
+
+        (MIN_DURATION..=MAX_DURATION)
+            .contains(&duration)
+            .then_some(Transition {
+                kind: TransitionKind::parse(kind)?,
+                duration,
+            })
+    }
+
+    pub fn is_cut(&self) -> bool {
+        self.kind == TransitionKind::Cut || self.duration.is_zero()
+    }
+
+    /// How far along the transition is `elapsed` after it started, `None` once it's over
+    pub fn progress(&self, elapsed: Duration) -> Option<u32> {
+        if self.is_cut() || elapsed >= self.duration {
+            return None;
+        }
+
+        Some((elapsed.as_millis() * DONE as u128 / self.duration.as_millis()) as u32)
+    }
+
+    /// The frame `progress` of the way from `from` to `to`, both panel sized
+    pub fn blend(&self, from: &RgbImage, to: &RgbImage, progress: u32) -> RgbImage {
+        let progress = progress.min(DONE);
+        let (width, height) = to.dimensions();
+
+        match self.kind {
+            TransitionKind::Cut => to.clone(),
+            TransitionKind::Crossfade => RgbImage::from_fn(width, height, |x, y| {
+                let (a, b) = (from.get_pixel(x, y), to.get_pixel(x, y));
+                Rgb(std::array::from_fn(|c| {
+                    ((a[c] as u32 * (DONE - progress) + b[c] as u32 * progress) / DONE) as u8
+                }))
+            }),
+            TransitionKind::Wipe => {
+                let edge = width * progress / DONE;
+                RgbImage::from_fn(width, height, |x, y| {
+                    if x < edge {
+                        *to.get_pixel(x, y)
+                    } else {
+                        *from.get_pixel(x, y)
+                    }
+                })
+            }
+            TransitionKind::Slide => {
+                let offset = width * progress / DONE;
+                RgbImage::from_fn(width, height, |x, y| {
+                    if x + offset < width {
+                        *from.get_pixel(x + offset, y)
+                    } else {
+                        *to.get_pixel(x + offset - width, y)
+                    }
+                })
+            }
+            TransitionKind::Dissolve => RgbImage::from_fn(width, height, |x, y| {
+                if noise(x, y) < progress {
+                    *to.get_pixel(x, y)
+                } else {
+                    *from.get_pixel(x, y)
+                }
+            }),
+            TransitionKind::Pixelate => {
+                // blocks grow on the old image up to halfway, then shrink on the new one
+                let (source, coarseness) = if progress < DONE / 2 {
+                    (from, progress * 2)
+                } else {
+                    (to, (DONE - progress) * 2)
+                };
+                pixelate(source, 1 + MAX_BLOCK * coarseness / DONE)
+            }
+        }
+    }
+}
+
+/// Biggest pixelate block, in panel pixels
+const MAX_BLOCK: u32 = 15;
+
+/// Fixed pseudo random value in 0..256 for every pixel,
+/// so dissolving pixels stay switched once they are
+fn noise(x: u32, y: u32) -> u32 {
+    let mut hash = x.wrapping_mul(0x9e37_79b1) ^ y.wrapping_mul(0x85eb_ca77);
+    hash ^= hash >> 15;
+    hash = hash.wrapping_mul(0x2c1b_3c6d);
+    hash ^= hash >> 12;
+    hash & 0xff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transition(kind: TransitionKind, millis: u64) -> Transition {
+        Transition {
+            kind,
+            duration: Duration::from_millis(millis),
+        }
+    }
+
+    /// Every frame the display thread draws, one per `FRAME_TIME`
+    fn frames(transition: &Transition, from: &RgbImage, to: &RgbImage) -> Vec<RgbImage> {
+        (0..)
+            .map(|step| transition.progress(FRAME_TIME * step))
+            .take_while(Option::is_some)
+            .map(|progress| transition.blend(from, to, progress.unwrap()))
+            .collect()
+    }
+
+    fn images() -> (RgbImage, RgbImage) {
+        let from = RgbImage::from_fn(64, 64, |x, y| Rgb([x as u8, y as u8, 0]));
+        let to = RgbImage::from_fn(64, 64, |x, y| Rgb([0, x as u8 * 2, y as u8 * 3]));
+        (from, to)
+    }
+
+    #[test]
+    fn draws_a_frame_per_frame_time() {
+        let (from, to) = images();
+        let count = |kind, millis| frames(&transition(kind, millis), &from, &to).len();
+
+        assert_eq!(count(TransitionKind::Wipe, 600), 15);
+        assert_eq!(count(TransitionKind::Slide, 100), 3);
+        assert_eq!(count(TransitionKind::Dissolve, 5000), 125);
+        assert_eq!(count(TransitionKind::Cut, 600), 0);
+    }
+
+    #[test]
+    fn goes_from_the_old_image_to_the_new_one() {
+        let (from, to) = images();
+
+        for kind in [
+            TransitionKind::Crossfade,
+            TransitionKind::Wipe,
+            TransitionKind::Slide,
+            TransitionKind::Dissolve,
+        ] {
+            let transition = transition(kind, 600);
+            let frames = frames(&transition, &from, &to);
+
+            assert_eq!(frames[0], from, "{}", kind.name());
+            // the display thread draws `to` once the time is up, the same as a finished blend
+            assert_eq!(transition.blend(&from, &to, DONE), to, "{}", kind.name());
+        }
+    }
+
+    #[test]
+    fn dissolved_pixels_stay_switched() {
+        let from = RgbImage::from_pixel(64, 64, Rgb([0, 0, 0]));
+        let to = RgbImage::from_pixel(64, 64, Rgb([255, 255, 255]));
+        let transition = transition(TransitionKind::Dissolve, 600);
+
+        let mut previous = from.clone();
+        for progress in (0..=DONE).step_by(16) {
+            let frame = transition.blend(&from, &to, progress);
+            for (before, after) in previous.pixels().zip(frame.pixels()) {
+                assert!(before[0] <= after[0]);
+            }
+            previous = frame;
+        }
+        assert_eq!(previous, to);
+    }
+
+    #[test]
+    fn reads_back_what_it_saved() {
+        let saved = transition(TransitionKind::Slide, 1200);
+        assert_eq!(Transition::parse_setting(&saved.setting()), Some(saved));
+
+        assert_eq!(Transition::parse_setting("slide 20"), None);
+        assert_eq!(Transition::parse_setting("spin 600"), None);
+        assert_eq!(Transition::parse_setting("slide"), None);
+    }
+}
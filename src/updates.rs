@@ -0,0 +1,177 @@
+use anyhow::Result;
+use frankenstein::{
+    AnswerCallbackQueryParams, SendMessageParams, TelegramApi, Update, UpdateContent,
+};
+use log::error;
+
+use crate::recovery::Failures;
+
+/// What updates are handed to, whether they were polled or came through the webhook
+pub trait Handler {
+    type Api: TelegramApi;
+
+    /// Where the sender is told when an update fails
+    fn api(&self) -> &Self::Api;
+
+    fn handle_update(&mut self, update: &Update) -> Result<()>;
+}
+
+/// Hands `update` to `handler` and tells whoever sent it what went wrong if it fails.
+/// True once `failures` reached its limit and the firmware should restart
+pub fn handle(handler: &mut impl Handler, update: Update, failures: &mut Failures) -> bool {
+    let Err(err) = handler.handle_update(&update) else {
+        failures.succeeded();
+        return false;
+    };
+
+    error!("Update {} failed: {:?}", update.update_id, err);
+    reply_error(handler.api(), &update, &err);
+
+    failures.failed()
+}
+
+/// Tells whoever sent `update` why it failed
+pub fn reply_error(api: &impl TelegramApi, update: &Update, err: &anyhow::Error) {
+    let text = format!("{err:#}");
+
+    match &update.content {
+        UpdateContent::Message(message) => {
+            api.send_message(
+                &SendMessageParams::builder()
+                    .chat_id(message.chat.id)
+                    .text(text)
+                    .build(),
+            )
+            .ok();
+        }
+        UpdateContent::CallbackQuery(query) => {
+            api.answer_callback_query(
+                &AnswerCallbackQueryParams::builder()
+                    .callback_query_id(query.id.clone())
+                    .text(text)
+                    .show_alert(true)
+                    .build(),
+            )
+            .ok();
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{anyhow, Context};
+    use serde_json::json;
+
+    use super::*;
+    use crate::fake_api::FakeApi;
+
+    /// Fails the updates whose id is in `failing`
+    struct FakeBot {
+        api: FakeApi,
+        failing: Vec<u32>,
+        handled: Vec<u32>,
+    }
+
+    impl FakeBot {
+        fn failing(failing: &[u32]) -> FakeBot {
+            FakeBot {
+                api: FakeApi::new(),
+                failing: failing.to_vec(),
+                handled: Vec::new(),
+            }
+        }
+    }
+
+    impl Handler for FakeBot {
+        type Api = FakeApi;
+
+        fn api(&self) -> &FakeApi {
+            &self.api
+        }
+
+        fn handle_update(&mut self, update: &Update) -> Result<()> {
+            self.handled.push(update.update_id);
+
+            if self.failing.contains(&update.update_id) {
+                return Err(anyhow!("the file is gone")).context("Can't display this image");
+            }
+            Ok(())
+        }
+    }
+
+    fn message(update_id: u32) -> Update {
+        serde_json::from_value(json!({
+            "update_id": update_id,
+            "message": {
+                "message_id": 7,
+                "date": 0,
+                "chat": { "id": 42, "type": "private" },
+                "text": "hello",
+            },
+        }))
+        .unwrap()
+    }
+
+    fn callback(update_id: u32) -> Update {
+        serde_json::from_value(json!({
+            "update_id": update_id,
+            "callback_query": {
+                "id": "query",
+                "from": { "id": 42, "is_bot": false, "first_name": "A" },
+                "chat_instance": "1",
+                "data": "panel:next",
+            },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn successes_say_nothing() {
+        let mut bot = FakeBot::failing(&[]);
+        let mut failures = Failures::new(2);
+
+        assert!(!handle(&mut bot, message(1), &mut failures));
+        assert_eq!(bot.handled, [1]);
+        assert!(bot.api.calls.borrow().is_empty());
+    }
+
+    #[test]
+    fn tells_the_sender_of_a_failed_message() {
+        let mut bot = FakeBot::failing(&[1]);
+        let mut failures = Failures::new(2);
+
+        assert!(!handle(&mut bot, message(1), &mut failures));
+
+        let (method, params) = bot.api.call();
+        assert_eq!(method, "sendMessage");
+        assert_eq!(params["chat_id"], 42);
+        assert_eq!(params["text"], "Can't display this image: the file is gone");
+        assert_eq!(failures.count(), 1);
+    }
+
+    #[test]
+    fn answers_a_failed_button_with_an_alert() {
+        let mut bot = FakeBot::failing(&[1]);
+        let mut failures = Failures::new(2);
+
+        handle(&mut bot, callback(1), &mut failures);
+
+        let (method, params) = bot.api.call();
+        assert_eq!(method, "answerCallbackQuery");
+        assert_eq!(params["callback_query_id"], "query");
+        assert_eq!(params["show_alert"], true);
+    }
+
+    #[test]
+    fn restarts_after_failures_in_a_row() {
+        let mut bot = FakeBot::failing(&[1, 3, 4]);
+        let mut failures = Failures::new(2);
+
+        assert!(!handle(&mut bot, message(1), &mut failures));
+        // a success in between starts the count over
+        assert!(!handle(&mut bot, message(2), &mut failures));
+        assert!(!handle(&mut bot, message(3), &mut failures));
+        assert!(handle(&mut bot, message(4), &mut failures));
+    }
+}
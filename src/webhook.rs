@@ -0,0 +1,265 @@
+#[cfg(target_os = "espidf")]
+use std::sync::mpsc::Sender;
+
+use anyhow::{Context, Result};
+#[cfg(target_os = "espidf")]
+use embedded_svc::http::server::Request;
+#[cfg(target_os = "espidf")]
+use embedded_svc::io::{Read, Write};
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::http::server::{Configuration, EspHttpConnection, EspHttpServer};
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::http::Method;
+use frankenstein::{AllowedUpdate, SetWebhookParams, TelegramApi, Update};
+#[cfg(target_os = "espidf")]
+use log::{info, warn};
+
+/// Telegram sends this with every update when the webhook was set with a secret
+pub const SECRET_HEADER: &str = "X-Telegram-Bot-Api-Secret-Token";
+
+/// Updates are a few KB at most, anything bigger isn't from Telegram
+pub const MAX_BODY: usize = 64 * 1024;
+
+/// Why a request to the webhook was turned down
+#[derive(Debug)]
+pub enum Rejection {
+    WrongMethod,
+    WrongSecret,
+    TooLarge,
+    Malformed(serde_json::Error),
+}
+
+impl Rejection {
+    pub fn status(&self) -> u16 {
+        match self {
+            Rejection::WrongMethod => 405,
+            Rejection::WrongSecret => 401,
+            Rejection::TooLarge => 413,
+            Rejection::Malformed(_) => 400,
+        }
+    }
+}
+
+/// Checks that a request is a POST from Telegram and reads the update in it.
+/// Doesn't touch the network, so it runs the same anywhere
+pub fn accept(
+    method: &str,
+    secret: Option<&str>,
+    expected: &str,
+    body: &[u8],
+) -> Result<Update, Rejection> {
+    if method != "POST" {
+        return Err(Rejection::WrongMethod);
+    }
+
+    if !secret.is_some_and(|secret| same_secret(secret.as_bytes(), expected.as_bytes())) {
+        return Err(Rejection::WrongSecret);
+    }
+
+    if body.len() > MAX_BODY {
+        return Err(Rejection::TooLarge);
+    }
+
+    serde_json::from_slice(body).map_err(Rejection::Malformed)
+}
+
+/// Compares in the same time wherever the first difference is
+fn same_secret(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// What `start` listens to, with the names `accept` knows them by
+#[cfg(target_os = "espidf")]
+const METHODS: [(Method, &str); 4] = [
+    (Method::Get, "GET"),
+    (Method::Post, "POST"),
+    (Method::Put, "PUT"),
+    (Method::Delete, "DELETE"),
+];
+
+/// Serves the webhook on `port`, accepted updates go to `updates`.
+/// The server stops when the returned handle is dropped
+#[cfg(target_os = "espidf")]
+pub fn start(
+    port: u16,
+    secret: &'static str,
+    updates: Sender<Update>,
+) -> Result<EspHttpServer<'static>> {
+    let mut server = EspHttpServer::new(&Configuration {
+        http_port: port,
+        // serde_json goes deep on nested messages
+        stack_size: 12 * 1024,
+        ..Default::default()
+    })?;
+
+    // every method is routed here, so the ones that aren't POST are answered by `accept`
+    for (method, name) in METHODS {
+        let updates = updates.clone();
+        server.fn_handler("/", method, move |request| {
+            serve(name, request, secret, &updates)
+        })?;
+    }
+
+    info!("Webhook listening on port {}", port);
+
+    Ok(server)
+}
+
+/// Reads one request and answers it, sending the update on if `accept` takes it
+#[cfg(target_os = "espidf")]
+fn serve(
+    method: &str,
+    mut request: Request<&mut EspHttpConnection>,
+    secret: &str,
+    updates: &Sender<Update>,
+) -> Result<()> {
+    let secret_header = request.header(SECRET_HEADER).map(str::to_string);
+
+    let mut body = Vec::new();
+    let mut buffer = [0u8; 1024];
+    loop {
+        let read = request.read(&mut buffer)?;
+        if read == 0 || body.len() > MAX_BODY {
+            break;
+        }
+        body.extend_from_slice(&buffer[..read]);
+    }
+
+    match accept(method, secret_header.as_deref(), secret, &body) {
+        Ok(update) => {
+            // answered right away, Telegram retries updates that take too long
+            request.into_ok_response()?;
+            updates.send(update).ok();
+        }
+        Err(rejection) => {
+            warn!("Webhook request rejected: {:?}", rejection);
+            request
+                .into_status_response(rejection.status())?
+                .write_all(b"rejected")?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Tells Telegram where to send updates, `url` is whatever forwards to `start`'s port
+pub fn register<A>(api: &A, url: &str, secret: &str) -> Result<()>
+where
+    A: TelegramApi,
+    A::Error: std::error::Error + Send + Sync + 'static,
+{
+    api.set_webhook(
+        &SetWebhookParams::builder()
+            .url(url)
+            .secret_token(secret)
+            .allowed_updates(vec![AllowedUpdate::Message, AllowedUpdate::CallbackQuery])
+            .build(),
+    )
+    .context("Could not set the webhook")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::fake_api::FakeApi;
+
+    const SECRET: &str = "s3cret";
+
+    fn update() -> Vec<u8> {
+        json!({
+            "update_id": 1,
+            "message": {
+                "message_id": 7,
+                "date": 0,
+                "chat": { "id": 42, "type": "private" },
+                "text": "hello",
+            },
+        })
+        .to_string()
+        .into_bytes()
+    }
+
+    #[test]
+    fn accepts_updates_from_telegram() {
+        let update = accept("POST", Some(SECRET), SECRET, &update()).unwrap();
+        assert_eq!(update.update_id, 1);
+    }
+
+    #[test]
+    fn refuses_other_methods() {
+        for method in ["GET", "PUT", "DELETE"] {
+            let rejection = accept(method, Some(SECRET), SECRET, &update()).unwrap_err();
+            assert!(matches!(rejection, Rejection::WrongMethod));
+            assert_eq!(rejection.status(), 405);
+        }
+    }
+
+    #[test]
+    fn refuses_wrong_or_missing_secrets() {
+        for secret in [
+            None,
+            Some(""),
+            Some("s3cre"),
+            Some("s3cret!"),
+            Some("S3CRET"),
+        ] {
+            let rejection = accept("POST", secret, SECRET, &update()).unwrap_err();
+            assert!(matches!(rejection, Rejection::WrongSecret), "{secret:?}");
+            assert_eq!(rejection.status(), 401);
+        }
+    }
+
+    #[test]
+    fn checks_the_secret_before_the_body() {
+        let rejection = accept("POST", Some("nope"), SECRET, b"not json").unwrap_err();
+        assert!(matches!(rejection, Rejection::WrongSecret));
+    }
+
+    #[test]
+    fn refuses_large_bodies() {
+        let body = vec![b' '; MAX_BODY + 1];
+
+        let rejection = accept("POST", Some(SECRET), SECRET, &body).unwrap_err();
+        assert!(matches!(rejection, Rejection::TooLarge));
+        assert_eq!(rejection.status(), 413);
+    }
+
+    #[test]
+    fn refuses_what_isnt_an_update() {
+        for body in [&b""[..], b"not json", b"{}", b"[1, 2]"] {
+            let rejection = accept("POST", Some(SECRET), SECRET, body).unwrap_err();
+            assert!(matches!(rejection, Rejection::Malformed(_)));
+            assert_eq!(rejection.status(), 400);
+        }
+    }
+
+    #[test]
+    fn registers_with_the_secret() {
+        let api = FakeApi::new();
+        register(&api, "https://example.com/bot", SECRET).unwrap();
+
+        let (method, params) = api.call();
+        assert_eq!(method, "setWebhook");
+        assert_eq!(params["url"], "https://example.com/bot");
+        assert_eq!(params["secret_token"], SECRET);
+        assert_eq!(
+            params["allowed_updates"],
+            json!(["message", "callback_query"])
+        );
+    }
+
+    #[test]
+    fn reports_when_telegram_refuses_the_webhook() {
+        let api = FakeApi::answering(json!({
+            "ok": false,
+            "error_code": 400,
+            "description": "Bad Request: bad webhook: HTTPS url must be provided for webhook",
+        }));
+
+        assert!(register(&api, "http://example.com/bot", SECRET).is_err());
+    }
+}